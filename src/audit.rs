@@ -0,0 +1,323 @@
+//! # Audit
+//! Check supply invariants of `StateData` before migration.
+//!
+use aurora_engine_migration_tool::{read_state_data_header, Provenance, StorageBalance};
+use near_primitives::hash::CryptoHash;
+use near_sdk::borsh::{self, BorshDeserialize};
+use near_sdk::AccountId;
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Raw `StateData` layout. Maps are read as lists to detect duplicated
+/// and invalid accounts, that are hidden or rejected by `StateData` itself.
+#[derive(BorshDeserialize)]
+struct RawStateData {
+    block_height: u64,
+    total_supply: u128,
+    total_stuck_supply: u128,
+    accounts: Vec<(String, u128)>,
+    storage_balances: Vec<(String, Option<StorageBalance>)>,
+    provenance: Vec<(String, Provenance)>,
+    stuck_accounts: Vec<(String, u128)>,
+}
+
+/// Raw legacy `StateData` layout without header
+#[derive(BorshDeserialize)]
+struct RawLegacyStateData {
+    total_supply: u128,
+    total_stuck_supply: u128,
+    accounts: Vec<(String, u128)>,
+}
+
+impl RawStateData {
+    fn read(data: &[u8]) -> std::io::Result<Self> {
+        let mut buf = data;
+        if read_state_data_header(&mut buf)? {
+            return Self::try_from_slice(buf);
+        }
+        let legacy = RawLegacyStateData::try_from_slice(buf)?;
+        Ok(Self {
+            block_height: 0,
+            total_supply: legacy.total_supply,
+            total_stuck_supply: legacy.total_stuck_supply,
+            accounts: legacy.accounts,
+            storage_balances: vec![],
+            provenance: vec![],
+            stuck_accounts: vec![],
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    pub violations: Vec<String>,
+    pub warnings: Vec<String>,
+    /// Sum of the account balances, `None` if it doesn't fit `u128`
+    pub real_total_supply: Option<u128>,
+}
+
+/// Check all `StateData` invariants and print report.
+/// Return error if any invariant is violated. With `strict`
+/// warnings are treated as violations.
+pub fn audit<P: AsRef<Path>>(data_file: P, strict: bool) -> anyhow::Result<()> {
+    let data = std::fs::read(&data_file)
+        .map_err(|e| anyhow::anyhow!("Failed read state data file, {e}"))?;
+    let raw_data = RawStateData::read(&data)
+        .map_err(|e| anyhow::anyhow!("Failed deserialize state data, {e}"))?;
+    let report = AuditReport::check(&raw_data);
+
+    println!("File: {}", data_file.as_ref().display());
+    println!("Data hash: {}", CryptoHash::hash_bytes(&data));
+    println!("Block height: {:?}", raw_data.block_height);
+    println!("Accounts: {}", raw_data.accounts.len());
+    println!("Total supply: {:?}", raw_data.total_supply);
+    println!("Total stuck supply: {:?}", raw_data.total_stuck_supply);
+    println!("Stuck accounts: {}", raw_data.stuck_accounts.len());
+    if let Some(real_total_supply) = report.real_total_supply {
+        println!("Real total supply: {real_total_supply:?}");
+    }
+    for warning in &report.warnings {
+        println!("\tWARNING: {warning}");
+    }
+    for violation in &report.violations {
+        println!("\tVIOLATION: {violation}");
+    }
+
+    let violations = if strict {
+        report.violations.len() + report.warnings.len()
+    } else {
+        report.violations.len()
+    };
+    if violations > 0 {
+        anyhow::bail!("Audit failed: {violations} violations");
+    }
+    println!("Audit passed");
+
+    Ok(())
+}
+
+impl AuditReport {
+    /// Check all `StateData` invariants
+    fn check(raw_data: &RawStateData) -> Self {
+        let mut report = Self::default();
+
+        let mut accounts = HashSet::new();
+        let mut real_total_supply = Some(0u128);
+        let mut zero_balances = 0;
+        for (account, balance) in &raw_data.accounts {
+            if AccountId::from_str(account).is_err() {
+                report
+                    .violations
+                    .push(format!("Invalid account: {account} with balance {balance}"));
+            }
+            if !accounts.insert(account.as_str()) {
+                report
+                    .violations
+                    .push(format!("Duplicated account: {account}"));
+            }
+            if *balance == 0 {
+                zero_balances += 1;
+            }
+            real_total_supply = real_total_supply.and_then(|sum| sum.checked_add(*balance));
+        }
+        if zero_balances > 0 {
+            report
+                .violations
+                .push(format!("Accounts with zero balance: {zero_balances}"));
+        }
+
+        let mut storage_accounts = HashSet::new();
+        for (account, _) in &raw_data.storage_balances {
+            if !storage_accounts.insert(account.as_str()) {
+                report
+                    .violations
+                    .push(format!("Duplicated storage balance for account: {account}"));
+            }
+            if !accounts.contains(account.as_str()) {
+                report
+                    .violations
+                    .push(format!("Storage balance for unknown account: {account}"));
+            }
+        }
+
+        let mut provenance_accounts = HashSet::new();
+        for (account, provenance) in &raw_data.provenance {
+            if !provenance_accounts.insert(account.as_str()) {
+                report
+                    .violations
+                    .push(format!("Duplicated provenance for account: {account}"));
+            }
+            if !accounts.contains(account.as_str()) {
+                report
+                    .violations
+                    .push(format!("Provenance for unknown account: {account}"));
+            }
+            if provenance.block_height > raw_data.block_height {
+                report.violations.push(format!(
+                    "Provenance block height {} for account {account} is higher than data block height {}",
+                    provenance.block_height, raw_data.block_height
+                ));
+            }
+        }
+
+        let stuck_supply = raw_data
+            .stuck_accounts
+            .iter()
+            .try_fold(0u128, |sum, (_, balance)| sum.checked_add(*balance));
+        if raw_data.stuck_accounts.is_empty() {
+            if raw_data.total_stuck_supply > 0 {
+                report
+                    .warnings
+                    .push("Stuck accounts list is missing".to_string());
+            }
+        } else if stuck_supply != Some(raw_data.total_stuck_supply) {
+            report.violations.push(format!(
+                "Stuck accounts balances sum doesn't match total stuck supply {}",
+                raw_data.total_stuck_supply
+            ));
+        }
+
+        let expected_total_supply =
+            real_total_supply.and_then(|sum| sum.checked_add(raw_data.total_stuck_supply));
+        match expected_total_supply {
+            None => report
+                .violations
+                .push("Balances sum + stuck supply doesn't fit u128".to_string()),
+            Some(sum) if sum != raw_data.total_supply => report.violations.push(format!(
+                "Balances sum + stuck supply {sum} doesn't match total supply {}",
+                raw_data.total_supply
+            )),
+            Some(_) => (),
+        }
+
+        report.real_total_supply = real_total_supply;
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aurora_engine_migration_tool::{AccountSource, StateData};
+    use aurora_engine_types::types::NEP141Wei;
+    use near_sdk::borsh::BorshSerialize;
+
+    /// Valid data: two accounts and one stuck account
+    fn raw_data() -> RawStateData {
+        RawStateData {
+            block_height: 100,
+            total_supply: 35,
+            total_stuck_supply: 5,
+            accounts: vec![("alice.near".to_string(), 10), ("bob.near".to_string(), 20)],
+            storage_balances: vec![("alice.near".to_string(), None)],
+            provenance: vec![(
+                "bob.near".to_string(),
+                Provenance {
+                    source: AccountSource::State,
+                    block_height: 100,
+                },
+            )],
+            stuck_accounts: vec![("Invalid.Account".to_string(), 5)],
+        }
+    }
+
+    #[test]
+    fn test_valid_data() {
+        let report = AuditReport::check(&raw_data());
+        assert!(report.violations.is_empty(), "{:?}", report.violations);
+        assert!(report.warnings.is_empty());
+        assert_eq!(report.real_total_supply, Some(30));
+    }
+
+    #[test]
+    fn test_accounts_violations() {
+        let mut data = raw_data();
+        data.accounts.push(("alice.near".to_string(), 0));
+        data.accounts.push(("Invalid.Account".to_string(), 0));
+        let report = AuditReport::check(&data);
+        assert_eq!(
+            report.violations,
+            vec![
+                "Duplicated account: alice.near",
+                "Invalid account: Invalid.Account with balance 0",
+                "Accounts with zero balance: 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_total_supply_mismatch() {
+        let mut data = raw_data();
+        data.total_supply = 36;
+        let report = AuditReport::check(&data);
+        assert_eq!(
+            report.violations,
+            vec!["Balances sum + stuck supply 35 doesn't match total supply 36"]
+        );
+
+        data.accounts.push(("carol.near".to_string(), u128::MAX));
+        let report = AuditReport::check(&data);
+        assert_eq!(report.real_total_supply, None);
+        assert_eq!(
+            report.violations,
+            vec!["Balances sum + stuck supply doesn't fit u128"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_accounts() {
+        let mut data = raw_data();
+        data.storage_balances
+            .push(("carol.near".to_string(), Some(StorageBalance::default())));
+        data.storage_balances.push(("alice.near".to_string(), None));
+        data.provenance[0].1.block_height = 101;
+        let report = AuditReport::check(&data);
+        assert_eq!(
+            report.violations,
+            vec![
+                "Storage balance for unknown account: carol.near",
+                "Duplicated storage balance for account: alice.near",
+                "Provenance block height 101 for account bob.near is higher than data block height 100",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stuck_supply() {
+        let mut data = raw_data();
+        data.stuck_accounts[0].1 = 4;
+        let report = AuditReport::check(&data);
+        assert_eq!(
+            report.violations,
+            vec!["Stuck accounts balances sum doesn't match total stuck supply 5"]
+        );
+
+        // Legacy data has no stuck accounts list
+        data.stuck_accounts.clear();
+        let report = AuditReport::check(&data);
+        assert!(report.violations.is_empty());
+        assert_eq!(report.warnings, vec!["Stuck accounts list is missing"]);
+    }
+
+    #[test]
+    fn test_read_layouts() {
+        let mut state = StateData {
+            block_height: 100,
+            total_supply: NEP141Wei::new(10),
+            ..StateData::default()
+        };
+        state
+            .accounts
+            .insert("alice.near".parse().unwrap(), NEP141Wei::new(10));
+        let data = RawStateData::read(&state.try_to_vec().unwrap()).unwrap();
+        assert_eq!(data.block_height, 100);
+        assert_eq!(data.accounts, vec![("alice.near".to_string(), 10)]);
+
+        let legacy = (10u128, 0u128, vec![("alice.near".to_string(), 10u128)]);
+        let data = RawStateData::read(&legacy.try_to_vec().unwrap()).unwrap();
+        assert_eq!(data.block_height, 0);
+        assert_eq!(data.total_supply, 10);
+        assert_eq!(data.accounts, vec![("alice.near".to_string(), 10)]);
+    }
+}
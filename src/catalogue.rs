@@ -0,0 +1,277 @@
+//! # Catalogue
+//! Engine contract account and catalogue of tracked methods, with rules
+//! how to pull accounts from the method arguments.
+//!
+use crate::rpc::AURORA_CONTRACT;
+use near_sdk::borsh::BorshDeserialize;
+use near_sdk::AccountId;
+use serde::Deserialize;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Engine contract and its tracked methods
+#[derive(Debug, Clone)]
+pub struct Catalogue {
+    /// Engine contract account ID
+    pub contract: String,
+    pub methods: Vec<MethodEntry>,
+}
+
+/// Tracked method
+#[derive(Debug, Clone, Deserialize)]
+pub struct MethodEntry {
+    pub method: String,
+    pub accounts: AccountsSource,
+}
+
+/// How to pull accounts from the method arguments
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountsSource {
+    /// JSON object fields with account IDs. Missing and `null` fields are skipped.
+    Json(Vec<String>),
+    /// Borsh fields layout in order, up to the last account field
+    Borsh(Vec<BorshField>),
+    /// Only `predecessor_account_id` is recorded
+    Predecessor,
+}
+
+/// Borsh field type
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BorshField {
+    Account,
+    String,
+    Bytes,
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+}
+
+impl Default for Catalogue {
+    /// Aurora contract with `aurora-eth-connector` and NEP-141 methods
+    fn default() -> Self {
+        use AccountsSource::{Borsh, Json, Predecessor};
+
+        let entry = |method: &str, accounts| MethodEntry {
+            method: method.to_string(),
+            accounts,
+        };
+        let fields =
+            |fields: &[&str]| -> Vec<String> { fields.iter().map(ToString::to_string).collect() };
+        Self {
+            contract: AURORA_CONTRACT.to_string(),
+            methods: vec![
+                entry("ft_transfer", Json(fields(&["receiver_id"]))),
+                entry("deposit", Predecessor),
+                entry("ft_transfer_call", Json(fields(&["receiver_id"]))),
+                entry("withdraw", Predecessor),
+                entry(
+                    "finish_deposit",
+                    Borsh(vec![
+                        BorshField::Account,
+                        BorshField::U128,
+                        BorshField::String,
+                        BorshField::Account,
+                    ]),
+                ),
+                entry("storage_deposit", Json(fields(&["account_id"]))),
+                entry("storage_withdraw", Predecessor),
+                entry("storage_unregister", Predecessor),
+                entry("ft_on_transfer", Json(fields(&["sender_id"]))),
+                entry(
+                    "ft_resolve_transfer",
+                    Json(fields(&["sender_id", "receiver_id"])),
+                ),
+            ],
+        }
+    }
+}
+
+impl Catalogue {
+    /// Default catalogue for the engine contract. If methods file is set,
+    /// the methods catalogue is read from JSON file.
+    pub fn new<P: AsRef<Path>>(contract: &str, methods_file: Option<P>) -> anyhow::Result<Self> {
+        AccountId::from_str(contract)
+            .map_err(|e| anyhow::anyhow!("Invalid engine account {contract}, {e}"))?;
+        let methods = match methods_file {
+            Some(methods_file) => {
+                let data = std::fs::read(&methods_file).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Failed read methods catalogue {}, {e}",
+                        methods_file.as_ref().display()
+                    )
+                })?;
+                serde_json::from_slice(&data)
+                    .map_err(|e| anyhow::anyhow!("Failed deserialize methods catalogue, {e}"))?
+            }
+            None => Self::default().methods,
+        };
+        Ok(Self {
+            contract: contract.to_string(),
+            methods,
+        })
+    }
+
+    /// Check if account is the engine contract
+    #[must_use]
+    pub fn is_contract(&self, account: &str) -> bool {
+        self.contract == account
+    }
+
+    #[must_use]
+    pub fn method(&self, method: &str) -> Option<&MethodEntry> {
+        self.methods.iter().find(|entry| entry.method == method)
+    }
+}
+
+impl MethodEntry {
+    /// Pull accounts from method arguments
+    pub fn parse_accounts(&self, args: &[u8]) -> anyhow::Result<Vec<AccountId>> {
+        match &self.accounts {
+            AccountsSource::Json(fields) => {
+                let args: serde_json::Value = serde_json::from_slice(args)?;
+                fields
+                    .iter()
+                    .filter_map(|field| args.get(field).filter(|value| !value.is_null()))
+                    .map(|value| {
+                        let account = value
+                            .as_str()
+                            .ok_or_else(|| anyhow::anyhow!("Expected account string"))?;
+                        Ok(AccountId::from_str(account)?)
+                    })
+                    .collect()
+            }
+            AccountsSource::Borsh(layout) => {
+                let mut args = args;
+                let mut accounts = vec![];
+                for field in layout {
+                    match field {
+                        BorshField::Account => {
+                            let account: String = BorshDeserialize::deserialize(&mut args)?;
+                            accounts.push(AccountId::from_str(&account)?);
+                        }
+                        BorshField::String => skip::<String>(&mut args)?,
+                        BorshField::Bytes => skip::<Vec<u8>>(&mut args)?,
+                        BorshField::Bool => skip::<bool>(&mut args)?,
+                        BorshField::U8 => skip::<u8>(&mut args)?,
+                        BorshField::U16 => skip::<u16>(&mut args)?,
+                        BorshField::U32 => skip::<u32>(&mut args)?,
+                        BorshField::U64 => skip::<u64>(&mut args)?,
+                        BorshField::U128 => skip::<u128>(&mut args)?,
+                    }
+                }
+                Ok(accounts)
+            }
+            AccountsSource::Predecessor => Ok(vec![]),
+        }
+    }
+}
+
+/// Skip borsh field of the given type
+fn skip<T: BorshDeserialize>(args: &mut &[u8]) -> std::io::Result<()> {
+    T::deserialize(args).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::borsh::BorshSerialize;
+
+    fn accounts(accounts: &[&str]) -> Vec<AccountId> {
+        accounts
+            .iter()
+            .map(|account| account.parse().unwrap())
+            .collect()
+    }
+
+    fn parse(method: &str, args: &[u8]) -> anyhow::Result<Vec<AccountId>> {
+        Catalogue::default()
+            .method(method)
+            .unwrap()
+            .parse_accounts(args)
+    }
+
+    #[test]
+    fn test_json_accounts() {
+        let args = br#"{"receiver_id":"bob.near","amount":"10"}"#;
+        assert_eq!(parse("ft_transfer", args).unwrap(), accounts(&["bob.near"]));
+
+        let args = br#"{"sender_id":"alice.near","receiver_id":"bob.near"}"#;
+        assert_eq!(
+            parse("ft_resolve_transfer", args).unwrap(),
+            accounts(&["alice.near", "bob.near"])
+        );
+    }
+
+    #[test]
+    fn test_json_missing_fields() {
+        let args = br#"{"account_id":null,"registration_only":true}"#;
+        assert!(parse("storage_deposit", args).unwrap().is_empty());
+        assert!(parse("storage_deposit", b"{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_json_invalid_args() {
+        assert!(parse("ft_transfer", b"not json").is_err());
+        assert!(parse("ft_transfer", br#"{"receiver_id":1}"#).is_err());
+        assert!(parse("ft_transfer", br#"{"receiver_id":"Bob.Near"}"#).is_err());
+    }
+
+    #[test]
+    fn test_borsh_accounts() {
+        let args = (
+            "alice.near".to_string(),
+            10u128,
+            "message".to_string(),
+            "bob.near".to_string(),
+        )
+            .try_to_vec()
+            .unwrap();
+        assert_eq!(
+            parse("finish_deposit", &args).unwrap(),
+            accounts(&["alice.near", "bob.near"])
+        );
+        assert!(parse("finish_deposit", &args[..args.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_predecessor_accounts() {
+        assert!(parse("withdraw", b"any args").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_methods_file_format() {
+        let methods: Vec<MethodEntry> = serde_json::from_str(
+            r#"[
+                {"method": "transfer", "accounts": {"json": ["to"]}},
+                {"method": "mint", "accounts": {"borsh": ["u64", "account"]}},
+                {"method": "burn", "accounts": "predecessor"}
+            ]"#,
+        )
+        .unwrap();
+        let catalogue = Catalogue {
+            contract: "token.near".to_string(),
+            methods,
+        };
+        assert!(catalogue.is_contract("token.near"));
+        assert!(catalogue.method("ft_transfer").is_none());
+
+        let args = br#"{"to":"bob.near"}"#;
+        let transfer = catalogue.method("transfer").unwrap();
+        assert_eq!(
+            transfer.parse_accounts(args).unwrap(),
+            accounts(&["bob.near"])
+        );
+
+        let args = (7u64, "alice.near".to_string()).try_to_vec().unwrap();
+        let mint = catalogue.method("mint").unwrap();
+        assert_eq!(
+            mint.parse_accounts(&args).unwrap(),
+            accounts(&["alice.near"])
+        );
+    }
+}
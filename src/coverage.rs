@@ -0,0 +1,397 @@
+//! # Coverage
+//! Check that the indexed blocks cover the whole window from the snapshot
+//! block to the pause block, so no account changes are lost between them.
+//!
+use crate::indexer::IndexerData;
+use crate::rpc::{FailureKind, MissedBlocks};
+use crate::store::load_indexer_state;
+use crate::summary::{Summary, SummaryFormat};
+use aurora_engine_migration_tool::StateData;
+use near_primitives::types::BlockHeight;
+use near_sdk::borsh::BorshDeserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+
+/// Range of the window heights that wasn't processed by the indexer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Gap {
+    from: BlockHeight,
+    to: BlockHeight,
+    reason: GapReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GapReason {
+    /// Heights outside of all indexed ranges
+    NotIndexed,
+    /// Missed blocks with failure kind
+    Missed(FailureKind),
+}
+
+impl Display for GapReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotIndexed => write!(f, "not indexed"),
+            Self::Missed(kind) => write!(f, "missed: {kind}"),
+        }
+    }
+}
+
+impl Gap {
+    pub(crate) const fn blocks(&self) -> u64 {
+        self.to - self.from + 1
+    }
+}
+
+/// Heights processed by the indexer into one data file: from the first
+/// processed block to the last handled block, except missed blocks
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IndexedRange<'a> {
+    pub first_block: BlockHeight,
+    pub last_block: BlockHeight,
+    pub missed_blocks: &'a MissedBlocks,
+}
+
+impl<'a> From<&'a IndexerData> for IndexedRange<'a> {
+    fn from(data: &'a IndexerData) -> Self {
+        Self {
+            first_block: data.first_block,
+            last_block: data.last_handled_block,
+            missed_blocks: &data.missed_blocks,
+        }
+    }
+}
+
+impl IndexedRange<'_> {
+    /// Check if no blocks are processed yet
+    const fn is_empty(&self) -> bool {
+        self.first_block == 0 || self.first_block > self.last_block
+    }
+}
+
+/// Find gaps of the `start..=stop` window. A height is covered if any range
+/// processed it: the height is in the range and isn't missed there. Skipped
+/// heights without blocks are already removed from missed blocks.
+pub(crate) fn find_gaps(
+    start: BlockHeight,
+    stop: BlockHeight,
+    ranges: &[IndexedRange],
+) -> Vec<Gap> {
+    // Covered parts of the window, and missed heights with failure kind
+    let mut covered = vec![];
+    let mut missed = BTreeMap::new();
+    for range in ranges.iter().filter(|range| !range.is_empty()) {
+        let from = range.first_block.max(start);
+        let to = range.last_block.min(stop);
+        if from > to {
+            continue;
+        }
+        let mut missed_heights: Vec<_> = range
+            .missed_blocks
+            .iter()
+            .filter(|(height, _)| (from..=to).contains(*height))
+            .map(|(height, missed_block)| (*height, missed_block.kind))
+            .collect();
+        missed_heights.sort_unstable_by_key(|(height, _)| *height);
+        let mut next = from;
+        for (height, kind) in missed_heights {
+            if next < height {
+                covered.push((next, height - 1));
+            }
+            next = height + 1;
+            missed.entry(height).or_insert(kind);
+        }
+        if next <= to {
+            covered.push((next, to));
+        }
+    }
+    covered.sort_unstable();
+
+    let mut gaps: Vec<Gap> = vec![];
+    let mut push = |from: BlockHeight, to: BlockHeight, reason: GapReason| match gaps.last_mut() {
+        // Consecutive heights with the same reason are one gap
+        Some(gap) if gap.to + 1 == from && gap.reason == reason => gap.to = to,
+        _ => gaps.push(Gap { from, to, reason }),
+    };
+    // Heights that are missed in a range and not covered by other ranges
+    // are missed, others are not indexed
+    let mut push_uncovered = |from: BlockHeight, to: BlockHeight| {
+        let mut next = from;
+        for (&height, &kind) in missed.range(from..=to) {
+            if next < height {
+                push(next, height - 1, GapReason::NotIndexed);
+            }
+            push(height, height, GapReason::Missed(kind));
+            next = height + 1;
+        }
+        if next <= to {
+            push(next, to, GapReason::NotIndexed);
+        }
+    };
+    let mut next = start;
+    for (from, to) in covered {
+        if next < from {
+            push_uncovered(next, from - 1);
+        }
+        next = next.max(to + 1);
+    }
+    if next <= stop {
+        push_uncovered(next, stop);
+    }
+    gaps
+}
+
+/// Check that every block from the snapshot height to the pause height
+/// was processed by the indexer into any of the data files and print
+/// report. Return error if there are gaps.
+pub fn check_coverage<P: AsRef<Path>>(
+    snapshot_file: P,
+    data_files: &[P],
+    pause_height: BlockHeight,
+    format: SummaryFormat,
+) -> anyhow::Result<()> {
+    let snapshot = std::fs::read(&snapshot_file)
+        .map_err(|e| anyhow::anyhow!("Failed read snapshot data file, {e}"))
+        .and_then(|data| {
+            StateData::try_from_slice(&data)
+                .map_err(|e| anyhow::anyhow!("Failed deserialize snapshot data, {e}"))
+        })?;
+    let snapshot_height = snapshot.block_height;
+    anyhow::ensure!(
+        pause_height >= snapshot_height,
+        "Pause height {pause_height} is lower than snapshot height {snapshot_height}"
+    );
+    let data = data_files
+        .iter()
+        .map(load_indexer_state)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let ranges: Vec<_> = data.iter().map(IndexedRange::from).collect();
+    let gaps = find_gaps(snapshot_height, pause_height, &ranges);
+    let window_blocks = pause_height - snapshot_height + 1;
+    let gap_blocks: u64 = gaps.iter().map(Gap::blocks).sum();
+
+    let mut summary = Summary::new(format);
+    summary.field("snapshot_height", "Snapshot height", snapshot_height);
+    summary.field("pause_height", "Pause height", pause_height);
+    for (data_file, data) in data_files.iter().zip(&data) {
+        summary.text(format_args!(
+            "Data file {}: first block: {:?}, last handled block: {:?}, missed blocks: {}",
+            data_file.as_ref().display(),
+            data.first_block,
+            data.last_handled_block,
+            data.missed_blocks.len()
+        ));
+    }
+    summary.set(
+        "data_files",
+        data_files
+            .iter()
+            .zip(&data)
+            .map(|(data_file, data)| {
+                json!({
+                    "file": data_file.as_ref().display().to_string(),
+                    "first_block": data.first_block,
+                    "last_handled_block": data.last_handled_block,
+                    "missed_blocks": data.missed_blocks.len(),
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+    summary.field("window_blocks", "Blocks in window", window_blocks);
+    summary.field(
+        "covered_blocks",
+        "Covered blocks",
+        window_blocks - gap_blocks,
+    );
+    summary.field("gaps_count", "Gaps", gaps.len());
+    for gap in &gaps {
+        summary.text(format_args!(
+            "\t{:?}..={:?}: {} blocks, {}",
+            gap.from,
+            gap.to,
+            gap.blocks(),
+            gap.reason
+        ));
+    }
+    summary.set(
+        "gaps",
+        gaps.iter()
+            .map(|gap| {
+                json!({
+                    "from": gap.from,
+                    "to": gap.to,
+                    "blocks": gap.blocks(),
+                    "reason": gap.reason.to_string(),
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+    summary.field("complete", "Complete", gaps.is_empty());
+    summary.finish();
+
+    if !gaps.is_empty() {
+        anyhow::bail!(
+            "Coverage check failed: {gap_blocks} blocks in {} gaps",
+            gaps.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::MissedBlock;
+
+    fn missed(heights: &[(BlockHeight, FailureKind)]) -> MissedBlocks {
+        heights
+            .iter()
+            .map(|(height, kind)| {
+                let missed_block = MissedBlock {
+                    kind: *kind,
+                    error: String::new(),
+                    attempts: 1,
+                    last_attempt: 0,
+                    chunks: vec![],
+                };
+                (*height, missed_block)
+            })
+            .collect()
+    }
+
+    const fn range(
+        first_block: BlockHeight,
+        last_block: BlockHeight,
+        missed_blocks: &MissedBlocks,
+    ) -> IndexedRange {
+        IndexedRange {
+            first_block,
+            last_block,
+            missed_blocks,
+        }
+    }
+
+    const fn gap(from: BlockHeight, to: BlockHeight, reason: GapReason) -> Gap {
+        Gap { from, to, reason }
+    }
+
+    #[test]
+    fn test_no_ranges() {
+        let empty = MissedBlocks::new();
+        assert_eq!(
+            find_gaps(10, 20, &[]),
+            vec![gap(10, 20, GapReason::NotIndexed)]
+        );
+        assert_eq!(
+            find_gaps(10, 20, &[range(0, 0, &empty)]),
+            vec![gap(10, 20, GapReason::NotIndexed)]
+        );
+    }
+
+    #[test]
+    fn test_complete_range() {
+        let empty = MissedBlocks::new();
+        assert!(find_gaps(10, 20, &[range(5, 25, &empty)]).is_empty());
+        assert!(find_gaps(10, 20, &[range(10, 20, &empty)]).is_empty());
+    }
+
+    #[test]
+    fn test_head_and_tail_gaps() {
+        let empty = MissedBlocks::new();
+        assert_eq!(
+            find_gaps(10, 20, &[range(13, 17, &empty)]),
+            vec![
+                gap(10, 12, GapReason::NotIndexed),
+                gap(18, 20, GapReason::NotIndexed)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missed_blocks() {
+        let missed_blocks = missed(&[
+            (12, FailureKind::Block),
+            (13, FailureKind::Block),
+            (14, FailureKind::StateChanges),
+            (16, FailureKind::Block),
+            // Outside of the window
+            (25, FailureKind::Block),
+        ]);
+        assert_eq!(
+            find_gaps(10, 20, &[range(10, 30, &missed_blocks)]),
+            vec![
+                gap(12, 13, GapReason::Missed(FailureKind::Block)),
+                gap(14, 14, GapReason::Missed(FailureKind::StateChanges)),
+                gap(16, 16, GapReason::Missed(FailureKind::Block)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missed_block_next_to_not_indexed() {
+        let missed_blocks = missed(&[(15, FailureKind::Block)]);
+        assert_eq!(
+            find_gaps(10, 20, &[range(10, 15, &missed_blocks)]),
+            vec![
+                gap(15, 15, GapReason::Missed(FailureKind::Block)),
+                gap(16, 20, GapReason::NotIndexed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merged_ranges() {
+        let empty = MissedBlocks::new();
+        // Head range is indexed into a separate data file
+        assert!(find_gaps(10, 30, &[range(18, 30, &empty), range(10, 20, &empty)]).is_empty());
+        // Adjacent ranges
+        assert!(find_gaps(10, 30, &[range(10, 19, &empty), range(20, 30, &empty)]).is_empty());
+        assert_eq!(
+            find_gaps(10, 30, &[range(10, 15, &empty), range(20, 30, &empty)]),
+            vec![gap(16, 19, GapReason::NotIndexed)]
+        );
+    }
+
+    #[test]
+    fn test_missed_block_covered_by_another_range() {
+        let missed_blocks = missed(&[(15, FailureKind::Block), (25, FailureKind::Block)]);
+        let empty = MissedBlocks::new();
+        assert_eq!(
+            find_gaps(
+                10,
+                30,
+                &[range(10, 30, &missed_blocks), range(12, 18, &empty)]
+            ),
+            vec![gap(25, 25, GapReason::Missed(FailureKind::Block))]
+        );
+    }
+
+    #[test]
+    fn test_window_boundaries() {
+        let missed_blocks = missed(&[(10, FailureKind::Block), (20, FailureKind::Block)]);
+        // Missed blocks at both edges of the window
+        assert_eq!(
+            find_gaps(10, 20, &[range(10, 20, &missed_blocks)]),
+            vec![
+                gap(10, 10, GapReason::Missed(FailureKind::Block)),
+                gap(20, 20, GapReason::Missed(FailureKind::Block)),
+            ]
+        );
+        let empty = MissedBlocks::new();
+        // One block ranges
+        assert!(find_gaps(20, 20, &[range(10, 20, &empty)]).is_empty());
+        assert!(find_gaps(10, 10, &[range(10, 20, &empty)]).is_empty());
+        // Range ends right before the window, or starts right after it
+        assert_eq!(
+            find_gaps(10, 20, &[range(5, 9, &empty), range(21, 30, &empty)]),
+            vec![gap(10, 20, GapReason::NotIndexed)]
+        );
+        assert_eq!(
+            find_gaps(10, 20, &[range(5, 10, &empty), range(20, 30, &empty)]),
+            vec![gap(11, 19, GapReason::NotIndexed)]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! # Filter
+//! Account allowlist and denylist for migration.
+//!
+use aurora_engine_migration_tool::StateData;
+use aurora_engine_types::types::NEP141Wei;
+use near_sdk::AccountId;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Account filter. Each entry is an exact account ID or a pattern
+/// with `*` wildcard, for ex: `*.aurora`.
+#[derive(Debug, Default, Clone)]
+pub struct AccountFilter {
+    /// If not empty, only matching accounts are allowed
+    pub allow: Vec<String>,
+    /// Matching accounts are excluded
+    pub deny: Vec<String>,
+}
+
+impl AccountFilter {
+    /// Read filter entries from file: one entry per line.
+    /// Empty lines and lines starting with `#` are skipped.
+    pub fn read_list<P: AsRef<Path>>(list_file: P) -> anyhow::Result<Vec<String>> {
+        let data = std::fs::read_to_string(&list_file).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed read account list {}, {e}",
+                list_file.as_ref().display()
+            )
+        })?;
+        Ok(data
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(ToString::to_string)
+            .collect())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    #[must_use]
+    pub fn is_allowed(&self, account: &AccountId) -> bool {
+        let matches = |pattern: &String| wildcard_match(pattern, account.as_str());
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+
+    /// Remove not allowed accounts from the state data and return them with their balances.
+    pub fn apply(&self, data: &mut StateData) -> HashMap<AccountId, NEP141Wei> {
+        if self.is_empty() {
+            return HashMap::new();
+        }
+        let excluded: HashMap<AccountId, NEP141Wei> = data
+            .accounts
+            .iter()
+            .filter(|(account, _)| !self.is_allowed(account))
+            .map(|(account, balance)| (account.clone(), *balance))
+            .collect();
+        for account in excluded.keys() {
+            data.accounts.remove(account);
+            data.storage_balances.remove(account);
+            data.provenance.remove(account);
+        }
+        excluded
+    }
+}
+
+/// Match value with pattern where `*` matches any sequence of characters.
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard: exact match
+        return rest.is_empty();
+    };
+    for part in middle {
+        let Some(index) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[index + part.len()..];
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_match() {
+        assert!(wildcard_match("alice.near", "alice.near"));
+        assert!(!wildcard_match("alice.near", "alice.near2"));
+        assert!(!wildcard_match("alice.near", "malice.near"));
+        assert!(!wildcard_match("", "alice.near"));
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*", "alice.near"));
+        assert!(wildcard_match("*.aurora", "relay.aurora"));
+        assert!(wildcard_match("*.aurora", ".aurora"));
+        assert!(!wildcard_match("*.aurora", "aurora"));
+        assert!(wildcard_match("relay.*", "relay.aurora"));
+        assert!(!wildcard_match("relay.*", "relayer.aurora"));
+        assert!(wildcard_match("a*b*c", "a-b-c"));
+        assert!(wildcard_match("a*b*c", "abc"));
+        assert!(!wildcard_match("a*b*c", "a-c-b"));
+        assert!(wildcard_match("**.near", "alice.near"));
+    }
+
+    #[test]
+    fn test_wildcard_parts_dont_overlap() {
+        assert!(!wildcard_match("a*a", "a"));
+        assert!(wildcard_match("a*a", "aa"));
+        assert!(!wildcard_match("ab*ba", "aba"));
+        assert!(!wildcard_match("*ab*ab", "xab"));
+        assert!(wildcard_match("*ab*ab", "xabab"));
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let account = |account: &str| account.parse::<AccountId>().unwrap();
+        let filter = AccountFilter {
+            allow: vec!["*.near".to_string()],
+            deny: vec!["bad*.near".to_string()],
+        };
+        assert!(filter.is_allowed(&account("alice.near")));
+        assert!(!filter.is_allowed(&account("bad-actor.near")));
+        assert!(!filter.is_allowed(&account("alice.aurora")));
+
+        let filter = AccountFilter {
+            allow: vec![],
+            deny: vec!["alice.near".to_string()],
+        };
+        assert!(filter.is_allowed(&account("alice.aurora")));
+        assert!(!filter.is_allowed(&account("alice.near")));
+        assert!(AccountFilter::default().is_allowed(&account("alice.near")));
+    }
+}
@@ -0,0 +1,336 @@
+//! # Follow
+//! Background balances refresh of the accounts touched by the indexer.
+//! Keeps continuously updated `StateData`, so at pause time only accounts
+//! touched after their last refresh have to be fetched again.
+//!
+use crate::migration::fetch_account_balance;
+use crate::rpc::{Client, RateLimiter};
+use crate::store::write_checkpoint;
+use aurora_engine_migration_tool::{AccountSource, Provenance, StateData};
+use aurora_engine_types::types::NEP141Wei;
+use near_primitives::types::BlockHeight;
+use near_sdk::borsh::BorshDeserialize;
+use near_sdk::json_types::U128;
+use near_sdk::AccountId;
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const SAVE_STATE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Delay after failed balance request, the account is queued again
+const RETRY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Change of the indexed chain
+enum Change {
+    /// Accounts touched in the block
+    Touched(Vec<AccountId>, BlockHeight),
+    /// Accounts touched in the orphaned blocks after the fork block
+    Rollback(Vec<AccountId>, BlockHeight),
+}
+
+/// Handle of the background balances refresh
+pub struct Follower {
+    changes: mpsc::UnboundedSender<Change>,
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Balances refresh task state
+struct Refresher {
+    // Followed state data. Provenance block height of the account is the
+    // last block in which the account was touched before its refresh.
+    state: StateData,
+    state_file: PathBuf,
+    client: Client,
+    contract: String,
+    // Accounts waiting for refresh, in touch order
+    queue: VecDeque<AccountId>,
+    // The last block in which queued accounts were touched
+    queued: HashMap<AccountId, BlockHeight>,
+    // The last block with touched accounts
+    last_touched: BlockHeight,
+    last_saved_time: Instant,
+}
+
+impl Follower {
+    /// Load followed state data file, or start with empty state if it doesn't
+    /// exist, and start balances refresh. Requests share `limiter` with the indexer.
+    pub fn start<P: AsRef<Path>>(
+        state_file: P,
+        contract: &str,
+        limiter: RateLimiter,
+    ) -> anyhow::Result<Self> {
+        let state = load_state(&state_file)?;
+        println!(
+            "Followed accounts: {}, refreshed up to: {:?}",
+            state.accounts.len(),
+            state.block_height
+        );
+        let mut client = Client::new();
+        client.limiter = limiter;
+        let refresher = Refresher {
+            last_touched: state.block_height,
+            state,
+            state_file: state_file.as_ref().to_path_buf(),
+            client,
+            contract: contract.to_string(),
+            queue: VecDeque::new(),
+            queued: HashMap::new(),
+            last_saved_time: Instant::now(),
+        };
+
+        let (changes, changes_rx) = mpsc::unbounded_channel();
+        let (stop, stop_rx) = oneshot::channel();
+        let handle = tokio::spawn(refresher.run(changes_rx, stop_rx));
+        Ok(Self {
+            changes,
+            stop,
+            handle,
+        })
+    }
+
+    /// Queue accounts touched in the block for balance refresh
+    pub fn touch(&self, accounts: Vec<AccountId>, height: BlockHeight) {
+        if !accounts.is_empty() {
+            let _ = self.changes.send(Change::Touched(accounts, height));
+        }
+    }
+
+    /// Roll back touches of the orphaned blocks after the fork block.
+    /// Accounts touched in them are queued for refresh again, their
+    /// balances could be refreshed from the orphaned chain.
+    pub fn rollback(&self, accounts: Vec<AccountId>, fork_height: BlockHeight) {
+        let _ = self.changes.send(Change::Rollback(accounts, fork_height));
+    }
+
+    /// Stop balances refresh and save followed state. Queued accounts are
+    /// left stale, they are fetched at pause time.
+    pub async fn stop(self) -> anyhow::Result<()> {
+        let _ = self.stop.send(());
+        self.handle.await?
+    }
+}
+
+impl Refresher {
+    async fn run(
+        mut self,
+        mut changes: mpsc::UnboundedReceiver<Change>,
+        mut stop: oneshot::Receiver<()>,
+    ) -> anyhow::Result<()> {
+        loop {
+            while let Ok(change) = changes.try_recv() {
+                self.apply(change);
+            }
+            if !matches!(stop.try_recv(), Err(oneshot::error::TryRecvError::Empty)) {
+                break;
+            }
+
+            if let Some(account) = self.queue.pop_front() {
+                self.refresh(account).await;
+            } else {
+                // All touched accounts are refreshed
+                self.state.block_height = self.last_touched;
+                tokio::select! {
+                    change = changes.recv() => match change {
+                        Some(change) => self.apply(change),
+                        None => break,
+                    },
+                    _ = &mut stop => break,
+                }
+            }
+
+            if self.last_saved_time.elapsed() > SAVE_STATE_TIMEOUT {
+                self.last_saved_time = Instant::now();
+                if let Err(e) = self.save().await {
+                    eprintln!("\n[SAVE FAILED: {e}]");
+                }
+            }
+        }
+        self.save().await
+    }
+
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Touched(accounts, height) => {
+                self.last_touched = self.last_touched.max(height);
+                self.enqueue(accounts, height);
+            }
+            Change::Rollback(accounts, fork_height) => {
+                // Orphaned blocks touches are moved to the fork block
+                self.last_touched = self.last_touched.min(fork_height);
+                self.state.block_height = self.state.block_height.min(fork_height);
+                for height in self.queued.values_mut() {
+                    *height = (*height).min(fork_height);
+                }
+                // Balances refreshed from the orphaned chain are stale until
+                // they are refreshed again
+                for account in &accounts {
+                    self.state.provenance.remove(account);
+                }
+                self.enqueue(accounts, fork_height);
+            }
+        }
+    }
+
+    fn enqueue(&mut self, accounts: Vec<AccountId>, height: BlockHeight) {
+        for account in accounts {
+            if self.queued.insert(account.clone(), height).is_none() {
+                self.queue.push_back(account);
+            }
+        }
+    }
+
+    /// Fetch account balance. Failed account is queued again.
+    async fn refresh(&mut self, account: AccountId) {
+        let Some(height) = self.queued.remove(&account) else {
+            return;
+        };
+        match fetch_account_balance(&self.client, &self.contract, &account).await {
+            Ok((balance, storage_balance)) => {
+                self.state
+                    .storage_balances
+                    .insert(account.clone(), storage_balance);
+                self.state.provenance.insert(
+                    account.clone(),
+                    Provenance {
+                        source: AccountSource::Indexed,
+                        block_height: height,
+                    },
+                );
+                self.state.accounts.insert(account, balance);
+            }
+            Err(e) => {
+                eprintln!("\n[REFRESH FAILED: {account}, {e}]");
+                self.queued.insert(account.clone(), height);
+                self.queue.push_back(account);
+                tokio::time::sleep(RETRY_TIMEOUT).await;
+            }
+        }
+    }
+
+    /// Save followed state with the current total supply
+    async fn save(&mut self) -> anyhow::Result<()> {
+        self.client.limiter.wait().await;
+        match self
+            .client
+            .request_view(&self.contract, "ft_total_supply".to_string(), vec![])
+            .await
+            .and_then(|data| {
+                serde_json::from_slice::<U128>(&data)
+                    .map_err(|e| anyhow::anyhow!("Failed deserialize total supply, {e}"))
+            }) {
+            Ok(total_supply) => self.state.total_supply = NEP141Wei::new(total_supply.0),
+            Err(e) => eprintln!("\n[REFRESH FAILED: total supply, {e}]"),
+        }
+        write_checkpoint(&self.state_file, &self.state)
+    }
+}
+
+/// Load followed state data. Return empty state if the file doesn't exist.
+fn load_state<P: AsRef<Path>>(path: P) -> anyhow::Result<StateData> {
+    match std::fs::read(&path) {
+        Ok(data) => StateData::try_from_slice(&data)
+            .map_err(|e| anyhow::anyhow!("Failed deserialize followed state data, {e}")),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(StateData {
+            block_height: 0,
+            total_supply: NEP141Wei::new(0),
+            total_stuck_supply: NEP141Wei::new(0),
+            accounts: HashMap::new(),
+            storage_balances: HashMap::new(),
+            provenance: HashMap::new(),
+            stuck_accounts: HashMap::new(),
+        }),
+        Err(e) => Err(anyhow::anyhow!(
+            "Failed read followed state data file {}, {e}",
+            path.as_ref().display()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+
+    fn account(account: &str) -> AccountId {
+        account.parse().unwrap()
+    }
+
+    fn refresher(dir: &TestDir) -> Refresher {
+        let state = load_state(dir.path("followed.borsh")).unwrap();
+        Refresher {
+            last_touched: state.block_height,
+            state,
+            state_file: dir.path("followed.borsh"),
+            client: Client::new(),
+            contract: "aurora".to_string(),
+            queue: VecDeque::new(),
+            queued: HashMap::new(),
+            last_saved_time: Instant::now(),
+        }
+    }
+
+    fn queued(refresher: &Refresher) -> Vec<(&str, BlockHeight)> {
+        refresher
+            .queue
+            .iter()
+            .map(|account| (account.as_str(), refresher.queued[account]))
+            .collect()
+    }
+
+    #[test]
+    fn test_touch() {
+        let dir = TestDir::new("follow-touch");
+        let mut refresher = refresher(&dir);
+        refresher.apply(Change::Touched(
+            vec![account("alice.near"), account("bob.near")],
+            10,
+        ));
+        refresher.apply(Change::Touched(vec![account("alice.near")], 12));
+        // Account is queued once with the last touch height
+        assert_eq!(queued(&refresher), [("alice.near", 12), ("bob.near", 10)]);
+        assert_eq!(refresher.last_touched, 12);
+    }
+
+    #[test]
+    fn test_rollback_requeues_accounts() {
+        let dir = TestDir::new("follow-rollback");
+        let mut refresher = refresher(&dir);
+        refresher.state.block_height = 12;
+        // Refreshed from the orphaned block 12
+        refresher
+            .state
+            .accounts
+            .insert(account("carol.near"), NEP141Wei::new(5));
+        refresher.state.provenance.insert(
+            account("carol.near"),
+            Provenance {
+                source: AccountSource::Indexed,
+                block_height: 12,
+            },
+        );
+        refresher.apply(Change::Touched(vec![account("alice.near")], 10));
+        refresher.apply(Change::Touched(vec![account("bob.near")], 13));
+
+        refresher.apply(Change::Rollback(
+            vec![account("bob.near"), account("carol.near")],
+            11,
+        ));
+        assert_eq!(
+            queued(&refresher),
+            [("alice.near", 10), ("bob.near", 11), ("carol.near", 11)]
+        );
+        assert_eq!(refresher.last_touched, 11);
+        assert_eq!(refresher.state.block_height, 11);
+        // Orphaned refresh isn't used until the account is refreshed again
+        assert!(refresher.state.provenance.is_empty());
+        assert_eq!(
+            refresher.state.accounts[&account("carol.near")],
+            NEP141Wei::new(5)
+        );
+    }
+}
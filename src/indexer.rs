@@ -1,10 +1,24 @@
-use crate::rpc::{BlockKind, Client, IndexedData};
+use crate::catalogue::Catalogue;
+use crate::coverage::{find_gaps, Gap, IndexedRange};
+use crate::follow::Follower;
+use crate::metrics::METRICS;
+use crate::rpc::{
+    ActionResultLog, BlockData, BlockKind, Client, ExecutionStatus, FailedAccounts, FailureKind,
+    FetchedChunk, IndexedData, IndexedResultLog, MissedBlock, MissedBlocks, MissedBlocksDelta,
+};
+use crate::source::{BlockSource, LakeSource, OUTCOME_BLOCKS};
+use crate::store::{load_checkpoint, load_indexer_data, write_checkpoint, Journal, StoreKind};
+use crate::summary::{Summary, SummaryFormat};
 use near_primitives::hash::CryptoHash;
-use near_primitives::types::BlockHeight;
+use near_primitives::types::{BlockHeight, ShardId};
+use near_primitives::views::StateChangesView;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use std::collections::HashSet;
-use std::io::Write;
+use near_sdk::AccountId;
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::signal::unix::SignalKind;
@@ -12,10 +26,51 @@ use tokio::time::{sleep, Instant};
 
 const SAVE_FILE_TIMEOUT: Duration = Duration::from_secs(60);
 const FORWARD_BLOCK_TIMEOUT: Duration = Duration::from_secs(120);
+/// Number of the last handled blocks kept to detect forks and roll back
+pub(crate) const RECENT_BLOCKS: usize = 32;
+/// Default number of blocks fetched concurrently
+pub const DEFAULT_WORKERS: usize = 8;
+/// Prefix of the versioned `IndexerData` layout. Data files written before
+/// the layout was versioned start with the first block height instead.
+pub const INDEXER_DATA_MAGIC: &[u8; 8] = b"AURIDXB2";
+/// Current `IndexerData` layout version, written after the magic prefix
+pub const INDEXER_DATA_VERSION: u8 = 1;
+
+/// Source of indexed accounts
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IndexMode {
+    /// Parse transactions and receipts actions arguments of known methods
+    #[default]
+    Actions,
+    /// Collect Aurora contract account balance keys changed in the block,
+    /// whatever method or cross-contract call caused the change
+    StateChanges,
+}
+
+impl FromStr for IndexMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "actions" => Ok(Self::Actions),
+            "state-changes" => Ok(Self::StateChanges),
+            _ => anyhow::bail!("Unknown index mode: {s}"),
+        }
+    }
+}
+
+impl std::fmt::Display for IndexMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Actions => write!(f, "actions"),
+            Self::StateChanges => write!(f, "state-changes"),
+        }
+    }
+}
 
 // Information about indexed data that is saved to a file
 // and will be loaded from the file when the program restarts.
-#[derive(Debug, Default, Clone, BorshSerialize, BorshDeserialize)]
+#[derive(Debug, Default, Clone)]
 pub struct IndexerData {
     // Height of the first indexed block
     pub first_block: BlockHeight,
@@ -28,12 +83,252 @@ pub struct IndexerData {
     pub current_block: BlockHeight,
     // Hash of the last successfully processed block with the height last_handled_block.
     pub last_block_hash: Option<CryptoHash>,
-    // A set of blocks that could not be successfully processed.
-    pub missed_blocks: HashSet<BlockHeight>,
+    // Blocks that could not be successfully processed, with failure info.
+    pub missed_blocks: MissedBlocks,
+    // Number of skipped heights: heights without blocks, that were
+    // confirmed by the hash chain and removed from missed blocks.
+    pub skipped_blocks: u64,
+    // Progress of each shard.
+    pub shards: HashMap<ShardId, ShardProgress>,
+    // The last handled blocks, in height order. Used to detect forks.
+    pub recent_blocks: Vec<RecentBlock>,
+    // Handled forks.
+    pub reorgs: Vec<Reorg>,
     // Indexed data: a list of accounts.
     pub data: IndexedData,
 }
 
+// `IndexerData` layout written before versioning: missed blocks without
+// failure info and logs without execution status.
+#[derive(BorshDeserialize)]
+struct LegacyIndexerData {
+    first_block: BlockHeight,
+    last_block: BlockHeight,
+    last_handled_block: BlockHeight,
+    current_block: BlockHeight,
+    last_block_hash: Option<CryptoHash>,
+    missed_blocks: HashSet<BlockHeight>,
+    accounts: HashSet<AccountId>,
+    logs: Vec<LegacyIndexedResultLog>,
+}
+
+#[derive(BorshDeserialize)]
+struct LegacyIndexedResultLog {
+    block_height: BlockHeight,
+    actions: Vec<ActionResultLog>,
+}
+
+impl From<LegacyIndexerData> for IndexerData {
+    fn from(legacy: LegacyIndexerData) -> Self {
+        // Failure reasons are unknown, the whole blocks are fetched again
+        let missed_blocks = legacy
+            .missed_blocks
+            .into_iter()
+            .map(|height| {
+                let missed_block = MissedBlock {
+                    kind: FailureKind::Block,
+                    error: "missed before failure tracking".to_string(),
+                    attempts: 1,
+                    last_attempt: 0,
+                    chunks: vec![],
+                };
+                (height, missed_block)
+            })
+            .collect();
+        let logs = legacy
+            .logs
+            .into_iter()
+            .map(|log| IndexedResultLog {
+                block_height: log.block_height,
+                actions: log.actions,
+                ..IndexedResultLog::default()
+            })
+            .collect();
+        Self {
+            first_block: legacy.first_block,
+            last_block: legacy.last_block,
+            last_handled_block: legacy.last_handled_block,
+            current_block: legacy.current_block,
+            last_block_hash: legacy.last_block_hash,
+            missed_blocks,
+            data: IndexedData {
+                accounts: legacy.accounts,
+                logs,
+                transfer_calls: HashMap::new(),
+            },
+            ..Self::default()
+        }
+    }
+}
+
+impl BorshSerialize for IndexerData {
+    fn serialize<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(INDEXER_DATA_MAGIC)?;
+        INDEXER_DATA_VERSION.serialize(writer)?;
+        self.first_block.serialize(writer)?;
+        self.last_block.serialize(writer)?;
+        self.last_handled_block.serialize(writer)?;
+        self.current_block.serialize(writer)?;
+        self.last_block_hash.serialize(writer)?;
+        self.missed_blocks.serialize(writer)?;
+        self.skipped_blocks.serialize(writer)?;
+        self.shards.serialize(writer)?;
+        self.recent_blocks.serialize(writer)?;
+        self.reorgs.serialize(writer)?;
+        self.data.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for IndexerData {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut magic = [0; INDEXER_DATA_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEXER_DATA_MAGIC {
+            let reader = &mut magic.as_slice().chain(reader);
+            return LegacyIndexerData::deserialize_reader(reader).map(Self::from);
+        }
+        let version = u8::deserialize_reader(reader)?;
+        if version != INDEXER_DATA_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "unsupported indexer data version {version}, expected {INDEXER_DATA_VERSION}"
+                ),
+            ));
+        }
+        Ok(Self {
+            first_block: BorshDeserialize::deserialize_reader(reader)?,
+            last_block: BorshDeserialize::deserialize_reader(reader)?,
+            last_handled_block: BorshDeserialize::deserialize_reader(reader)?,
+            current_block: BorshDeserialize::deserialize_reader(reader)?,
+            last_block_hash: BorshDeserialize::deserialize_reader(reader)?,
+            missed_blocks: BorshDeserialize::deserialize_reader(reader)?,
+            skipped_blocks: BorshDeserialize::deserialize_reader(reader)?,
+            shards: BorshDeserialize::deserialize_reader(reader)?,
+            recent_blocks: BorshDeserialize::deserialize_reader(reader)?,
+            reorgs: BorshDeserialize::deserialize_reader(reader)?,
+            data: BorshDeserialize::deserialize_reader(reader)?,
+        })
+    }
+}
+
+impl IndexerData {
+    // Remove accounts and logs that came from the orphaned blocks, and
+    // restore shards progress and `ft_transfer_call` receipts as they were
+    // before the orphaned blocks. Recent blocks are expected to still
+    // include the orphaned blocks.
+    pub(crate) fn rollback(&mut self, reorg: &Reorg) {
+        for account in &reorg.accounts {
+            self.data.accounts.remove(account);
+        }
+        let orphaned = self.recent_blocks.iter().rev().filter(|block| {
+            reorg
+                .orphaned
+                .iter()
+                .any(|(height, _)| *height == block.height)
+        });
+        for block in orphaned {
+            for (shard_id, shard) in &block.shards {
+                match shard {
+                    Some(shard) => self.shards.insert(*shard_id, shard.clone()),
+                    None => self.shards.remove(shard_id),
+                };
+            }
+            self.data
+                .transfer_calls
+                .extend(block.linked_transfer_calls.iter().copied());
+            for receipt_id in &block.transfer_calls {
+                self.data.transfer_calls.remove(receipt_id);
+            }
+        }
+        self.data.logs.retain(|log| {
+            !reorg
+                .orphaned
+                .iter()
+                .any(|(height, _)| *height == log.block_height)
+        });
+        self.reorgs.push(reorg.clone());
+    }
+}
+
+// Handled block with its contribution to the indexed data.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct RecentBlock {
+    pub height: BlockHeight,
+    pub hash: CryptoHash,
+    // Accounts that were indexed for the first time in the block.
+    pub accounts: Vec<AccountId>,
+    // All accounts indexed in the block.
+    pub touched: Vec<AccountId>,
+    // Number of logs of the block.
+    pub logs: u64,
+    // Progress before the block of the shards changed by the block.
+    pub shards: Vec<(ShardId, Option<ShardProgress>)>,
+    // Receipts created by `ft_transfer_call` receipts of the block.
+    pub transfer_calls: Vec<CryptoHash>,
+    // Receipts created before the block that were linked by its callbacks.
+    pub linked_transfer_calls: Vec<(CryptoHash, CryptoHash)>,
+}
+
+// Fork handled by the indexer: blocks after the last common block were
+// orphaned and their indexed data was rolled back.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct Reorg {
+    // Height and hash of the last common block.
+    pub fork_height: BlockHeight,
+    pub fork_hash: CryptoHash,
+    // Heights and hashes of the orphaned blocks.
+    pub orphaned: Vec<(BlockHeight, CryptoHash)>,
+    // Removed accounts that were indexed only in the orphaned blocks.
+    pub accounts: Vec<AccountId>,
+    // Number of removed logs.
+    pub logs: u64,
+    // Unix time of the rollback in seconds.
+    pub time: u64,
+}
+
+// Shard progress. A block includes a new chunk of the shard only if the chunk
+// `height_included` is the block height, otherwise the chunk is missing and
+// the block carries over the last chunk header of the shard.
+#[derive(Debug, Default, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ShardProgress {
+    // Height of the last block that included a new chunk of the shard.
+    pub last_chunk_block: BlockHeight,
+    // Hash of the last handled chunk of the shard.
+    pub last_chunk_hash: CryptoHash,
+    // Number of handled chunks.
+    pub chunks: u64,
+    // Number of blocks without a new chunk of the shard.
+    pub missing_chunks: u64,
+}
+
+/// Completeness of the indexed blocks range
+#[derive(Debug, PartialEq, Eq)]
+struct RangeSummary {
+    start_block: BlockHeight,
+    stop_block: BlockHeight,
+    handled_up_to: BlockHeight,
+    blocks: u64,
+    handled_blocks: u64,
+    missed_blocks: Vec<BlockHeight>,
+    complete: bool,
+}
+
+impl RangeSummary {
+    fn print(&self) {
+        println!("\nRange: {:?}..={:?}", self.start_block, self.stop_block);
+        println!("Handled up to: {:?}", self.handled_up_to);
+        println!("Blocks in range: {}", self.blocks);
+        println!("Handled blocks: {}", self.handled_blocks);
+        println!(
+            "Missed blocks: [{}] {:?}",
+            self.missed_blocks.len(),
+            self.missed_blocks
+        );
+        println!("Complete: {}", self.complete);
+    }
+}
+
 pub struct Indexer {
     // Data that is saved to a file every SAVE_FILE_TIMEOUT interval.
     pub data: Arc<Mutex<IndexerData>>,
@@ -45,6 +340,98 @@ pub struct Indexer {
     last_saved_time: Instant,
     // The time when the height of the latest block in NEAR was last retrieved.
     last_forward_time: Instant,
+    // Height of the first block of the requested range.
+    start_block: Option<BlockHeight>,
+    // Height of the last block to index. If set, the indexer exits after it.
+    stop_block: Option<BlockHeight>,
+    // Number of blocks fetched concurrently.
+    workers: usize,
+    // Source of indexed accounts.
+    mode: IndexMode,
+    // Engine contract and tracked methods.
+    catalogue: Catalogue,
+    // Blocks being fetched, in height order.
+    pending: VecDeque<(BlockHeight, tokio::task::JoinHandle<FetchedBlock>)>,
+    // Journal store. If not set, data is saved to a borsh file.
+    journal: Option<Journal>,
+    // Local blocks directory in the NEAR Lake layout. If not set,
+    // blocks are fetched from NEAR RPC.
+    lake: Option<PathBuf>,
+    // Followed state data file. If set, balances of touched accounts
+    // are refreshed in the background.
+    follow: Option<PathBuf>,
+    // Background balances refresh, running in follow mode.
+    follower: Option<Follower>,
+}
+
+// Result of block and its indexed content fetching.
+struct FetchedBlock {
+    block: anyhow::Result<BlockData>,
+    content: FetchedContent,
+}
+
+// Block content fetched according to the index mode.
+enum FetchedContent {
+    Chunks(Vec<FetchedChunk>),
+    StateChanges(anyhow::Result<StateChangesView>),
+}
+
+impl FetchedBlock {
+    fn failed(e: anyhow::Error) -> Self {
+        Self {
+            block: Err(e),
+            content: FetchedContent::Chunks(vec![]),
+        }
+    }
+
+    /// Index block content. Failures are recorded in client unresolved blocks.
+    fn index(self, client: &mut Client, height: BlockHeight) -> IndexedData {
+        match self.content {
+            FetchedContent::Chunks(chunks) => client.get_chunk_indexed_data(chunks, height),
+            FetchedContent::StateChanges(changes) => {
+                client.get_state_changes_indexed_data(changes, height)
+            }
+        }
+    }
+
+    /// Update shards progress with the block chunks. Fetched chunks that
+    /// are already handled are dropped, so every chunk is indexed once.
+    /// The last chunk of the shard is kept if the block is lower, as for
+    /// retried missed blocks. Return progress before the block of the
+    /// changed shards.
+    fn track_shards(
+        &mut self,
+        shards: &mut HashMap<ShardId, ShardProgress>,
+        height: BlockHeight,
+    ) -> Vec<(ShardId, Option<ShardProgress>)> {
+        let Ok((_, headers, _, _)) = &self.block else {
+            return vec![];
+        };
+        let mut handled_chunks = vec![];
+        let mut changed_shards = vec![];
+        for header in headers {
+            let previous = shards.get(&header.shard_id).cloned();
+            let shard = shards.entry(header.shard_id).or_default();
+            if header.height_included == height && shard.last_chunk_hash == header.chunk_hash {
+                handled_chunks.push(header.chunk_hash);
+                continue;
+            }
+            changed_shards.push((header.shard_id, previous));
+            if header.height_included == height {
+                if height > shard.last_chunk_block {
+                    shard.last_chunk_block = height;
+                    shard.last_chunk_hash = header.chunk_hash;
+                }
+                shard.chunks += 1;
+            } else {
+                shard.missing_chunks += 1;
+            }
+        }
+        if let FetchedContent::Chunks(chunks) = &mut self.content {
+            chunks.retain(|chunk| !handled_chunks.contains(&chunk.chunk_hash));
+        }
+        changed_shards
+    }
 }
 
 impl Indexer {
@@ -52,15 +439,39 @@ impl Indexer {
     pub fn new<P: AsRef<Path>>(
         data_file: P,
         block_height: Option<BlockHeight>,
+        stop_block: Option<BlockHeight>,
     ) -> anyhow::Result<Self> {
-        // If file doesn't exist just return default data
-        let data = std::fs::read(&data_file).unwrap_or_default();
-        let mut data = IndexerData::try_from_slice(&data).unwrap_or_default();
+        let (journal, mut data) = if Journal::is_journal(&data_file) {
+            // Logs are stored only in the journal
+            let (journal, data) = Journal::open(&data_file)?;
+            (Some(journal), data)
+        } else {
+            // If file doesn't exist just return default data
+            (None, load_checkpoint(&data_file)?.unwrap_or_default())
+        };
 
         if let Some(block_height) = block_height {
-            data.last_block = block_height - 1;
-            if data.first_block > block_height {
-                data.first_block = block_height;
+            if data.first_block == 0 {
+                data.last_block = block_height - 1;
+            } else {
+                // Data file covers the contiguous range of heights from
+                // the first block, indexing resumes after the last block
+                anyhow::ensure!(
+                    block_height >= data.first_block,
+                    "Start block {block_height} is lower than the first block {} of the data file, use another data file for the range",
+                    data.first_block
+                );
+                anyhow::ensure!(
+                    block_height <= data.last_block + 1,
+                    "Start block {block_height} is higher than the next block {} of the data file, heights between them would be left unindexed",
+                    data.last_block + 1
+                );
+                if block_height <= data.last_block {
+                    eprintln!(
+                        "Start block {block_height} is already indexed in the data file, indexing resumes from block {}",
+                        data.last_block + 1
+                    );
+                }
             }
         }
 
@@ -70,77 +481,381 @@ impl Indexer {
             forward_block: None,
             last_saved_time: Instant::now(),
             last_forward_time: Instant::now(),
+            start_block: block_height,
+            stop_block,
+            workers: DEFAULT_WORKERS,
+            mode: IndexMode::default(),
+            catalogue: Catalogue::default(),
+            pending: VecDeque::new(),
+            journal,
+            lake: None,
+            follow: None,
+            follower: None,
         })
     }
 
-    pub async fn stats(&self, extend: bool) {
+    /// Set number of blocks fetched concurrently
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers.max(1);
+    }
+
+    /// Set source of indexed accounts
+    pub fn set_mode(&mut self, mode: IndexMode) {
+        self.mode = mode;
+    }
+
+    /// Set data file format. Borsh data file is converted to the journal,
+    /// but journal isn't converted back.
+    pub fn set_store(&mut self, store: StoreKind) -> anyhow::Result<()> {
+        match store {
+            StoreKind::Journal if self.journal.is_none() => {
+                let mut data = self.data.lock().unwrap();
+                self.journal = Some(Journal::create(&self.data_file, &data)?);
+                data.data.logs.clear();
+                println!("Data file converted to journal");
+            }
+            StoreKind::Borsh if self.journal.is_some() => {
+                anyhow::bail!("Data file is a journal, it can't be used as borsh file")
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Set engine contract and tracked methods
+    pub fn set_catalogue(&mut self, catalogue: Catalogue) {
+        self.catalogue = catalogue;
+    }
+
+    /// Set local blocks directory in the NEAR Lake layout, blocks are
+    /// read from it instead of NEAR RPC
+    pub fn set_lake<P: AsRef<Path>>(&mut self, dir: P) {
+        self.lake = Some(dir.as_ref().to_path_buf());
+    }
+
+    /// Set followed state data file, balances of the accounts touched by
+    /// the indexer are refreshed in it in the background
+    pub fn set_follow<P: AsRef<Path>>(&mut self, state_file: P) {
+        self.follow = Some(state_file.as_ref().to_path_buf());
+    }
+
+    /// Blocks source: local NEAR Lake directory or NEAR RPC
+    fn block_source(&self, client: &Client) -> Arc<dyn BlockSource> {
+        match &self.lake {
+            Some(dir) => Arc::new(LakeSource::new(dir, client.catalogue.clone())),
+            None => Arc::new(client.fetcher()),
+        }
+    }
+
+    pub async fn stats(&self, extend: bool, format: SummaryFormat) -> anyhow::Result<()> {
         let mut client = Client::new();
         let height = if let Ok(block) = client.get_block(BlockKind::Latest).await {
             block.0
         } else {
             0
         };
-        let data = self.data.lock().unwrap();
+        self.stats_summary(height, extend, format)?.finish();
+        Ok(())
+    }
 
+    /// Stats summary of the indexed data, `height` is the current block
+    fn stats_summary(
+        &self,
+        height: BlockHeight,
+        extend: bool,
+        format: SummaryFormat,
+    ) -> anyhow::Result<Summary> {
+        // Logs are counted while they are read, all logs are kept only
+        // to print them
+        let mut logs = vec![];
+        let mut failed_logs = vec![];
+        let (mut logs_count, mut failed_logs_count) = (0, 0);
+        let mut failed_accounts = FailedAccounts::default();
+        let mut on_log = |log: IndexedResultLog| {
+            failed_accounts.add(&log);
+            if log.status == ExecutionStatus::Failure {
+                failed_logs_count += 1;
+                if extend {
+                    failed_logs.push(log);
+                }
+            } else {
+                logs_count += 1;
+                if extend {
+                    logs.push(log);
+                }
+            }
+        };
+        // Journal logs are streamed from the logs file
+        let data = if self.journal.is_some() {
+            load_indexer_data(&self.data_file, on_log)?
+        } else {
+            let data = self.data.lock().unwrap();
+            data.data.logs.iter().cloned().for_each(&mut on_log);
+            IndexerData {
+                first_block: data.first_block,
+                last_block: data.last_block,
+                current_block: data.current_block,
+                missed_blocks: data.missed_blocks.clone(),
+                skipped_blocks: data.skipped_blocks,
+                shards: data.shards.clone(),
+                reorgs: data.reorgs.clone(),
+                data: IndexedData {
+                    accounts: data.data.accounts.clone(),
+                    ..IndexedData::default()
+                },
+                ..IndexerData::default()
+            }
+        };
+
+        let mut summary = Summary::new(format);
         if extend {
-            println!("Logs: {:#?}\n", data.data.logs);
-            println!(
-                "Missed block list: [{}] {:?}\n",
-                data.missed_blocks.len(),
-                data.missed_blocks
+            summary.text(format_args!("Logs: {logs:#?}\n"));
+            summary.text(format_args!("Failed logs: {failed_logs:#?}\n"));
+
+            let mut missed_blocks: Vec<_> = data.missed_blocks.iter().collect();
+            missed_blocks.sort_by_key(|(height, _)| **height);
+            summary.text(format_args!("Missed block list: [{}]", missed_blocks.len()));
+            for (height, missed_block) in &missed_blocks {
+                summary.text(format_args!(
+                    "\t{height:?}: {}, failed chunks: {}, attempts: {}, last attempt: {}, error: {}",
+                    missed_block.kind,
+                    missed_block.chunks.len(),
+                    missed_block.attempts,
+                    missed_block.last_attempt,
+                    missed_block.error
+                ));
+            }
+            summary.set(
+                "missed_block_list",
+                missed_blocks
+                    .iter()
+                    .map(|(height, missed_block)| {
+                        json!({
+                            "height": height,
+                            "kind": missed_block.kind.to_string(),
+                            "failed_chunks": missed_block.chunks.len(),
+                            "attempts": missed_block.attempts,
+                            "last_attempt": missed_block.last_attempt,
+                            "error": missed_block.error,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            let count = |f: fn(&FailureKind) -> bool| {
+                missed_blocks
+                    .iter()
+                    .filter(|(_, missed_block)| f(&missed_block.kind))
+                    .count()
+            };
+            let by_kind = [
+                (
+                    "unknown_block",
+                    count(|kind| matches!(kind, FailureKind::UnknownBlock)),
+                ),
+                ("block", count(|kind| matches!(kind, FailureKind::Block))),
+                (
+                    "chunk",
+                    count(|kind| matches!(kind, FailureKind::Chunk { .. })),
+                ),
+                (
+                    "state_changes",
+                    count(|kind| matches!(kind, FailureKind::StateChanges)),
+                ),
+                (
+                    "outcome",
+                    count(|kind| matches!(kind, FailureKind::Outcome { .. })),
+                ),
+            ];
+            summary.text(format_args!(
+                "Missed blocks by kind: unknown block: {}, block: {}, chunk: {}, state changes: {}, outcome: {}\n",
+                by_kind[0].1, by_kind[1].1, by_kind[2].1, by_kind[3].1, by_kind[4].1,
+            ));
+            summary.set(
+                "missed_blocks_by_kind",
+                by_kind
+                    .iter()
+                    .map(|(kind, count)| ((*kind).to_string(), json!(count)))
+                    .collect::<serde_json::Map<_, _>>(),
             );
+
+            summary.text(format_args!("Reorgs: [{}]", data.reorgs.len()));
+            for reorg in &data.reorgs {
+                summary.text(format_args!(
+                    "\tfork block: {:?} {}, time: {}, orphaned blocks: {:?}, removed accounts: {:?}, removed logs: {}",
+                    reorg.fork_height,
+                    reorg.fork_hash,
+                    reorg.time,
+                    reorg.orphaned,
+                    reorg.accounts,
+                    reorg.logs
+                ));
+            }
+            summary.set(
+                "reorg_list",
+                data.reorgs
+                    .iter()
+                    .map(|reorg| {
+                        json!({
+                            "fork_height": reorg.fork_height,
+                            "fork_hash": reorg.fork_hash.to_string(),
+                            "time": reorg.time,
+                            "orphaned_blocks": reorg.orphaned.iter().map(|(height, _)| height).collect::<Vec<_>>(),
+                            "removed_accounts": reorg.accounts,
+                            "removed_logs": reorg.logs,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            summary.text("");
         }
 
-        println!(r#"First block: {:?}"#, data.first_block);
-        println!("Last block: {:?}", data.last_block);
-        println!("Last saved in current block: {:?}", data.current_block);
-        println!("Current block: {height:?}");
-        println!("Missed blocks: {}", data.missed_blocks.len());
-        println!("Accounts: {}", data.data.accounts.len());
+        summary.field("first_block", "First block", data.first_block);
+        summary.field("last_block", "Last block", data.last_block);
+        summary.field(
+            "last_saved_current_block",
+            "Last saved in current block",
+            data.current_block,
+        );
+        summary.field("current_block", "Current block", height);
+        summary.field("missed_blocks", "Missed blocks", data.missed_blocks.len());
+        summary.field("skipped_blocks", "Skipped blocks", data.skipped_blocks);
+        summary.field("reorgs", "Reorgs", data.reorgs.len());
+        let mut shards: Vec<_> = data.shards.iter().collect();
+        shards.sort_by_key(|(shard_id, _)| **shard_id);
+        for (shard_id, shard) in &shards {
+            summary.text(format_args!(
+                "Shard {shard_id}: last chunk block: {:?}, chunks: {}, missing chunks: {}",
+                shard.last_chunk_block, shard.chunks, shard.missing_chunks
+            ));
+        }
+        summary.set(
+            "shards",
+            shards
+                .iter()
+                .map(|(shard_id, shard)| {
+                    json!({
+                        "shard_id": shard_id,
+                        "last_chunk_block": shard.last_chunk_block,
+                        "chunks": shard.chunks,
+                        "missing_chunks": shard.missing_chunks,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        );
+        summary.field("accounts", "Accounts", data.data.accounts.len());
+        summary.field("logs", "Logs", logs_count);
+        summary.field("failed_logs", "Failed logs", failed_logs_count);
+        summary.field(
+            "failed_only_accounts",
+            "Accounts only in failed actions",
+            failed_accounts.failed_only().len(),
+        );
+        Ok(summary)
     }
 
-    /// Save indexed data
+    /// Save indexed data checkpoint
     fn save_data<P: AsRef<Path>>(
         data: &IndexerData,
         data_file: P,
         current_block_height: BlockHeight,
         first_handled_block_height: BlockHeight,
         last_handled_block_height: BlockHeight,
-    ) {
-        std::fs::write(data_file, data.try_to_vec().expect("Failed serialize"))
-            .expect("Failed save indexed data");
+    ) -> anyhow::Result<()> {
+        write_checkpoint(data_file, data)?;
         println!(
             " [SAVE: current block: {current_block_height:?}, \
                           first handled block: {first_handled_block_height:?}, \
                           last handled block: {last_handled_block_height:?}]"
         );
+        Ok(())
     }
 
     /// Set current index data
     pub fn set_indexed_data(
         &mut self,
         indexed_data: IndexedData,
-        missed_blocks: HashSet<BlockHeight>,
+        shards: Vec<(ShardId, Option<ShardProgress>)>,
+        missed_blocks: &MissedBlocksDelta,
         current_block: BlockHeight,
-        first_block: BlockHeight,
         last_block: BlockHeight,
         block_hash: CryptoHash,
-    ) {
+    ) -> anyhow::Result<()> {
         let mut data = self.data.lock().unwrap();
-        data.first_block = first_block;
-        if data.first_block == 0 {
-            data.first_block = last_block;
-        }
         data.last_block = last_block;
         data.last_handled_block = last_block;
         data.current_block = current_block;
-        for account in indexed_data.accounts {
-            data.data.accounts.insert(account);
-        }
-        let mut logs = indexed_data.logs;
-        data.data.logs.append(&mut logs);
-        data.missed_blocks = missed_blocks;
         data.last_block_hash = Some(block_hash);
+        let recent_block = RecentBlock {
+            height: last_block,
+            hash: block_hash,
+            accounts: indexed_data
+                .accounts
+                .iter()
+                .filter(|account| !data.data.accounts.contains(*account))
+                .cloned()
+                .collect(),
+            touched: indexed_data.accounts.iter().cloned().collect(),
+            logs: indexed_data.logs.len() as u64,
+            shards,
+            transfer_calls: indexed_data.transfer_calls.keys().copied().collect(),
+            linked_transfer_calls: indexed_data
+                .logs
+                .iter()
+                .filter_map(|log| log.receipt_id)
+                .filter_map(|receipt_id| {
+                    let origin = data.data.transfer_calls.get(&receipt_id)?;
+                    Some((receipt_id, *origin))
+                })
+                .collect(),
+        };
+        data.recent_blocks.push(recent_block);
+        let excess = data.recent_blocks.len().saturating_sub(RECENT_BLOCKS);
+        data.recent_blocks.drain(..excess);
+        missed_blocks.apply(&mut data.missed_blocks);
+        if let Some(journal) = &mut self.journal {
+            // Logs are linked by merge and kept only in the journal
+            let accounts = indexed_data.accounts.clone();
+            let transfer_calls = indexed_data.transfer_calls.clone();
+            data.data.merge(indexed_data);
+            let block_data = IndexedData {
+                accounts,
+                logs: std::mem::take(&mut data.data.logs),
+                transfer_calls,
+            };
+            journal.append_block(&data, &block_data, missed_blocks)?;
+        } else {
+            data.data.merge(indexed_data);
+        }
+        METRICS.set_block(
+            last_block,
+            data.missed_blocks.len(),
+            data.data.accounts.len(),
+        );
+        Ok(())
+    }
+
+    /// Apply missed blocks changes, and save data to the borsh file, or
+    /// append cursor and the changes to the journal and flush it to disk
+    fn save(&mut self, missed_blocks: &MissedBlocksDelta) -> anyhow::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        missed_blocks.apply(&mut data.missed_blocks);
+        if let Some(journal) = &mut self.journal {
+            journal.append_cursor(&data, missed_blocks)?;
+            journal.sync(&data)?;
+            println!(
+                " [SAVE: current block: {:?}, first handled block: {:?}, last handled block: {:?}]",
+                data.current_block, data.first_block, data.last_handled_block
+            );
+            Ok(())
+        } else {
+            Self::save_data(
+                &data,
+                &self.data_file,
+                data.current_block,
+                data.first_block,
+                data.last_handled_block,
+            )
+        }
     }
 
     fn shutdown_listener() -> tokio::sync::mpsc::Receiver<()> {
@@ -168,102 +883,1054 @@ impl Indexer {
         rx
     }
 
+    /// Completeness summary for the indexed blocks range. Only blocks
+    /// actually handled in the data file are counted.
+    fn range_summary(
+        data: &IndexerData,
+        start_block: BlockHeight,
+        stop_block: BlockHeight,
+    ) -> RangeSummary {
+        let gaps = find_gaps(start_block, stop_block, &[IndexedRange::from(data)]);
+        let mut missed_blocks: Vec<_> = data
+            .missed_blocks
+            .keys()
+            .copied()
+            .filter(|height| (start_block..=stop_block).contains(height))
+            .collect();
+        missed_blocks.sort_unstable();
+        let blocks = (stop_block + 1).saturating_sub(start_block);
+        let gap_blocks: u64 = gaps.iter().map(Gap::blocks).sum();
+        RangeSummary {
+            start_block,
+            stop_block,
+            handled_up_to: data.last_handled_block.min(stop_block),
+            blocks,
+            handled_blocks: blocks.saturating_sub(gap_blocks),
+            missed_blocks,
+            complete: gaps.is_empty(),
+        }
+    }
+
+    /// Check is the last block of the range already handled
+    fn is_stop_block_reached(&self) -> bool {
+        self.stop_block.map_or(false, |stop_block| {
+            self.data.lock().unwrap().last_block >= stop_block
+        })
+    }
+
     /// Run indexing
     pub async fn run(&mut self) -> anyhow::Result<()> {
         let mut client = Client::new();
+        client.set_catalogue(self.catalogue.clone());
         let missed_blocks = self.data.lock().unwrap().missed_blocks.clone();
         client.set_missed_blocks(missed_blocks);
+        let source = self.block_source(&client);
+        if let Some(state_file) = &self.follow {
+            self.follower = Some(Follower::start(
+                state_file,
+                &self.catalogue.contract,
+                client.limiter.clone(),
+            )?);
+        }
         let last_block = self.data.lock().unwrap().last_block;
         println!("Starting height: {last_block}");
+        if let Some(stop_block) = self.stop_block {
+            println!("Stop height: {stop_block}");
+        }
         let mut handle = None;
 
         let mut shutdown_stream = Self::shutdown_listener();
         loop {
+            if self.is_stop_block_reached() {
+                break;
+            }
             tokio::select! {
-                h = self.handle_block(&mut client) => handle = h,
+                h = self.handle_block(&mut client, &source) => match h {
+                    Ok(Some(h)) => handle = Some(h),
+                    Ok(None) => (),
+                    Err(e) => {
+                        // Journal wasn't appended, its last complete record
+                        // is the saved state
+                        self.clear_pending();
+                        if let Some(follower) = self.follower.take() {
+                            follower.stop().await?;
+                        }
+                        return Err(e);
+                    }
+                },
                 _ = shutdown_stream.recv() => break,
                 else => break,
             }
         }
 
-        // Wait for data saving
+        self.clear_pending();
+
+        // Wait for periodic data saving, then save the final state
         if let Some(handle) = handle {
-            handle.await.map_err(Into::into)
+            handle.await?;
+        }
+        self.save(&client.take_missed_blocks_delta())?;
+        if let Some(follower) = self.follower.take() {
+            follower.stop().await?;
+        }
+
+        // For blocks range print summary
+        if let Some(stop_block) = self.stop_block {
+            let data = self.data.lock().unwrap();
+            let start_block = self.start_block.unwrap_or(data.first_block);
+            Self::range_summary(&data, start_block, stop_block).print();
+        }
+
+        Ok(())
+    }
+
+    /// Retry fetching of missed blocks with all their chunks. Indexed data
+    /// of resolved blocks is merged. Print heights that are still unresolved
+    /// with the last error for each.
+    pub async fn retry_missed(&mut self, archival: bool) -> anyhow::Result<()> {
+        let mut client = if archival {
+            Client::new_archival()
         } else {
-            Ok(())
+            Client::new()
+        };
+        client.set_catalogue(self.catalogue.clone());
+        let missed_blocks = self.data.lock().unwrap().missed_blocks.clone();
+        let mut heights: Vec<BlockHeight> = missed_blocks.keys().copied().collect();
+        heights.sort_unstable();
+        client.set_missed_blocks(missed_blocks.clone());
+        let source = self.block_source(&client);
+        println!("Missed blocks: {}", heights.len());
+
+        let mut resolved = 0;
+        let mut heights = heights.into_iter();
+        let mut shutdown_stream = Self::shutdown_listener();
+        loop {
+            while self.pending.len() < self.workers {
+                let Some(height) = heights.next() else {
+                    break;
+                };
+                // Only failed chunks of the block are fetched again
+                let failed_chunks = missed_blocks
+                    .get(&height)
+                    .map(|missed_block| missed_block.chunks.clone())
+                    .unwrap_or_default();
+                let handle = tokio::spawn(Self::fetch_block(
+                    source.clone(),
+                    height,
+                    self.mode,
+                    failed_chunks,
+                ));
+                self.pending.push_back((height, handle));
+            }
+            let Some((height, handle)) = self.pending.front_mut() else {
+                break;
+            };
+            let height = *height;
+            let mut fetched = tokio::select! {
+                fetched = handle => fetched.unwrap_or_else(|e| FetchedBlock::failed(e.into())),
+                _ = shutdown_stream.recv() => break,
+            };
+            self.pending.pop_front();
+
+            print!("\rHeight: {height:?}");
+            std::io::stdout().flush().expect("Flush failed");
+
+            // Shards progress is tracked once the block is fetched. Content
+            // failures of the tracked block keep their kind.
+            let missed_block = &missed_blocks[&height];
+            let is_tracked = !matches!(
+                missed_block.kind,
+                FailureKind::Block | FailureKind::UnknownBlock
+            ) || !missed_block.chunks.is_empty();
+            if let Err(e) = &fetched.block {
+                let kind = if is_tracked {
+                    missed_block.kind
+                } else {
+                    Client::block_failure_kind(e)
+                };
+                client.set_unresolved(height, kind, e);
+                continue;
+            }
+            let mut data = self.data.lock().unwrap();
+            let shards: Vec<_> = if is_tracked {
+                vec![]
+            } else {
+                fetched
+                    .track_shards(&mut data.shards, height)
+                    .into_iter()
+                    .map(|(shard_id, _)| shard_id)
+                    .collect()
+            };
+            // Indexed data of resolved chunks is merged even if other chunks
+            // of the block failed, only failed chunks are retried next time
+            let indexed_data = fetched.index(&mut client, height);
+            let missed_blocks = client.take_missed_blocks_delta();
+            missed_blocks.apply(&mut data.missed_blocks);
+            if let Some(journal) = &mut self.journal {
+                // Callbacks of the next blocks are linked and replaced logs
+                // are skipped when logs are read
+                let accounts = indexed_data.accounts.clone();
+                let transfer_calls = indexed_data.transfer_calls.clone();
+                data.data.merge(indexed_data);
+                let block_data = IndexedData {
+                    accounts,
+                    logs: std::mem::take(&mut data.data.logs),
+                    transfer_calls,
+                };
+                journal.append_retry(&data, &block_data, &shards, &missed_blocks)?;
+            } else {
+                data.data.merge_retried(indexed_data);
+            }
+            if !client.unresolved_blocks.contains_key(&height) {
+                resolved += 1;
+            }
+        }
+        self.clear_pending();
+
+        {
+            let mut data = self.data.lock().unwrap();
+            data.data.logs.sort_by_key(|log| log.block_height);
+            // Resolved blocks could be merged after blocks with their callbacks
+            data.data.link_transfer_calls();
+        }
+        self.save(&client.take_missed_blocks_delta())?;
+
+        let data = self.data.lock().unwrap();
+        let mut unresolved: Vec<_> = data.missed_blocks.iter().collect();
+        unresolved.sort_by_key(|(height, _)| **height);
+        println!("Resolved blocks: {resolved}");
+        println!("Unresolved blocks: {}", unresolved.len());
+        for (height, missed_block) in unresolved {
+            println!(
+                "\t{height:?}: {}, attempts: {}, error: {}",
+                missed_block.kind, missed_block.attempts, missed_block.error
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Abort fetching of pending blocks
+    fn clear_pending(&mut self) {
+        for (_, handle) in self.pending.drain(..) {
+            handle.abort();
+        }
+    }
+
+    /// The highest block to index for the latest final block `final_block`.
+    /// Receipts of the block are executed in the next blocks, so in actions
+    /// mode the block is indexed only when `OUTCOME_BLOCKS` blocks from it are
+    /// final and execution outcomes of its receipts can be proved.
+    fn max_block(&self, final_block: BlockHeight) -> BlockHeight {
+        let max_block = match self.mode {
+            IndexMode::Actions => final_block.saturating_sub(OUTCOME_BLOCKS - 1),
+            IndexMode::StateChanges => final_block,
+        };
+        self.stop_block
+            .map_or(max_block, |stop_block| stop_block.min(max_block))
+    }
+
+    /// Schedule concurrent fetching of the next blocks, not higher than `max_block`
+    fn schedule_blocks(&mut self, source: &Arc<dyn BlockSource>, max_block: BlockHeight) {
+        let next_block = self.pending.back().map_or_else(
+            || self.data.lock().unwrap().last_block + 1,
+            |(height, _)| height + 1,
+        );
+        let free_workers = self.workers.saturating_sub(self.pending.len());
+        for height in (next_block..=max_block).take(free_workers) {
+            let handle = tokio::spawn(Self::fetch_block(source.clone(), height, self.mode, vec![]));
+            self.pending.push_back((height, handle));
+        }
+    }
+
+    /// Fetch block and its new chunks or state changes, depending on mode.
+    /// Chunks carried over from the previous blocks are not fetched.
+    /// If `failed_chunks` are set, only these chunks are fetched.
+    async fn fetch_block(
+        source: Arc<dyn BlockSource>,
+        height: BlockHeight,
+        mode: IndexMode,
+        failed_chunks: Vec<CryptoHash>,
+    ) -> FetchedBlock {
+        match source.get_block(BlockKind::Height(height)).await {
+            Ok(block) => {
+                let content = match mode {
+                    IndexMode::Actions => {
+                        let chunks: Vec<_> = block
+                            .1
+                            .iter()
+                            .filter(|chunk| {
+                                if failed_chunks.is_empty() {
+                                    chunk.height_included == height
+                                } else {
+                                    failed_chunks.contains(&chunk.chunk_hash)
+                                }
+                            })
+                            .cloned()
+                            .collect();
+                        FetchedContent::Chunks(source.get_chunks(&chunks).await)
+                    }
+                    IndexMode::StateChanges => FetchedContent::StateChanges(
+                        source.get_state_changes(height, block.2).await,
+                    ),
+                };
+                FetchedBlock {
+                    block: Ok(block),
+                    content,
+                }
+            }
+            Err(e) => FetchedBlock::failed(e),
+        }
+    }
+
+    /// Handle block that isn't linked to the last handled block. Find the last
+    /// common block of the indexed chain and the chain of the block, and roll
+    /// back indexed data of the orphaned blocks after it. Indexing continues
+    /// after the last common block.
+    async fn handle_fork(
+        &mut self,
+        client: &mut Client,
+        source: &Arc<dyn BlockSource>,
+        prev_block_hash: CryptoHash,
+    ) -> anyhow::Result<()> {
+        let recent_blocks = self.data.lock().unwrap().recent_blocks.clone();
+        let mut fork_block = recent_blocks
+            .iter()
+            .rposition(|block| block.hash == prev_block_hash);
+        if fork_block.is_none() {
+            // Parent block isn't indexed yet or belongs to another chain:
+            // find the newest recent block that is still in the chain
+            for (i, block) in recent_blocks.iter().enumerate().rev() {
+                match source.get_block(BlockKind::Height(block.height)).await {
+                    Ok((_, _, hash, _)) if hash == block.hash => {
+                        fork_block = Some(i);
+                        break;
+                    }
+                    Ok(_) => (),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        let mut data = self.data.lock().unwrap();
+        let Some(fork_block) = fork_block else {
+            if !recent_blocks.is_empty() {
+                println!(
+                    "\n[REORG: fork point not found in the last {} blocks, height: {:?}]",
+                    recent_blocks.len(),
+                    data.last_handled_block
+                );
+            }
+            data.last_block = data.last_handled_block;
+            return Ok(());
+        };
+        let fork = &recent_blocks[fork_block];
+        let orphaned = &recent_blocks[fork_block + 1..];
+        data.last_block = fork.height;
+        if orphaned.is_empty() {
+            // Parent block was missed, fetch it again
+            return Ok(());
         }
+
+        let reorg = Reorg {
+            fork_height: fork.height,
+            fork_hash: fork.hash,
+            orphaned: orphaned
+                .iter()
+                .map(|block| (block.height, block.hash))
+                .collect(),
+            accounts: orphaned
+                .iter()
+                .flat_map(|block| block.accounts.iter().cloned())
+                .collect(),
+            logs: orphaned.iter().map(|block| block.logs).sum(),
+            time: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+        };
+        data.last_handled_block = fork.height;
+        data.last_block_hash = Some(fork.hash);
+        // Orphaned blocks failures are not relevant anymore
+        client.retain_unresolved(|height| height <= fork.height);
+        let missed_blocks = client.take_missed_blocks_delta();
+        missed_blocks.apply(&mut data.missed_blocks);
+        data.rollback(&reorg);
+        data.recent_blocks.truncate(fork_block + 1);
+        if let Some(journal) = &mut self.journal {
+            journal.append_rollback(&data, &reorg, &missed_blocks)?;
+        }
+        // Balances refreshed after the orphaned blocks are refreshed again
+        if let Some(follower) = &self.follower {
+            follower.rollback(
+                orphaned
+                    .iter()
+                    .flat_map(|block| block.touched.iter().cloned())
+                    .collect(),
+                fork.height,
+            );
+        }
+        println!(
+            "\n[REORG: fork block: {:?} {}, orphaned blocks: {:?}, removed accounts: {}, removed logs: {}]",
+            reorg.fork_height,
+            reorg.fork_hash,
+            reorg.orphaned.iter().map(|(height, _)| height).collect::<Vec<_>>(),
+            reorg.accounts.len(),
+            reorg.logs
+        );
+        Ok(())
     }
 
-    /// Handle fetching blocks
-    async fn handle_block(&mut self, client: &mut Client) -> Option<tokio::task::JoinHandle<()>> {
-        let last_block = self.data.lock().unwrap().last_block + 1;
-        let first_block = self.data.lock().unwrap().first_block;
+    /// Handle fetching blocks. Blocks are fetched concurrently,
+    /// but applied to the indexed data strictly in height order.
+    async fn handle_block(
+        &mut self,
+        client: &mut Client,
+        source: &Arc<dyn BlockSource>,
+    ) -> anyhow::Result<Option<tokio::task::JoinHandle<()>>> {
         let mut current_height = self.forward_block.unwrap_or_default();
 
         if self.forward_block.is_none() || self.last_forward_time.elapsed() > FORWARD_BLOCK_TIMEOUT
         {
             self.last_forward_time = Instant::now();
-            if let Ok(block) = client.get_block(BlockKind::Latest).await {
+            if let Ok(block) = source.get_block(BlockKind::Latest).await {
                 self.forward_block = Some(block.0);
-                current_height = block.0
+                current_height = block.0;
+                METRICS.set_tip_height(current_height);
             }
         }
 
-        let block = if last_block > current_height {
+        let max_block = self.max_block(current_height);
+        self.schedule_blocks(source, max_block);
+
+        let Some((last_block, handle)) = self.pending.front_mut() else {
             println!("Reached the latest block. Sleep: {FORWARD_BLOCK_TIMEOUT:?}");
             sleep(FORWARD_BLOCK_TIMEOUT).await;
-            None
-        } else if let Ok(block) = client.get_block(BlockKind::Height(last_block)).await {
-            Some(block)
-        } else {
-            // If block not found do not fail, just increment height
+            return Ok(None);
+        };
+        let last_block = *last_block;
+        let mut fetched = handle
+            .await
+            .unwrap_or_else(|e| FetchedBlock::failed(e.into()));
+        self.pending.pop_front();
+
+        // The first processed block starts the range of the data file,
+        // failed blocks of the range are kept as missed blocks
+        let first_block = {
             let mut data = self.data.lock().unwrap();
-            data.last_block = last_block;
-            None
+            if data.first_block == 0 {
+                data.first_block = last_block;
+            }
+            data.first_block
         };
 
-        let (_, chunks, block_hash, prev_block_hash) = block?;
+        let (block_hash, prev_block_hash) = match &fetched.block {
+            Ok((_, _, block_hash, prev_block_hash)) => (*block_hash, *prev_block_hash),
+            Err(e) => {
+                // If block not found do not fail, just increment height
+                client.set_unresolved(last_block, Client::block_failure_kind(e), e);
+                let mut data = self.data.lock().unwrap();
+                data.last_block = last_block;
+                return Ok(None);
+            }
+        };
 
-        let last_block_hash = self.data.lock().unwrap().last_block_hash;
+        let (last_block_hash, last_handled_block) = {
+            let data = self.data.lock().unwrap();
+            (data.last_block_hash, data.last_handled_block)
+        };
         if let Some(block_hash) = last_block_hash {
             if block_hash != prev_block_hash {
-                let mut data = self.data.lock().unwrap();
-                data.last_block = data.last_handled_block;
-                return None;
+                self.handle_fork(client, source, prev_block_hash).await?;
+                // Blocks after mismatched block should be fetched again
+                self.clear_pending();
+                return Ok(None);
+            }
+
+            // The block is linked to the last handled block, so heights
+            // between them are skipped and have no blocks at all.
+            for height in last_handled_block + 1..last_block {
+                if client.resolve(height) {
+                    self.data.lock().unwrap().skipped_blocks += 1;
+                }
             }
         }
 
         print!("\rHeight: {last_block:?}");
         std::io::stdout().flush().expect("Flush failed");
 
-        let indexed_data = client.get_chunk_indexed_data(chunks, last_block).await;
+        let shards = fetched.track_shards(&mut self.data.lock().unwrap().shards, last_block);
+        let indexed_data = fetched.index(client, last_block);
+        if let Some(follower) = &self.follower {
+            follower.touch(indexed_data.accounts.iter().cloned().collect(), last_block);
+        }
         self.set_indexed_data(
             indexed_data,
-            client.unresolved_blocks.clone(),
+            shards,
+            &client.take_missed_blocks_delta(),
             current_height,
-            first_block,
             last_block,
             block_hash,
-        );
+        )?;
 
         // Save data
         if self.last_saved_time.elapsed() > SAVE_FILE_TIMEOUT {
             self.last_saved_time = Instant::now();
+            if self.journal.is_some() {
+                // Blocks are already in the journal, just flush it
+                if let Err(e) = self.save(&MissedBlocksDelta::default()) {
+                    println!("\n[SAVE FAILED: {e}]");
+                }
+                return Ok(None);
+            }
             let current_block_height = current_height;
             let data_file = self.data_file.clone();
             let data = self.data.lock().unwrap().clone();
 
-            Some(tokio::spawn(async move {
-                Self::save_data(
+            // Failed checkpoint keeps the previous one, it's retried on the next save
+            Ok(Some(tokio::spawn(async move {
+                if let Err(e) = Self::save_data(
                     &data,
                     &data_file,
                     current_block_height,
                     first_block,
                     last_block,
-                );
-            }))
+                ) {
+                    println!("\n[SAVE FAILED: {e}]");
+                }
+            })))
         } else {
-            None
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::BoxFuture;
+    use crate::test_utils::TestDir;
+
+    fn new_indexer(stop_block: Option<BlockHeight>) -> Indexer {
+        let data_file = std::env::temp_dir().join("indexer-test-not-existing.borsh");
+        Indexer::new(data_file, None, stop_block).unwrap()
+    }
+
+    #[test]
+    fn test_max_block() {
+        let mut indexer = new_indexer(None);
+        // Outcomes of the receipts are proved when the next 7 blocks are final
+        assert_eq!(indexer.max_block(100), 93);
+        assert_eq!(indexer.max_block(3), 0);
+
+        indexer.set_mode(IndexMode::StateChanges);
+        assert_eq!(indexer.max_block(100), 100);
+
+        let mut indexer = new_indexer(Some(90));
+        assert_eq!(indexer.max_block(100), 90);
+        assert_eq!(indexer.max_block(95), 88);
+        indexer.set_mode(IndexMode::StateChanges);
+        assert_eq!(indexer.max_block(95), 90);
+    }
+
+    /// Data file with blocks 10..=20 handled and the missed blocks
+    fn data(missed: &[BlockHeight]) -> IndexerData {
+        IndexerData {
+            first_block: 10,
+            last_block: 20,
+            last_handled_block: 20,
+            missed_blocks: missed
+                .iter()
+                .map(|height| {
+                    let missed_block = MissedBlock {
+                        kind: FailureKind::Block,
+                        error: String::new(),
+                        attempts: 1,
+                        last_attempt: 0,
+                        chunks: vec![],
+                    };
+                    (*height, missed_block)
+                })
+                .collect(),
+            ..IndexerData::default()
+        }
+    }
+
+    #[test]
+    fn test_start_block_in_data_file_range() {
+        let dir = TestDir::new("indexer-start-block");
+        let data_file = dir.path("data.borsh");
+        write_checkpoint(&data_file, &data(&[])).unwrap();
+
+        let error = |block| Indexer::new(&data_file, Some(block), None).err();
+        assert!(error(9).is_some());
+        assert!(error(22).is_some());
+        // Already indexed start blocks and the next block resume indexing
+        for block in [10, 15, 20, 21] {
+            let indexer = Indexer::new(&data_file, Some(block), None).unwrap();
+            let data = indexer.data.lock().unwrap();
+            assert_eq!((data.first_block, data.last_block), (10, 20));
         }
     }
+
+    #[test]
+    fn test_range_summary() {
+        let data = data(&[15]);
+        let summary = Indexer::range_summary(&data, 10, 20);
+        assert_eq!(
+            summary,
+            RangeSummary {
+                start_block: 10,
+                stop_block: 20,
+                handled_up_to: 20,
+                blocks: 11,
+                handled_blocks: 10,
+                missed_blocks: vec![15],
+                complete: false,
+            }
+        );
+
+        // Range boundaries are the first and the last handled blocks
+        let summary = Indexer::range_summary(&data, 16, 20);
+        assert_eq!((summary.handled_blocks, summary.complete), (5, true));
+        let summary = Indexer::range_summary(&data, 20, 20);
+        assert_eq!((summary.blocks, summary.complete), (1, true));
+
+        // Blocks out of the data file range aren't handled
+        let summary = Indexer::range_summary(&data, 9, 21);
+        assert_eq!(summary.blocks, 13);
+        assert_eq!(summary.handled_blocks, 10);
+        assert_eq!(summary.handled_up_to, 20);
+        let summary = Indexer::range_summary(&data, 21, 25);
+        assert_eq!((summary.handled_blocks, summary.complete), (0, false));
+    }
+
+    /// Data file with blocks 100 and 101 of the lake fixture handled, where
+    /// block 100 is missed with `kind` and its chunk of shard 0
+    fn retried_indexer(
+        dir: &TestDir,
+        kind: FailureKind,
+        shard: ShardProgress,
+        logs: Vec<IndexedResultLog>,
+    ) -> Indexer {
+        let mut data = IndexerData {
+            first_block: 100,
+            last_block: 101,
+            last_handled_block: 101,
+            ..IndexerData::default()
+        };
+        let chunks = match kind {
+            FailureKind::Outcome { chunk_hash, .. } => vec![chunk_hash],
+            _ => vec![],
+        };
+        data.missed_blocks.insert(
+            100,
+            MissedBlock {
+                kind,
+                error: String::new(),
+                attempts: 1,
+                last_attempt: 0,
+                chunks,
+            },
+        );
+        data.shards.insert(0, shard);
+        data.data.logs = logs;
+        let data_file = dir.path("data.borsh");
+        write_checkpoint(&data_file, &data).unwrap();
+
+        let mut indexer = Indexer::new(&data_file, None, None).unwrap();
+        indexer.set_lake(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lake"));
+        indexer
+    }
+
+    fn chunk_hash() -> CryptoHash {
+        "CBaew361U9Xas2jmZsQx944VZVT8yxeRvggVUdWHcX5S"
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retry_tracks_shards_of_missed_block() {
+        let dir = TestDir::new("indexer-retry-block");
+        let shard = ShardProgress {
+            last_chunk_block: 101,
+            last_chunk_hash: CryptoHash::hash_bytes(b"chunk101"),
+            chunks: 1,
+            missing_chunks: 0,
+        };
+        let mut indexer = retried_indexer(&dir, FailureKind::Block, shard, vec![]);
+        indexer.retry_missed(false).await.unwrap();
+
+        let data = indexer.data.lock().unwrap();
+        assert!(data.missed_blocks.is_empty());
+        // Chunk of the retried block is counted, the last chunk is kept
+        assert_eq!(
+            data.shards[&0],
+            ShardProgress {
+                last_chunk_block: 101,
+                last_chunk_hash: CryptoHash::hash_bytes(b"chunk101"),
+                chunks: 2,
+                missing_chunks: 0,
+            }
+        );
+        assert_eq!(data.data.logs.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_replaces_logs_without_outcome() {
+        let dir = TestDir::new("indexer-retry-outcome");
+        let shard = ShardProgress {
+            last_chunk_block: 100,
+            last_chunk_hash: chunk_hash(),
+            chunks: 1,
+            missing_chunks: 0,
+        };
+        let account = |account: &str| account.parse::<AccountId>().unwrap();
+        // Indexed before without execution outcomes of the chunk
+        let logs = vec![IndexedResultLog {
+            block_height: 100,
+            actions: vec![ActionResultLog {
+                accounts: vec![
+                    account("bob.near"),
+                    account("alice.near"),
+                    account("aurora"),
+                ],
+                method: "ft_transfer".to_string(),
+            }],
+            status: ExecutionStatus::Unknown,
+            receipt_id: None,
+            origin_receipt_id: None,
+        }];
+        let kind = FailureKind::Outcome {
+            shard_id: 0,
+            chunk_hash: chunk_hash(),
+        };
+        let mut indexer = retried_indexer(&dir, kind, shard.clone(), logs);
+        indexer.retry_missed(false).await.unwrap();
+
+        let data = indexer.data.lock().unwrap();
+        assert!(data.missed_blocks.is_empty());
+        // Shards of the block were tracked when the block was indexed
+        assert_eq!(data.shards[&0], shard);
+        let logs = &data.data.logs;
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].actions[0].method, "ft_transfer");
+        assert_eq!(logs[0].status, ExecutionStatus::Success);
+        assert_eq!(logs[1].actions[0].method, "withdraw");
+        assert_eq!(logs[1].status, ExecutionStatus::Failure);
+    }
+
+    /// Lake fixture source, where block 100 is fetched longer than block 101
+    struct DelayedSource {
+        source: LakeSource,
+        // Heights of the fetched blocks, in completion order
+        fetched: Mutex<Vec<BlockHeight>>,
+    }
+
+    impl BlockSource for DelayedSource {
+        fn get_block(&self, block_kind: BlockKind) -> BoxFuture<'_, anyhow::Result<BlockData>> {
+            Box::pin(async move {
+                if let BlockKind::Height(100) = block_kind {
+                    sleep(Duration::from_millis(100)).await;
+                }
+                let block = self.source.get_block(block_kind).await;
+                if let BlockKind::Height(height) = block_kind {
+                    self.fetched.lock().unwrap().push(height);
+                }
+                block
+            })
+        }
+
+        fn get_chunks<'a>(
+            &'a self,
+            chunks: &'a [near_primitives::views::ChunkHeaderView],
+        ) -> BoxFuture<'a, Vec<FetchedChunk>> {
+            self.source.get_chunks(chunks)
+        }
+
+        fn get_state_changes(
+            &self,
+            height: BlockHeight,
+            block_hash: CryptoHash,
+        ) -> BoxFuture<'_, anyhow::Result<StateChangesView>> {
+            self.source.get_state_changes(height, block_hash)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ordered_commit() {
+        let dir = TestDir::new("indexer-ordered-commit");
+        let mut indexer = Indexer::new(dir.path("data.borsh"), Some(100), Some(101)).unwrap();
+        indexer.set_workers(2);
+        indexer.set_mode(IndexMode::StateChanges);
+        let lake = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lake");
+        let delayed = Arc::new(DelayedSource {
+            source: LakeSource::new(lake, Arc::new(Catalogue::default())),
+            fetched: Mutex::new(vec![]),
+        });
+        let source: Arc<dyn BlockSource> = delayed.clone();
+        let mut client = Client::new();
+
+        indexer.handle_block(&mut client, &source).await.unwrap();
+        // Block 101 is fetched first, but block 100 is handled first
+        assert_eq!(*delayed.fetched.lock().unwrap(), [101, 100]);
+        assert_eq!(indexer.data.lock().unwrap().last_handled_block, 100);
+
+        indexer.handle_block(&mut client, &source).await.unwrap();
+        let data = indexer.data.lock().unwrap();
+        assert_eq!(data.last_handled_block, 101);
+        assert_eq!(data.first_block, 100);
+        let heights: Vec<_> = data
+            .recent_blocks
+            .iter()
+            .map(|block| block.height)
+            .collect();
+        assert_eq!(heights, [100, 101]);
+        assert!(data.missed_blocks.is_empty());
+        assert!(data.reorgs.is_empty());
+    }
+
+    /// Lake fixture source, where block 101 carries over the chunk of block 100
+    struct CarriedOverSource {
+        source: LakeSource,
+        // Hashes of the fetched chunks
+        fetched: Mutex<Vec<CryptoHash>>,
+    }
+
+    impl BlockSource for CarriedOverSource {
+        fn get_block(&self, block_kind: BlockKind) -> BoxFuture<'_, anyhow::Result<BlockData>> {
+            Box::pin(async move {
+                let mut block = self.source.get_block(block_kind).await?;
+                if block.0 == 101 {
+                    block.1 = self.source.get_block(BlockKind::Height(100)).await?.1;
+                }
+                Ok(block)
+            })
+        }
+
+        fn get_chunks<'a>(
+            &'a self,
+            chunks: &'a [near_primitives::views::ChunkHeaderView],
+        ) -> BoxFuture<'a, Vec<FetchedChunk>> {
+            self.fetched
+                .lock()
+                .unwrap()
+                .extend(chunks.iter().map(|chunk| chunk.chunk_hash));
+            self.source.get_chunks(chunks)
+        }
+
+        fn get_state_changes(
+            &self,
+            height: BlockHeight,
+            block_hash: CryptoHash,
+        ) -> BoxFuture<'_, anyhow::Result<StateChangesView>> {
+            self.source.get_state_changes(height, block_hash)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunk_selection_by_height_included() {
+        let lake = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lake");
+        let carried = Arc::new(CarriedOverSource {
+            source: LakeSource::new(lake, Arc::new(Catalogue::default())),
+            fetched: Mutex::new(vec![]),
+        });
+        let source: Arc<dyn BlockSource> = carried.clone();
+        let fetched_chunks = |fetched: &FetchedBlock| match &fetched.content {
+            FetchedContent::Chunks(chunks) => chunks.len(),
+            FetchedContent::StateChanges(_) => unreachable!(),
+        };
+        let mut shards = HashMap::new();
+
+        let mut fetched =
+            Indexer::fetch_block(source.clone(), 100, IndexMode::Actions, vec![]).await;
+        assert_eq!(fetched_chunks(&fetched), 1);
+        fetched.track_shards(&mut shards, 100);
+
+        // Carried over chunk is neither fetched nor counted twice
+        let mut fetched =
+            Indexer::fetch_block(source.clone(), 101, IndexMode::Actions, vec![]).await;
+        assert_eq!(fetched_chunks(&fetched), 0);
+        assert_eq!(*carried.fetched.lock().unwrap(), vec![chunk_hash()]);
+        fetched.track_shards(&mut shards, 101);
+        assert_eq!(
+            shards[&0],
+            ShardProgress {
+                last_chunk_block: 100,
+                last_chunk_hash: chunk_hash(),
+                chunks: 1,
+                missing_chunks: 1,
+            }
+        );
+
+        // Failed chunks are fetched regardless of the height
+        let fetched =
+            Indexer::fetch_block(source, 101, IndexMode::Actions, vec![chunk_hash()]).await;
+        assert_eq!(fetched_chunks(&fetched), 1);
+        assert_eq!(carried.fetched.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let account = |account: &str| account.parse::<AccountId>().unwrap();
+        let hash = |height: BlockHeight| CryptoHash::hash_bytes(&height.to_le_bytes());
+        let log = |block_height| IndexedResultLog {
+            block_height,
+            actions: vec![],
+            status: ExecutionStatus::Success,
+            receipt_id: None,
+            origin_receipt_id: None,
+        };
+        let recent_block = |height| RecentBlock {
+            height,
+            hash: hash(height),
+            accounts: vec![],
+            touched: vec![],
+            logs: 1,
+            shards: vec![],
+            transfer_calls: vec![],
+            linked_transfer_calls: vec![],
+        };
+        let shard = |last_chunk_block| ShardProgress {
+            last_chunk_block,
+            last_chunk_hash: hash(last_chunk_block),
+            chunks: 1,
+            missing_chunks: 0,
+        };
+        let (receipt_100, receipt_101) = (
+            CryptoHash::hash_bytes(b"100"),
+            CryptoHash::hash_bytes(b"101"),
+        );
+        let (callback_100, callback_101) = (
+            CryptoHash::hash_bytes(b"callback100"),
+            CryptoHash::hash_bytes(b"callback101"),
+        );
+
+        // Block 101 changes shard 0 and creates a transfer call, block 102
+        // adds shard 1 and links transfer calls of blocks 100 and 101
+        let mut data = IndexerData {
+            last_block: 102,
+            shards: HashMap::from([(0, shard(101)), (1, shard(102))]),
+            recent_blocks: vec![
+                recent_block(100),
+                RecentBlock {
+                    accounts: vec![account("bob.near")],
+                    touched: vec![account("alice.near"), account("bob.near")],
+                    shards: vec![(0, Some(shard(100)))],
+                    transfer_calls: vec![receipt_101],
+                    ..recent_block(101)
+                },
+                RecentBlock {
+                    accounts: vec![account("carol.near")],
+                    touched: vec![account("carol.near")],
+                    shards: vec![(1, None)],
+                    linked_transfer_calls: vec![
+                        (receipt_100, callback_100),
+                        (receipt_101, callback_101),
+                    ],
+                    ..recent_block(102)
+                },
+            ],
+            data: IndexedData {
+                accounts: HashSet::from([
+                    account("alice.near"),
+                    account("bob.near"),
+                    account("carol.near"),
+                ]),
+                logs: vec![log(100), log(101), log(102)],
+                transfer_calls: HashMap::new(),
+            },
+            ..IndexerData::default()
+        };
+        let reorg = Reorg {
+            fork_height: 100,
+            fork_hash: hash(100),
+            orphaned: vec![(101, hash(101)), (102, hash(102))],
+            accounts: vec![account("bob.near"), account("carol.near")],
+            logs: 2,
+            time: 0,
+        };
+        data.rollback(&reorg);
+
+        assert_eq!(data.data.accounts, HashSet::from([account("alice.near")]));
+        assert_eq!(data.shards, HashMap::from([(0, shard(100))]));
+        // Transfer call created in the orphaned block is dropped, even if it was linked
+        assert_eq!(
+            data.data.transfer_calls,
+            HashMap::from([(receipt_100, callback_100)])
+        );
+        let heights: Vec<_> = data.data.logs.iter().map(|log| log.block_height).collect();
+        assert_eq!(heights, vec![100]);
+        assert_eq!(data.reorgs.len(), 1);
+    }
+
+    #[test]
+    fn test_stats_summary_json() {
+        let dir = TestDir::new("indexer-stats-json");
+        let shard = ShardProgress {
+            last_chunk_block: 101,
+            last_chunk_hash: chunk_hash(),
+            chunks: 2,
+            missing_chunks: 0,
+        };
+        let log = |account: &str, status| IndexedResultLog {
+            block_height: 101,
+            actions: vec![ActionResultLog {
+                accounts: vec![account.parse().unwrap()],
+                method: "ft_transfer".to_string(),
+            }],
+            status,
+            receipt_id: None,
+            origin_receipt_id: None,
+        };
+        let logs = vec![
+            log("alice.near", ExecutionStatus::Success),
+            log("bob.near", ExecutionStatus::Failure),
+        ];
+        let indexer = retried_indexer(&dir, FailureKind::Block, shard, logs);
+        indexer.data.lock().unwrap().data.accounts = HashSet::from(["alice.near".parse().unwrap()]);
+
+        let summary = indexer
+            .stats_summary(120, false, SummaryFormat::Json)
+            .unwrap();
+        assert_eq!(
+            summary.to_json(),
+            json!({
+                "first_block": 100,
+                "last_block": 101,
+                "last_saved_current_block": 0,
+                "current_block": 120,
+                "missed_blocks": 1,
+                "skipped_blocks": 0,
+                "reorgs": 0,
+                "shards": [{
+                    "shard_id": 0,
+                    "last_chunk_block": 101,
+                    "chunks": 2,
+                    "missing_chunks": 0,
+                }],
+                "accounts": 1,
+                "logs": 1,
+                "failed_logs": 1,
+                "failed_only_accounts": 1,
+            })
+        );
+
+        let summary = indexer
+            .stats_summary(120, true, SummaryFormat::Json)
+            .unwrap();
+        let summary = summary.to_json();
+        assert_eq!(
+            summary["missed_block_list"],
+            json!([{
+                "height": 100,
+                "kind": FailureKind::Block.to_string(),
+                "failed_chunks": 0,
+                "attempts": 1,
+                "last_attempt": 0,
+                "error": "",
+            }])
+        );
+        assert_eq!(summary["missed_blocks_by_kind"]["block"], 1);
+        assert_eq!(summary["reorg_list"], json!([]));
+    }
 }
@@ -3,6 +3,7 @@ pub use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::AccountId;
 use serde_derive::Deserialize;
 use std::collections::HashMap;
+use std::io::Read;
 
 #[derive(Deserialize, Debug)]
 pub struct ResultValues {
@@ -28,9 +29,115 @@ pub struct FungibleToken {
     pub account_storage_usage: StorageUsage,
 }
 
-#[derive(Debug, Default, BorshSerialize, BorshDeserialize)]
+/// NEP-145 storage balance of the account, amounts in `yoctoNEAR`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct StorageBalance {
+    pub total: u128,
+    pub available: u128,
+}
+
+/// Data source of the account balance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum AccountSource {
+    State,
+    Indexed,
+}
+
+impl std::fmt::Display for AccountSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::State => write!(f, "state"),
+            Self::Indexed => write!(f, "indexed"),
+        }
+    }
+}
+
+/// Where the account balance was taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Provenance {
+    pub source: AccountSource,
+    pub block_height: u64,
+}
+
+/// Prefix of the versioned `StateData` layout. Files written before the
+/// layout was versioned start with the total supply instead.
+pub const STATE_DATA_MAGIC: &[u8; 8] = b"AURSTATE";
+/// Current `StateData` layout version, written after the magic prefix
+pub const STATE_DATA_VERSION: u8 = 1;
+
+#[derive(Debug, Default)]
 pub struct StateData {
+    /// Block height at which the data was taken
+    pub block_height: u64,
     pub total_supply: NEP141Wei,
     pub total_stuck_supply: NEP141Wei,
     pub accounts: HashMap<AccountId, NEP141Wei>,
+    /// NEP-145 storage balances. `None` means the account is not registered.
+    pub storage_balances: HashMap<AccountId, Option<StorageBalance>>,
+    /// Provenance of the account balances. Filled only for combined data.
+    pub provenance: HashMap<AccountId, Provenance>,
+    /// Balances of invalid accounts that make up `total_stuck_supply`
+    pub stuck_accounts: HashMap<String, NEP141Wei>,
+}
+
+/// Read `StateData` header. Return `false` for the legacy layout without
+/// header: `total_supply`, `total_stuck_supply` and `accounts` only.
+pub fn read_state_data_header(buf: &mut &[u8]) -> std::io::Result<bool> {
+    let Some(rest) = buf.strip_prefix(STATE_DATA_MAGIC.as_slice()) else {
+        return Ok(false);
+    };
+    *buf = rest;
+    check_state_data_version(u8::deserialize(buf)?)?;
+    Ok(true)
+}
+
+fn check_state_data_version(version: u8) -> std::io::Result<()> {
+    if version != STATE_DATA_VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unsupported state data version {version}, expected {STATE_DATA_VERSION}"),
+        ));
+    }
+    Ok(())
+}
+
+impl BorshSerialize for StateData {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(STATE_DATA_MAGIC)?;
+        STATE_DATA_VERSION.serialize(writer)?;
+        self.block_height.serialize(writer)?;
+        self.total_supply.serialize(writer)?;
+        self.total_stuck_supply.serialize(writer)?;
+        self.accounts.serialize(writer)?;
+        self.storage_balances.serialize(writer)?;
+        self.provenance.serialize(writer)?;
+        self.stuck_accounts.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for StateData {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut magic = [0; STATE_DATA_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != STATE_DATA_MAGIC {
+            // Legacy layout, the block height is unknown
+            let reader = &mut magic.as_slice().chain(reader);
+            return Ok(Self {
+                total_supply: BorshDeserialize::deserialize_reader(reader)?,
+                total_stuck_supply: BorshDeserialize::deserialize_reader(reader)?,
+                accounts: BorshDeserialize::deserialize_reader(reader)?,
+                ..Self::default()
+            });
+        }
+        check_state_data_version(u8::deserialize_reader(reader)?)?;
+        Ok(Self {
+            block_height: BorshDeserialize::deserialize_reader(reader)?,
+            total_supply: BorshDeserialize::deserialize_reader(reader)?,
+            total_stuck_supply: BorshDeserialize::deserialize_reader(reader)?,
+            accounts: BorshDeserialize::deserialize_reader(reader)?,
+            storage_balances: BorshDeserialize::deserialize_reader(reader)?,
+            provenance: BorshDeserialize::deserialize_reader(reader)?,
+            stuck_accounts: BorshDeserialize::deserialize_reader(reader)?,
+        })
+    }
 }
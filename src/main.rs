@@ -1,12 +1,32 @@
-use crate::indexer::Indexer;
+use crate::catalogue::Catalogue;
+use crate::filter::AccountFilter;
+use crate::indexer::{IndexMode, Indexer};
+use crate::merge::MergePolicy;
 use crate::migration::Migration;
-use clap::{arg, command, value_parser, ArgAction, Command};
+use crate::query::{LogFilter, OutputFormat};
+use crate::store::StoreKind;
+use crate::summary::SummaryFormat;
+use clap::{arg, command, value_parser, ArgAction, ArgMatches, Command};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
+mod audit;
+mod catalogue;
+mod coverage;
+mod filter;
+mod follow;
 pub mod indexer;
+mod merge;
+mod metrics;
 mod migration;
 mod parser;
+mod query;
 pub mod rpc;
+mod source;
+mod store;
+mod summary;
+#[cfg(test)]
+mod test_utils;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -24,7 +44,8 @@ async fn main() -> anyhow::Result<()> {
                     arg!(-o --output <FILE> "Output file with results data serialized with borsh")
                         .required(false)
                         .value_parser(value_parser!(PathBuf)),
-                ),
+                )
+                .arg(format_arg()),
         )
         .subcommand(
             Command::new("indexer")
@@ -39,7 +60,95 @@ async fn main() -> anyhow::Result<()> {
                 )
                 .arg(
                     arg!(-b --block <BLOCK_HEIGHT> "Start indexing from specific block")
+                        .visible_alias("from")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--to <BLOCK_HEIGHT> "Stop indexing after specific block and print completeness summary")
                         .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(-w --workers <NUM> "Number of blocks fetched concurrently")
+                        .default_value("8")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(engine_arg())
+                .arg(
+                    arg!(--methods <FILE> "JSON file with tracked methods catalogue")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--mode <MODE> "Source of indexed accounts: actions arguments or Aurora contract balance state changes")
+                        .value_parser(["actions", "state-changes"])
+                        .default_value("actions"),
+                )
+                .arg(
+                    arg!(-d --"data-file" <FILE> "Indexed data file")
+                        .default_value("data.borsh")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--store <STORE> "Indexed data file format, borsh file is converted to journal. Existing journal is detected automatically")
+                        .value_parser(["borsh", "journal"]),
+                )
+                .arg(
+                    arg!(--lake <DIR> "Read blocks and chunks from local directory in NEAR Lake layout instead of NEAR RPC")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--follow <FILE> "Keep balances of touched accounts updated in state data file, in the background")
+                        .required(false)
+                        .conflicts_with("lake")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(metrics_arg())
+                .arg(format_arg())
+                .subcommand(
+                    Command::new("retry-missed")
+                        .about("Retry fetching of missed blocks and merge indexed data")
+                        .arg(
+                            arg!(--archival "Use archival NEAR RPC endpoint")
+                                .action(ArgAction::SetTrue),
+                        ),
+                )
+                .subcommand(
+                    Command::new("query")
+                        .about("Search indexed actions by account, method, block range and execution status")
+                        .arg(
+                            arg!(--account <ACCOUNT_ID> "Actions with the account")
+                                .required(false),
+                        )
+                        .arg(arg!(--method <METHOD> "Actions of the method").required(false))
+                        .arg(
+                            arg!(--from <BLOCK_HEIGHT> "Actions from specific block")
+                                .required(false)
+                                .value_parser(value_parser!(u64)),
+                        )
+                        .arg(
+                            arg!(--to <BLOCK_HEIGHT> "Actions up to specific block")
+                                .required(false)
+                                .value_parser(value_parser!(u64)),
+                        )
+                        .arg(
+                            arg!(--status <STATUS> "Actions with execution status")
+                                .value_parser(["success", "failure", "unknown"]),
+                        )
+                        .arg(
+                            arg!(--format <FORMAT> "Output format")
+                                .value_parser(["table", "json", "csv"])
+                                .default_value("table"),
+                        )
+                        .arg(
+                            arg!(--latest "Print the newest actions first")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            arg!(--limit <NUM> "Max number of printed actions")
+                                .required(false)
+                                .value_parser(value_parser!(usize)),
+                        ),
                 ),
         )
         .subcommand(
@@ -54,7 +163,18 @@ async fn main() -> anyhow::Result<()> {
                     arg!(-o --output <FILE> "Output file with migration results data serialized with borsh")
                         .required(true)
                         .value_parser(value_parser!(PathBuf)),
-                ),
+                )
+                .arg(engine_arg())
+                .arg(
+                    arg!(--"exclude-failed" "Skip accounts found only in failed actions")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--followed <FILE> "State data file kept by indexer follow mode, only accounts touched after their last refresh are fetched")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(format_arg()),
         )
         .subcommand(
             Command::new("migrate")
@@ -76,6 +196,15 @@ async fn main() -> anyhow::Result<()> {
                     arg!(-k --key <ACCOUNT_KEY> "Account private key for sign migration transactions")
                         .required(true),
                 )
+                .args(filter_args())
+                .arg(treasury_arg())
+                .arg(
+                    arg!(--"stuck-accounts" <FILE> "Output file for stuck accounts list in json format, used with --treasury")
+                        .required(false)
+                        .default_value("stuck_accounts.json")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(metrics_arg())
         )
         .subcommand(
             Command::new("combine-indexed-and-state-data")
@@ -95,6 +224,73 @@ async fn main() -> anyhow::Result<()> {
                         .required(true)
                         .value_parser(value_parser!(PathBuf)),
                 )
+                .arg(
+                    arg!(--report <FILE> "Output file for combine report in json format")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(format_arg())
+        )
+        .subcommand(
+            Command::new("merge")
+                .about("Merge any number of state data files ordered by source block height")
+                .arg(
+                    arg!(-i --input <FILE> "Path to the state data file in borsh format. Can be repeated")
+                        .required(true)
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-p --policy <POLICY> "Precedence policy for accounts present in several files")
+                        .required(false)
+                        .value_parser(["latest", "max", "priority"])
+                        .default_value("latest"),
+                )
+                .arg(
+                    arg!(-o --output <FILE> "Output file for merged data in borsh format")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-m --manifest <FILE> "Output file for merge manifest in json format")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+        )
+        .subcommand(
+            Command::new("audit")
+                .about("Audit supply invariants of state data. Should be invoked before migration")
+                .arg(
+                    arg!(-f --file <FILE> "State data file serialized with borsh")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--strict "Treat warnings as violations")
+                        .action(ArgAction::SetTrue),
+                )
+        )
+        .subcommand(
+            Command::new("coverage")
+                .about("Check that indexed blocks cover the whole window from the snapshot height to the pause height")
+                .arg(
+                    arg!(-s --snapshot <FILE> "Parsed snapshot state data file serialized with borsh")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-d --"data-file" <FILE> "Indexed data file. Can be repeated, ranges of the files are merged")
+                        .default_value("data.borsh")
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-p --pause <BLOCK_HEIGHT> "Height of the block at which Aurora contract was paused")
+                        .required(true)
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(format_arg()),
         )
         .subcommand(
             Command::new("check-migration")
@@ -108,13 +304,28 @@ async fn main() -> anyhow::Result<()> {
                     arg!(-c --contract <ACCOUNT_ID> "Account ID of aurora-eth-connector")
                         .required(true),
                 )
+                .args(filter_args())
+                .arg(treasury_arg())
+                .arg(format_arg())
         )
         .get_matches();
 
-    println!(
-        "Aurora Engine migration tool v{}",
-        env!("CARGO_PKG_VERSION")
-    );
+    // JSON summary or query rows are the only output. Format of the nested
+    // subcommand (like `indexer query`) takes precedence.
+    let mut format = None;
+    let mut subcommand = matches.subcommand();
+    while let Some((_, cmd)) = subcommand {
+        if let Ok(Some(cmd_format)) = cmd.try_get_one::<String>("format") {
+            format = Some(cmd_format.as_str());
+        }
+        subcommand = cmd.subcommand();
+    }
+    if !matches!(format, Some("json" | "csv")) {
+        println!(
+            "Aurora Engine migration tool v{}",
+            env!("CARGO_PKG_VERSION")
+        );
+    }
 
     match matches.subcommand() {
         Some(("parse", cmd)) => {
@@ -122,22 +333,103 @@ async fn main() -> anyhow::Result<()> {
                 .get_one::<PathBuf>("file")
                 .ok_or_else(|| anyhow::anyhow!("Expected snapshot file"))?;
             let output = cmd.get_one::<PathBuf>("output");
-            parser::parse(snapshot_json_file, output)?;
+            parser::parse(snapshot_json_file, output, summary_format(cmd)?)?;
         }
         Some(("indexer", cmd)) => {
             let stat = cmd.get_flag("stat");
             let fullstat = cmd.get_flag("fullstat");
+            let data_file = cmd
+                .get_one::<PathBuf>("data-file")
+                .expect("Expected data file");
 
-            if stat || fullstat {
-                let indexer = Indexer::new("data.borsh", None)?;
-                indexer.stats(fullstat).await;
+            let workers = cmd
+                .get_one::<usize>("workers")
+                .copied()
+                .expect("Expected workers number");
+            let mode: IndexMode = cmd
+                .get_one::<String>("mode")
+                .expect("Expected index mode")
+                .parse()?;
+            let catalogue = Catalogue::new(
+                cmd.get_one::<String>("engine")
+                    .expect("Expected engine account"),
+                cmd.get_one::<PathBuf>("methods"),
+            )?;
+            let store = cmd
+                .get_one::<String>("store")
+                .map(|store| store.parse::<StoreKind>())
+                .transpose()?;
+            let lake = cmd.get_one::<PathBuf>("lake");
+
+            if let Some(("retry-missed", retry_cmd)) = cmd.subcommand() {
+                let mut indexer = Indexer::new(data_file, None, None)?;
+                if let Some(store) = store {
+                    indexer.set_store(store)?;
+                }
+                indexer.set_workers(workers);
+                indexer.set_mode(mode);
+                indexer.set_catalogue(catalogue);
+                if let Some(lake) = lake {
+                    indexer.set_lake(lake);
+                }
+                serve_metrics(cmd).await?;
+                indexer.retry_missed(retry_cmd.get_flag("archival")).await?;
+            } else if let Some(("query", query_cmd)) = cmd.subcommand() {
+                let filter = LogFilter {
+                    account: query_cmd
+                        .get_one::<String>("account")
+                        .map(|account| account.parse())
+                        .transpose()
+                        .map_err(|e| anyhow::anyhow!("Invalid account, {e}"))?,
+                    method: query_cmd.get_one::<String>("method").cloned(),
+                    from_block: query_cmd.get_one::<u64>("from").copied(),
+                    to_block: query_cmd.get_one::<u64>("to").copied(),
+                    status: query_cmd
+                        .get_one::<String>("status")
+                        .map(|status| status.parse())
+                        .transpose()?,
+                };
+                let format: OutputFormat = query_cmd
+                    .get_one::<String>("format")
+                    .expect("Expected output format")
+                    .parse()?;
+                query::run_query(
+                    data_file,
+                    &filter,
+                    format,
+                    query_cmd.get_flag("latest"),
+                    query_cmd.get_one::<usize>("limit").copied(),
+                )?;
+            } else if stat || fullstat {
+                let indexer = Indexer::new(data_file, None, None)?;
+                indexer.stats(fullstat, summary_format(cmd)?).await?;
             } else {
                 let block = cmd
                     .get_one::<u64>("block")
                     .copied()
                     .expect("Expected start block height");
+                let stop_block = cmd.get_one::<u64>("to").copied();
+                if let Some(stop_block) = stop_block {
+                    anyhow::ensure!(
+                        stop_block >= block,
+                        "Stop block {stop_block} is lower than start block {block}"
+                    );
+                }
 
-                let mut indexer = Indexer::new("data.borsh", Some(block))?;
+                let mut indexer = Indexer::new(data_file, Some(block), stop_block)?;
+                if let Some(store) = store {
+                    indexer.set_store(store)?;
+                }
+                indexer.set_workers(workers);
+                indexer.set_mode(mode);
+                indexer.set_catalogue(catalogue);
+                if let Some(lake) = lake {
+                    indexer.set_lake(lake);
+                }
+                if let Some(state_file) = cmd.get_one::<PathBuf>("follow") {
+                    indexer.set_follow(state_file);
+                }
+                serve_metrics(cmd).await?;
                 indexer.run().await?;
             }
         }
@@ -152,21 +444,42 @@ async fn main() -> anyhow::Result<()> {
                 .expect("Expected account-id");
             let signer_account_key = cmd.get_one::<String>("key").expect("Expected account-key");
 
-            Migration::new(
+            serve_metrics(cmd).await?;
+            let treasury = treasury(cmd)?;
+            let migration = Migration::new(
                 data_file,
                 contract_account_id.clone(),
                 Some(signer_account_id.clone()),
                 Some(signer_account_key.clone()),
-            )?
-            .run()
-            .await?;
+                &account_filter(cmd)?,
+                treasury.clone(),
+            )?;
+            if treasury.is_some() {
+                let stuck_accounts_file = cmd
+                    .get_one::<PathBuf>("stuck-accounts")
+                    .expect("Expected stuck accounts file");
+                migration.save_stuck_accounts(stuck_accounts_file)?;
+            }
+            migration.run().await?;
         }
         Some(("prepare-migrate-indexed", cmd)) => {
             let input_data_file = cmd.get_one::<PathBuf>("file").expect("Expected data file");
             let output_file = cmd
                 .get_one::<PathBuf>("output")
                 .expect("Expected output file");
-            Migration::prepare_indexed(input_data_file, output_file).await?;
+            let engine = cmd
+                .get_one::<String>("engine")
+                .expect("Expected engine account");
+            let exclude_failed = cmd.get_flag("exclude-failed");
+            Migration::prepare_indexed(
+                input_data_file,
+                output_file,
+                engine,
+                exclude_failed,
+                cmd.get_one::<PathBuf>("followed"),
+                summary_format(cmd)?,
+            )
+            .await?;
         }
         Some(("combine-indexed-and-state-data", cmd)) => {
             let state_data_file = cmd.get_one::<PathBuf>("state").expect("Expected data file");
@@ -176,10 +489,52 @@ async fn main() -> anyhow::Result<()> {
             let output_file = cmd
                 .get_one::<PathBuf>("output")
                 .expect("Expected output file");
+            let report_file = cmd.get_one::<PathBuf>("report");
             Migration::combine_indexed_and_state_data(
                 state_data_file,
                 indexed_data_file,
                 output_file,
+                report_file,
+                summary_format(cmd)?,
+            )?;
+        }
+        Some(("merge", cmd)) => {
+            let input_files: Vec<&PathBuf> = cmd
+                .get_many::<PathBuf>("input")
+                .expect("Expected input files")
+                .collect();
+            let policy: MergePolicy = cmd
+                .get_one::<String>("policy")
+                .expect("Expected merge policy")
+                .parse()?;
+            let output_file = cmd
+                .get_one::<PathBuf>("output")
+                .expect("Expected output file");
+            let manifest_file = cmd.get_one::<PathBuf>("manifest");
+            merge::merge(&input_files, policy, output_file, manifest_file)?;
+        }
+        Some(("audit", cmd)) => {
+            let data_file = cmd.get_one::<PathBuf>("file").expect("Expected data file");
+            let strict = cmd.get_flag("strict");
+            audit::audit(data_file, strict)?;
+        }
+        Some(("coverage", cmd)) => {
+            let snapshot_file = cmd
+                .get_one::<PathBuf>("snapshot")
+                .expect("Expected snapshot data file");
+            let data_files: Vec<_> = cmd
+                .get_many::<PathBuf>("data-file")
+                .expect("Expected data file")
+                .collect();
+            let pause_height = cmd
+                .get_one::<u64>("pause")
+                .copied()
+                .expect("Expected pause height");
+            coverage::check_coverage(
+                snapshot_file,
+                &data_files,
+                pause_height,
+                summary_format(cmd)?,
             )?;
         }
         Some(("check-migration", cmd)) => {
@@ -189,12 +544,114 @@ async fn main() -> anyhow::Result<()> {
                 .get_one::<String>("contract")
                 .expect("Expected account-id");
 
-            Migration::new(data_file, contract_account_id.clone(), None, None)?
-                .validate_migration()
-                .await?;
+            Migration::new(
+                data_file,
+                contract_account_id.clone(),
+                None,
+                None,
+                &account_filter(cmd)?,
+                treasury(cmd)?,
+            )?
+            .validate_migration(summary_format(cmd)?)
+            .await?;
         }
         _ => (),
     }
 
     Ok(())
 }
+
+/// Account filter arguments for migration commands
+fn filter_args() -> [clap::Arg; 4] {
+    [
+        arg!(--allowlist <FILE> "File with allowed accounts or patterns, one per line. Can be repeated")
+            .required(false)
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--denylist <FILE> "File with excluded accounts or patterns, one per line. Can be repeated")
+            .required(false)
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--allow <PATTERN> "Allowed account or pattern, for ex: *.aurora. Can be repeated")
+            .required(false)
+            .action(ArgAction::Append),
+        arg!(--deny <PATTERN> "Excluded account or pattern, for ex: *.aurora. Can be repeated")
+            .required(false)
+            .action(ArgAction::Append),
+    ]
+}
+
+/// Summary output format argument: human-readable text or JSON
+fn format_arg() -> clap::Arg {
+    arg!(--format <FORMAT> "Summary output format")
+        .value_parser(["text", "json"])
+        .default_value("text")
+}
+
+fn summary_format(cmd: &ArgMatches) -> anyhow::Result<SummaryFormat> {
+    cmd.get_one::<String>("format")
+        .expect("Expected summary format")
+        .parse()
+}
+
+/// Prometheus metrics endpoint argument for indexer and migration commands
+fn metrics_arg() -> clap::Arg {
+    arg!(--metrics <ADDR> "Serve Prometheus metrics on the address, for ex: 127.0.0.1:9100")
+        .required(false)
+        .value_parser(value_parser!(SocketAddr))
+}
+
+/// Start metrics endpoint if it's set in command arguments
+async fn serve_metrics(cmd: &ArgMatches) -> anyhow::Result<()> {
+    if let Some(addr) = cmd.get_one::<SocketAddr>("metrics") {
+        metrics::serve(*addr).await?;
+    }
+    Ok(())
+}
+
+/// Engine contract argument, tracked methods are called on it
+fn engine_arg() -> clap::Arg {
+    arg!(--engine <ACCOUNT_ID> "Aurora Engine contract account ID")
+        .default_value(rpc::AURORA_CONTRACT)
+}
+
+/// Treasury account argument for migration commands
+fn treasury_arg() -> clap::Arg {
+    arg!(--treasury <ACCOUNT_ID> "Account ID that receives stuck supply during migration")
+        .required(false)
+}
+
+/// Parse treasury account from command arguments
+fn treasury(cmd: &ArgMatches) -> anyhow::Result<Option<near_sdk::AccountId>> {
+    cmd.get_one::<String>("treasury")
+        .map(|account| {
+            account
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid treasury account {account}, {e}"))
+        })
+        .transpose()
+}
+
+/// Build account filter from command arguments
+fn account_filter(cmd: &ArgMatches) -> anyhow::Result<AccountFilter> {
+    let mut filter = AccountFilter::default();
+    for list_file in cmd.get_many::<PathBuf>("allowlist").into_iter().flatten() {
+        filter.allow.extend(AccountFilter::read_list(list_file)?);
+    }
+    for list_file in cmd.get_many::<PathBuf>("denylist").into_iter().flatten() {
+        filter.deny.extend(AccountFilter::read_list(list_file)?);
+    }
+    filter.allow.extend(
+        cmd.get_many::<String>("allow")
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    filter.deny.extend(
+        cmd.get_many::<String>("deny")
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    Ok(filter)
+}
@@ -0,0 +1,329 @@
+//! # Merge
+//! Merge any number of `StateData` files into one, with precedence policy
+//! for accounts present in several files.
+//!
+use aurora_engine_migration_tool::StateData;
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::AccountId;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Precedence policy for accounts present in several input files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Balance from the input with the highest block height wins
+    Latest,
+    /// The highest balance wins
+    Max,
+    /// Balance from the input given first in the command line wins
+    Priority,
+}
+
+impl FromStr for MergePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(Self::Latest),
+            "max" => Ok(Self::Max),
+            "priority" => Ok(Self::Priority),
+            _ => anyhow::bail!("Unknown merge policy: {s}"),
+        }
+    }
+}
+
+impl std::fmt::Display for MergePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Latest => write!(f, "latest"),
+            Self::Max => write!(f, "max"),
+            Self::Priority => write!(f, "priority"),
+        }
+    }
+}
+
+struct MergeInput {
+    file: PathBuf,
+    // Position of the file in the command line
+    priority: usize,
+    data: StateData,
+}
+
+/// Merge `StateData` files ordered by source block height and store result
+/// with merge manifest, that records which file each account balance came from.
+pub fn merge<P: AsRef<Path>>(
+    inputs: &[P],
+    policy: MergePolicy,
+    output: P,
+    manifest: Option<P>,
+) -> anyhow::Result<()> {
+    let mut inputs = inputs
+        .iter()
+        .enumerate()
+        .map(|(priority, file)| {
+            let data = std::fs::read(file).map_err(|e| {
+                anyhow::anyhow!("Failed read data file {}, {e}", file.as_ref().display())
+            })?;
+            let data = StateData::try_from_slice(&data).map_err(|e| {
+                anyhow::anyhow!("Failed deserialize data {}, {e}", file.as_ref().display())
+            })?;
+            Ok(MergeInput {
+                file: file.as_ref().to_path_buf(),
+                priority,
+                data,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Stable sort: inputs with the same height keep the command line order
+    inputs.sort_by_key(|input| input.data.block_height);
+
+    let latest = inputs
+        .last()
+        .ok_or_else(|| anyhow::anyhow!("Expected at least one input file"))?;
+    let mut result = StateData {
+        block_height: latest.data.block_height,
+        // Stuck supply is a part of the total supply, so both of them and
+        // stuck accounts are taken from the same input.
+        total_supply: latest.data.total_supply,
+        total_stuck_supply: latest.data.total_stuck_supply,
+        stuck_accounts: latest.data.stuck_accounts.clone(),
+        ..StateData::default()
+    };
+
+    // Index of the input that each account balance came from
+    let mut sources: HashMap<AccountId, usize> = HashMap::new();
+    for (index, input) in inputs.iter().enumerate() {
+        for (account, balance) in &input.data.accounts {
+            let take = sources.get(account).map_or(true, |&current| {
+                let current = &inputs[current];
+                let current_balance = current.data.accounts[account];
+                match policy {
+                    MergePolicy::Latest => true,
+                    MergePolicy::Max => balance.as_u128() >= current_balance.as_u128(),
+                    MergePolicy::Priority => input.priority < current.priority,
+                }
+            });
+            if take {
+                sources.insert(account.clone(), index);
+            }
+        }
+    }
+
+    let mut manifest_accounts = Vec::with_capacity(sources.len());
+    for (account, index) in &sources {
+        let input = &inputs[*index];
+        let balance = input.data.accounts[account];
+        result.accounts.insert(account.clone(), balance);
+        if let Some(storage_balance) = input.data.storage_balances.get(account) {
+            result
+                .storage_balances
+                .insert(account.clone(), *storage_balance);
+        }
+        if let Some(provenance) = input.data.provenance.get(account) {
+            result.provenance.insert(account.clone(), *provenance);
+        }
+        manifest_accounts.push((account.clone(), *index, balance));
+    }
+    manifest_accounts.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (index, input) in inputs.iter().enumerate() {
+        println!(
+            "Input [{index}] {}: block height: {:?}, accounts: {}, total supply: {:?}",
+            input.file.display(),
+            input.data.block_height,
+            input.data.accounts.len(),
+            input.data.total_supply.as_u128()
+        );
+    }
+    println!("Policy: {policy}");
+    println!("Block height: {:?}", result.block_height);
+    println!("Accounts: {:?}", result.accounts.len());
+    println!("Total supply: {:?}", result.total_supply.as_u128());
+    println!(
+        "Total stuck supply: {:?}",
+        result.total_stuck_supply.as_u128()
+    );
+
+    let manifest_data = json!({
+        "policy": policy.to_string(),
+        "block_height": result.block_height,
+        "total_supply": result.total_supply.as_u128().to_string(),
+        "total_stuck_supply": result.total_stuck_supply.as_u128().to_string(),
+        "inputs": inputs.iter().map(|input| json!({
+            "file": input.file.display().to_string(),
+            "priority": input.priority,
+            "block_height": input.data.block_height,
+            "accounts": input.data.accounts.len(),
+            "total_supply": input.data.total_supply.as_u128().to_string(),
+        })).collect::<Vec<_>>(),
+        "accounts": manifest_accounts.iter().map(|(account, index, balance)| json!({
+            "account_id": account,
+            "file": inputs[*index].file.display().to_string(),
+            "block_height": inputs[*index].data.block_height,
+            "balance": balance.as_u128().to_string(),
+        })).collect::<Vec<_>>(),
+    });
+    let manifest = manifest.map_or_else(
+        || output.as_ref().with_extension("manifest.json"),
+        |p| p.as_ref().to_path_buf(),
+    );
+    std::fs::write(&manifest, manifest_data.to_string())
+        .map_err(|e| anyhow::anyhow!("Failed save merge manifest, {e}"))?;
+    println!("Manifest: {}", manifest.display());
+
+    result
+        .try_to_vec()
+        .and_then(|data| std::fs::write(output, data))
+        .map_err(|e| anyhow::anyhow!("Failed save merged data, {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+    use aurora_engine_types::types::NEP141Wei;
+
+    fn write(dir: &TestDir, file: &str, block_height: u64, accounts: &[(&str, u128)]) -> PathBuf {
+        let data = StateData {
+            block_height,
+            total_supply: NEP141Wei::new(accounts.iter().map(|(_, balance)| balance).sum()),
+            accounts: accounts
+                .iter()
+                .map(|(account, balance)| (account.parse().unwrap(), NEP141Wei::new(*balance)))
+                .collect(),
+            ..StateData::default()
+        };
+        let path = dir.path(file);
+        std::fs::write(&path, data.try_to_vec().unwrap()).unwrap();
+        path
+    }
+
+    fn merge_files(dir: &TestDir, inputs: &[PathBuf], policy: MergePolicy) -> StateData {
+        let output = dir.path("merged.borsh");
+        merge(inputs, policy, output.clone(), None).unwrap();
+        StateData::try_from_slice(&std::fs::read(output).unwrap()).unwrap()
+    }
+
+    fn balance(data: &StateData, account: &str) -> u128 {
+        data.accounts[&account.parse::<AccountId>().unwrap()].as_u128()
+    }
+
+    /// Newer snapshot is given first: `alice.near` is in both files
+    fn inputs(dir: &TestDir) -> Vec<PathBuf> {
+        vec![
+            write(dir, "new.borsh", 20, &[("alice.near", 5), ("bob.near", 7)]),
+            write(
+                dir,
+                "old.borsh",
+                10,
+                &[("alice.near", 9), ("carol.near", 3)],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_latest_policy() {
+        let dir = TestDir::new("merge-latest");
+        let data = merge_files(&dir, &inputs(&dir), MergePolicy::Latest);
+        assert_eq!(data.block_height, 20);
+        assert_eq!(data.accounts.len(), 3);
+        assert_eq!(balance(&data, "alice.near"), 5);
+        assert_eq!(balance(&data, "bob.near"), 7);
+        assert_eq!(balance(&data, "carol.near"), 3);
+    }
+
+    #[test]
+    fn test_max_policy() {
+        let dir = TestDir::new("merge-max");
+        let data = merge_files(&dir, &inputs(&dir), MergePolicy::Max);
+        assert_eq!(data.block_height, 20);
+        assert_eq!(balance(&data, "alice.near"), 9);
+    }
+
+    #[test]
+    fn test_priority_policy() {
+        let dir = TestDir::new("merge-priority");
+        let mut inputs = inputs(&dir);
+        let data = merge_files(&dir, &inputs, MergePolicy::Priority);
+        assert_eq!(balance(&data, "alice.near"), 5);
+
+        inputs.reverse();
+        let data = merge_files(&dir, &inputs, MergePolicy::Priority);
+        assert_eq!(data.block_height, 20);
+        assert_eq!(balance(&data, "alice.near"), 9);
+    }
+
+    #[test]
+    fn test_same_height_keeps_order() {
+        let dir = TestDir::new("merge-same-height");
+        let inputs = vec![
+            write(&dir, "first.borsh", 10, &[("alice.near", 1)]),
+            write(&dir, "second.borsh", 10, &[("alice.near", 2)]),
+        ];
+        let data = merge_files(&dir, &inputs, MergePolicy::Latest);
+        assert_eq!(balance(&data, "alice.near"), 2);
+    }
+
+    #[test]
+    fn test_stuck_supply_of_latest_input() {
+        let dir = TestDir::new("merge-stuck-supply");
+        let write_stuck = |file: &str, block_height, stuck_supply| {
+            let data = StateData {
+                block_height,
+                total_supply: NEP141Wei::new(100),
+                total_stuck_supply: NEP141Wei::new(stuck_supply),
+                stuck_accounts: HashMap::from([(
+                    format!("Invalid {file}"),
+                    NEP141Wei::new(stuck_supply),
+                )]),
+                ..StateData::default()
+            };
+            let path = dir.path(file);
+            std::fs::write(&path, data.try_to_vec().unwrap()).unwrap();
+            path
+        };
+        let inputs = vec![
+            write_stuck("new.borsh", 20, 5),
+            write_stuck("old.borsh", 10, 9),
+        ];
+        let data = merge_files(&dir, &inputs, MergePolicy::Max);
+        assert_eq!(data.total_supply.as_u128(), 100);
+        assert_eq!(data.total_stuck_supply.as_u128(), 5);
+        assert_eq!(
+            data.stuck_accounts,
+            HashMap::from([("Invalid new.borsh".to_string(), NEP141Wei::new(5))])
+        );
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = TestDir::new("merge-manifest");
+        merge_files(&dir, &inputs(&dir), MergePolicy::Latest);
+        let manifest = std::fs::read(dir.path("merged.manifest.json")).unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+        assert_eq!(manifest["policy"], "latest");
+        assert_eq!(manifest["inputs"].as_array().unwrap().len(), 2);
+        let accounts = manifest["accounts"].as_array().unwrap();
+        assert_eq!(accounts[0]["account_id"], "alice.near");
+        assert_eq!(accounts[0]["block_height"], 20);
+        assert_eq!(accounts[0]["balance"], "5");
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("max".parse::<MergePolicy>().unwrap(), MergePolicy::Max);
+        assert!("min".parse::<MergePolicy>().is_err());
+        for policy in [MergePolicy::Latest, MergePolicy::Max, MergePolicy::Priority] {
+            assert_eq!(policy.to_string().parse::<MergePolicy>().unwrap(), policy);
+        }
+    }
+
+    #[test]
+    fn test_no_inputs() {
+        let dir = TestDir::new("merge-empty");
+        let output = dir.path("merged.borsh");
+        assert!(merge(&[], MergePolicy::Latest, output, None).is_err());
+    }
+}
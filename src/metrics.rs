@@ -0,0 +1,320 @@
+//! # Metrics
+//! Indexer and migration metrics, served in Prometheus text format
+//! by a minimal HTTP endpoint.
+//!
+use near_primitives::types::{BlockHeight, Gas};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Interval of the blocks per second gauge calculation
+const RATE_INTERVAL: Duration = Duration::from_secs(10);
+/// Max time to wait for the request, so idle connections don't pile up
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Max size of the request headers
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Process metrics
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    height: AtomicU64,
+    tip_height: AtomicU64,
+    blocks: AtomicU64,
+    // Blocks count at the scrape times. The rate is calculated at scrape
+    // time from the last sample older than the rate interval, so it drops
+    // when indexing stalls.
+    rate_samples: Mutex<VecDeque<(Instant, u64)>>,
+    missed_blocks: AtomicU64,
+    accounts: AtomicU64,
+    rpc_errors: Mutex<BTreeMap<&'static str, u64>>,
+    batches_committed: AtomicU64,
+    batches_verified: AtomicU64,
+    gas_burnt: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            height: AtomicU64::new(0),
+            tip_height: AtomicU64::new(0),
+            blocks: AtomicU64::new(0),
+            rate_samples: Mutex::new(VecDeque::new()),
+            missed_blocks: AtomicU64::new(0),
+            accounts: AtomicU64::new(0),
+            rpc_errors: Mutex::new(BTreeMap::new()),
+            batches_committed: AtomicU64::new(0),
+            batches_verified: AtomicU64::new(0),
+            gas_burnt: AtomicU64::new(0),
+        }
+    }
+
+    /// Set handled block with the indexer totals
+    pub fn set_block(&self, height: BlockHeight, missed_blocks: usize, accounts: usize) {
+        self.height.store(height, Ordering::Relaxed);
+        self.missed_blocks
+            .store(missed_blocks as u64, Ordering::Relaxed);
+        self.accounts.store(accounts as u64, Ordering::Relaxed);
+        let blocks = self.blocks.fetch_add(1, Ordering::Relaxed);
+
+        // The rate is counted from the first handled block
+        let mut rate_samples = self.rate_samples.lock().unwrap();
+        if rate_samples.is_empty() {
+            rate_samples.push_back((Instant::now(), blocks));
+        }
+    }
+
+    /// Handled blocks per second at the scrape time `now`
+    fn blocks_per_second(&self, now: Instant) -> f64 {
+        let blocks = self.blocks.load(Ordering::Relaxed);
+        let mut rate_samples = self.rate_samples.lock().unwrap();
+        while rate_samples.len() > 1
+            && now.saturating_duration_since(rate_samples[1].0) >= RATE_INTERVAL
+        {
+            rate_samples.pop_front();
+        }
+        let rate = rate_samples.front().map_or(0., |(start, start_blocks)| {
+            let elapsed = now.saturating_duration_since(*start).as_secs_f64();
+            if elapsed > 0. {
+                (blocks - start_blocks) as f64 / elapsed
+            } else {
+                0.
+            }
+        });
+        if !rate_samples.is_empty() {
+            rate_samples.push_back((now, blocks));
+        }
+        rate
+    }
+
+    /// Set the latest block height of the network
+    pub fn set_tip_height(&self, height: BlockHeight) {
+        self.tip_height.store(height, Ordering::Relaxed);
+    }
+
+    /// Count failed RPC request by kind
+    pub fn rpc_error(&self, kind: &'static str) {
+        *self.rpc_errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    /// Count committed migration batch with the gas burnt by its transaction
+    pub fn batch_committed(&self, gas_burnt: Gas) {
+        self.batches_committed.fetch_add(1, Ordering::Relaxed);
+        self.gas_burnt.fetch_add(gas_burnt, Ordering::Relaxed);
+    }
+
+    /// Count migration batch verified by the contract
+    pub fn batch_verified(&self) {
+        self.batches_verified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Metrics in Prometheus text format
+    #[must_use]
+    pub fn render(&self) -> String {
+        let height = self.height.load(Ordering::Relaxed);
+        let tip_height = self.tip_height.load(Ordering::Relaxed);
+        let blocks_per_second = self.blocks_per_second(Instant::now());
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            );
+        };
+        metric(
+            "aurora_indexer_height",
+            "gauge",
+            "Last handled block height",
+            height.to_string(),
+        );
+        metric(
+            "aurora_indexer_tip_height",
+            "gauge",
+            "Latest block height of the network",
+            tip_height.to_string(),
+        );
+        metric(
+            "aurora_indexer_lag_blocks",
+            "gauge",
+            "Number of blocks between the tip and the last handled block",
+            tip_height.saturating_sub(height).to_string(),
+        );
+        metric(
+            "aurora_indexer_blocks_total",
+            "counter",
+            "Number of handled blocks",
+            self.blocks.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "aurora_indexer_blocks_per_second",
+            "gauge",
+            "Handled blocks per second",
+            format!("{blocks_per_second:.3}"),
+        );
+        metric(
+            "aurora_indexer_missed_blocks",
+            "gauge",
+            "Number of missed blocks",
+            self.missed_blocks.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "aurora_indexer_accounts",
+            "gauge",
+            "Number of indexed accounts",
+            self.accounts.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "aurora_migration_batches_committed_total",
+            "counter",
+            "Number of committed migration batches",
+            self.batches_committed.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "aurora_migration_batches_verified_total",
+            "counter",
+            "Number of migration batches verified by the contract",
+            self.batches_verified.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "aurora_migration_gas_burnt_total",
+            "counter",
+            "Gas burnt by migration transactions",
+            self.gas_burnt.load(Ordering::Relaxed).to_string(),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP aurora_rpc_errors_total Number of failed NEAR RPC requests by kind\n\
+             # TYPE aurora_rpc_errors_total counter"
+        );
+        for (kind, count) in self.rpc_errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "aurora_rpc_errors_total{{kind=\"{kind}\"}} {count}");
+        }
+        out
+    }
+}
+
+/// Serve metrics on `GET /metrics`. The listener runs in the background.
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed bind metrics endpoint {addr}, {e}"))?;
+    println!("Metrics endpoint: http://{addr}/metrics");
+    tokio::spawn(async move {
+        loop {
+            if let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(respond(stream));
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> std::io::Result<()> {
+    let request = tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Request timeout"))??;
+    let (status, body) = if request.starts_with("GET /metrics ") {
+        ("200 OK", METRICS.render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read the request until the end of headers. The request can arrive in
+/// several reads.
+async fn read_request<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..len]);
+        if request.len() > MAX_REQUEST_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Request is too large",
+            ));
+        }
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_per_second() {
+        let metrics = Metrics::new();
+        let start = Instant::now();
+        assert_eq!(metrics.blocks_per_second(start), 0.);
+        for height in 1..=20 {
+            metrics.set_block(height, 0, 0);
+        }
+        let rate = metrics.blocks_per_second(start + Duration::from_secs(4));
+        assert!((rate - 5.).abs() < 0.01, "{rate}");
+
+        // Indexing stalled: the rate drops once the interval is passed
+        let rate = metrics.blocks_per_second(start + Duration::from_secs(8));
+        assert!((rate - 2.5).abs() < 0.01, "{rate}");
+        let rate = metrics.blocks_per_second(start + Duration::from_secs(20));
+        assert_eq!(rate, 0.);
+
+        // The rate window starts at the last scrape older than the interval
+        metrics.set_block(21, 0, 0);
+        let rate = metrics.blocks_per_second(start + Duration::from_secs(25));
+        assert!((rate - 1. / 17.).abs() < 1e-9, "{rate}");
+        assert!(metrics.render().contains("aurora_indexer_height 21\n"));
+    }
+
+    /// Send the request in parts and read the response
+    async fn request(parts: &[&str]) -> String {
+        let (mut client, server) = tokio::io::duplex(64);
+        let handle = tokio::spawn(respond(server));
+        for part in parts {
+            client.write_all(part.as_bytes()).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handle.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let response = request(&["GET /metr", "ics HTTP/1.1\r\nHost: localhost\r\n", "\r\n"]).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE aurora_indexer_blocks_total counter\n"));
+
+        let response = request(&["GET / HTTP/1.1\r\n\r\n"]).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_request_too_large() {
+        let (mut client, mut server) = tokio::io::duplex(MAX_REQUEST_LEN * 2);
+        let header = format!(
+            "GET /metrics HTTP/1.1\r\nX: {}\r\n",
+            "a".repeat(MAX_REQUEST_LEN)
+        );
+        client.write_all(header.as_bytes()).await.unwrap();
+        let error = read_request(&mut server).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -1,11 +1,14 @@
-use crate::rpc::{Client, REQUEST_TIMEOUT};
-use aurora_engine_migration_tool::StateData;
+use crate::filter::AccountFilter;
+use crate::metrics::METRICS;
+use crate::rpc::Client;
+use crate::summary::{Summary, SummaryFormat};
+use aurora_engine_migration_tool::{AccountSource, Provenance, StateData, StorageBalance};
 use aurora_engine_types::types::NEP141Wei;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
 use near_sdk::{AccountId, Balance};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::path::Path;
 
@@ -23,6 +26,10 @@ pub struct Migration {
     pub client: Client,
     pub data: StateData,
     pub config: MigrationConfig,
+    /// Accounts excluded from migration by account filter
+    pub excluded: HashMap<AccountId, NEP141Wei>,
+    /// Account that receives stuck supply during migration
+    pub treasury: Option<AccountId>,
 }
 
 #[derive(Debug, BorshDeserialize, BorshSerialize)]
@@ -45,21 +52,104 @@ impl Migration {
         contract_account_id: String,
         signer_account_id: Option<String>,
         signer_secret_key: Option<String>,
+        filter: &AccountFilter,
+        treasury: Option<AccountId>,
     ) -> anyhow::Result<Self> {
         let data = std::fs::read(data_file).unwrap_or_default();
-        let data: StateData = StateData::try_from_slice(&data)?;
+        let mut data: StateData = StateData::try_from_slice(&data)?;
+        let excluded = filter.apply(&mut data);
+
+        // Treasury account is migrated with its own balance and stuck supply
+        if let Some(treasury) = &treasury {
+            anyhow::ensure!(
+                filter.is_allowed(treasury),
+                "Treasury account {treasury} is excluded by account filter"
+            );
+            if !data.accounts.contains_key(treasury) {
+                eprintln!("Treasury account {treasury} not found in data, assume zero balance");
+            }
+            data.accounts
+                .entry(treasury.clone())
+                .or_insert_with(|| NEP141Wei::new(0));
+        }
 
         Ok(Self {
             client: Client::new(),
             data,
             config: MigrationConfig {
-                signer_account_id: signer_account_id.clone(),
+                signer_account_id,
                 signer_secret_key,
                 contract: contract_account_id,
             },
+            excluded,
+            treasury,
         })
     }
 
+    /// Balance of the account expected in `aurora-eth-connector` after
+    /// migration. Treasury account also receives stuck supply.
+    fn expected_balance(&self, account: &AccountId, balance: NEP141Wei) -> Balance {
+        if self.treasury.as_ref() == Some(account) {
+            balance.as_u128() + self.data.total_stuck_supply.as_u128()
+        } else {
+            balance.as_u128()
+        }
+    }
+
+    /// Total balance of accounts excluded from migration
+    fn excluded_supply(&self) -> NEP141Wei {
+        self.excluded
+            .values()
+            .fold(NEP141Wei::new(0), |sum, balance| sum + *balance)
+    }
+
+    /// Total supply expected in `aurora-eth-connector` after migration.
+    /// Stuck supply is lost unless it's redirected to the treasury account.
+    fn expected_total_supply(&self) -> anyhow::Result<Balance> {
+        let stuck_supply = if self.treasury.is_some() {
+            0
+        } else {
+            self.data.total_stuck_supply.as_u128()
+        };
+        self.data
+            .total_supply
+            .as_u128()
+            .checked_sub(stuck_supply)
+            .and_then(|supply| supply.checked_sub(self.excluded_supply().as_u128()))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Stuck supply {stuck_supply} and excluded supply {} exceed total supply {}",
+                    self.excluded_supply(),
+                    self.data.total_supply
+                )
+            })
+    }
+
+    /// Store stuck accounts list to json file, so claims for
+    /// stuck supply can be honoured later.
+    pub fn save_stuck_accounts<P: AsRef<Path>>(&self, output: P) -> anyhow::Result<()> {
+        let mut stuck_accounts: Vec<_> = self.data.stuck_accounts.iter().collect();
+        stuck_accounts.sort_by(|a, b| a.0.cmp(b.0));
+
+        let data = json!({
+            "treasury": self.treasury,
+            "block_height": self.data.block_height,
+            "total_stuck_supply": self.data.total_stuck_supply.as_u128().to_string(),
+            "accounts": stuck_accounts.iter().map(|(account, balance)| json!({
+                "account": account,
+                "balance": balance.as_u128().to_string(),
+            })).collect::<Vec<_>>(),
+        });
+        std::fs::write(&output, data.to_string())
+            .map_err(|e| anyhow::anyhow!("Failed save stuck accounts, {e}"))?;
+        println!(
+            "Stuck accounts: {} saved to {}",
+            stuck_accounts.len(),
+            output.as_ref().display()
+        );
+        Ok(())
+    }
+
     /// Commit migration data as transaction call
     async fn commit_migration(
         &self,
@@ -67,7 +157,8 @@ impl Migration {
         msg: &str,
         counter: usize,
     ) -> anyhow::Result<()> {
-        self.client
+        let gas_burnt = self
+            .client
             .commit_tx(
                 self.config.signer_account_id.clone().unwrap(),
                 self.config.signer_secret_key.clone().unwrap(),
@@ -76,6 +167,7 @@ impl Migration {
                 migration_data,
             )
             .await?;
+        METRICS.batch_committed(gas_burnt);
         print!("\r{msg}: {counter}");
         std::io::stdout().flush()?;
         Ok(())
@@ -87,7 +179,8 @@ impl Migration {
         msg: &str,
         migration_data: Vec<u8>,
         counter: usize,
-    ) -> anyhow::Result<()> {
+        summary: &Summary,
+    ) -> anyhow::Result<MigrationCheckResult> {
         let res = self
             .client
             .request_view(
@@ -97,22 +190,31 @@ impl Migration {
             )
             .await?;
         let correctness = MigrationCheckResult::try_from_slice(&res).unwrap();
-        match correctness {
+        match &correctness {
             MigrationCheckResult::AccountNotExist(missed) => {
-                println!("{msg}: {counter} [Missed: {:?}]", missed.len());
+                summary.text(format_args!(
+                    "{msg}: {counter} [Missed: {:?}]",
+                    missed.len()
+                ));
             }
             MigrationCheckResult::AccountAmount(missed) => {
-                println!("{msg}: {counter} [Missed: {:?}]", missed.len());
+                summary.text(format_args!(
+                    "{msg}: {counter} [Missed: {:?}]",
+                    missed.len()
+                ));
             }
             MigrationCheckResult::Success => {
-                print!("\r{msg}: {counter} [{correctness:?}]");
-                std::io::stdout().flush()?;
+                METRICS.batch_verified();
+                if summary.is_text() {
+                    print!("\r{msg}: {counter} [{correctness:?}]");
+                    std::io::stdout().flush()?;
+                }
             }
             MigrationCheckResult::TotalSupply(_) => {
-                println!("{msg} [Missed field: {correctness:?}]");
+                summary.text(format_args!("{msg} [Missed field: {correctness:?}]"));
             }
         }
-        Ok(())
+        Ok(correctness)
     }
 
     // Checking the correctness and integrity of data, regardless of
@@ -120,8 +222,15 @@ impl Migration {
     async fn check_migration_full(
         &self,
         reproducible_data_for_accounts: Vec<(HashMap<AccountId, Balance>, usize)>,
+        summary: &mut Summary,
     ) -> anyhow::Result<()> {
-        println!("Num of batches: {}", reproducible_data_for_accounts.len());
+        summary.field(
+            "num_of_batches",
+            "Num of batches",
+            reproducible_data_for_accounts.len(),
+        );
+        let mut verified_batches = 0;
+        let mut missed_accounts = 0;
         for (accounts, counter) in reproducible_data_for_accounts {
             let migration_data = MigrationInputData {
                 accounts: accounts.clone(),
@@ -130,145 +239,309 @@ impl Migration {
             .try_to_vec()
             .expect("Failed serialize");
 
-            self.check_migration("Accounts:", migration_data, counter)
-                .await?;
+            match self
+                .check_migration("Accounts:", migration_data, counter, summary)
+                .await?
+            {
+                MigrationCheckResult::Success => verified_batches += 1,
+                MigrationCheckResult::AccountNotExist(missed) => missed_accounts += missed.len(),
+                MigrationCheckResult::AccountAmount(missed) => missed_accounts += missed.len(),
+                MigrationCheckResult::TotalSupply(_) => (),
+            }
         }
 
-        println!();
+        summary.text("");
+        let expected_total_supply = self.expected_total_supply()?;
         let contract_migration_data = MigrationInputData {
             accounts: HashMap::new(),
-            total_supply: Some(
-                self.data.total_supply.as_u128() - self.data.total_stuck_supply.as_u128(),
-            ),
+            total_supply: Some(expected_total_supply),
         }
         .try_to_vec()
         .expect("Failed serialize");
 
-        println!(
-            "Expected total supply: {:?}",
-            self.data.total_supply.as_u128() - self.data.total_stuck_supply.as_u128()
-        );
-        self.check_migration("Contract data:", contract_migration_data, 1)
+        summary.text(format_args!(
+            "Expected total supply: {expected_total_supply:?}"
+        ));
+        summary.set("expected_total_supply", expected_total_supply.to_string());
+        let total_supply = self
+            .check_migration("Contract data:", contract_migration_data, 1, summary)
             .await?;
 
-        println!();
+        summary.text("");
+        summary.set("verified_batches", verified_batches);
+        summary.set("missed_accounts", missed_accounts);
+        summary.set(
+            "total_supply_verified",
+            total_supply == MigrationCheckResult::Success,
+        );
         Ok(())
     }
 
-    fn get_reproducible_data_for_accounts(&self) -> Vec<(HashMap<AccountId, Balance>, usize)> {
+    fn get_reproducible_data_for_accounts(
+        &self,
+        summary: &mut Summary,
+    ) -> Vec<(HashMap<AccountId, Balance>, usize)> {
         // Data limit per transaction
         let limit = RECORDS_COUNT_PER_TX;
 
-        // Accounts migration
-        let mut accounts: HashMap<AccountId, Balance> = HashMap::new();
-        let mut accounts_count = 0;
-        let mut reproducible_data_for_accounts: Vec<(HashMap<AccountId, Balance>, usize)> = vec![];
-
+        // Accounts migration. Accounts that are known to be not registered
+        // in the engine and have no balance are not registered in the connector.
         let mut real_total_supply = NEP141Wei::new(0);
-        for (i, (account, amount)) in self.data.accounts.iter().enumerate() {
+        let mut migrated_accounts = vec![];
+        let mut unregistered_accounts = 0;
+        for (account, amount) in &self.data.accounts {
             real_total_supply = real_total_supply + *amount;
-            accounts.insert(account.clone(), amount.as_u128());
-
-            if accounts.len() < limit && i < self.data.accounts.len() - 1 {
+            if self.data.storage_balances.get(account) == Some(&None)
+                && amount.as_u128() == 0
+                && self.treasury.as_ref() != Some(account)
+            {
+                unregistered_accounts += 1;
                 continue;
             }
-            accounts_count += &accounts.len();
-
-            reproducible_data_for_accounts.push((accounts.clone(), accounts_count));
+            migrated_accounts.push((account, *amount));
+        }
 
-            // Clear
-            accounts.clear();
+        let mut accounts_count = 0;
+        let mut reproducible_data_for_accounts: Vec<(HashMap<AccountId, Balance>, usize)> = vec![];
+        for batch in migrated_accounts.chunks(limit) {
+            let accounts: HashMap<AccountId, Balance> = batch
+                .iter()
+                .map(|(account, amount)| {
+                    ((*account).clone(), self.expected_balance(account, *amount))
+                })
+                .collect();
+            accounts_count += accounts.len();
+            reproducible_data_for_accounts.push((accounts, accounts_count));
         }
 
+        let excluded_supply = self.excluded_supply();
         let diff_total_supply = self
             .data
             .total_supply
-            .checked_sub(real_total_supply)
+            .checked_sub(real_total_supply + excluded_supply)
             .expect("Real total supply cannot be higher than the total supply");
 
-        println!("num_of_accounts: {}", self.data.accounts.len());
-        println!("total_supply: {}", self.data.total_supply);
-        println!("real_total_supply: {}", real_total_supply);
-        println!("total_stuck_supply: {}", self.data.total_stuck_supply);
-        println!("total_supply - real_total_supply: {}", diff_total_supply);
-        assert_eq!(self.data.accounts.len(), accounts_count);
+        let mut excluded: Vec<_> = self.excluded.iter().collect();
+        excluded.sort_by(|a, b| a.0.cmp(b.0));
+        for (account, balance) in excluded {
+            summary.text(format_args!(
+                "\tExcluded account: {account} with balance {balance}"
+            ));
+        }
+
+        summary.field(
+            "num_of_accounts",
+            "num_of_accounts",
+            self.data.accounts.len(),
+        );
+        summary.field(
+            "num_of_excluded_accounts",
+            "num_of_excluded_accounts",
+            self.excluded.len(),
+        );
+        summary.field(
+            "num_of_unregistered_accounts",
+            "num_of_unregistered_accounts",
+            unregistered_accounts,
+        );
+        summary.field(
+            "total_supply",
+            "total_supply",
+            self.data.total_supply.to_string(),
+        );
+        summary.field(
+            "real_total_supply",
+            "real_total_supply",
+            real_total_supply.to_string(),
+        );
+        summary.field(
+            "excluded_supply",
+            "excluded_supply",
+            excluded_supply.to_string(),
+        );
+        summary.field(
+            "total_stuck_supply",
+            "total_stuck_supply",
+            self.data.total_stuck_supply.to_string(),
+        );
+        if let Some(treasury) = &self.treasury {
+            summary.field(
+                "treasury",
+                "treasury (receives total_stuck_supply)",
+                treasury.to_string(),
+            );
+        }
+        summary.field(
+            "diff_total_supply",
+            "total_supply - real_total_supply - excluded_supply",
+            diff_total_supply.to_string(),
+        );
+        assert_eq!(
+            self.data.accounts.len(),
+            accounts_count + unregistered_accounts
+        );
 
         reproducible_data_for_accounts
     }
 
-    /// Check migration
-    pub async fn validate_migration(&self) -> anyhow::Result<()> {
-        let reproducible_data_for_accounts = self.get_reproducible_data_for_accounts();
-        self.check_migration_full(reproducible_data_for_accounts)
-            .await
+    /// Check migration and print summary in the format
+    pub async fn validate_migration(&self, format: SummaryFormat) -> anyhow::Result<()> {
+        let mut summary = Summary::new(format);
+        let reproducible_data_for_accounts = self.get_reproducible_data_for_accounts(&mut summary);
+        self.check_migration_full(reproducible_data_for_accounts, &mut summary)
+            .await?;
+        summary.finish();
+        Ok(())
     }
 
     /// Run migration process
     pub async fn run(&self) -> anyhow::Result<()> {
-        let reproducible_data_for_accounts = self.get_reproducible_data_for_accounts();
+        let mut summary = Summary::new(SummaryFormat::Text);
+        let reproducible_data_for_accounts = self.get_reproducible_data_for_accounts(&mut summary);
         println!("Num of batches: {}", reproducible_data_for_accounts.len());
         for (index, (accounts, accounts_count)) in reproducible_data_for_accounts.iter().enumerate()
         {
-            let migration_data: Vec<AccountId> = accounts.keys().cloned().collect();
             println!(
                 "commit_migration_batch: {index}, num_of_accounts: {}",
-                migration_data.len()
+                accounts.len()
             );
-            self.commit_migration(
-                migration_data.try_to_vec().expect("Failed serialize"),
-                "Accounts",
-                *accounts_count,
-            )
-            .await?;
+            // Treasury account is in the batches with its expected balance
+            let migration_data = MigrationInputData {
+                accounts: accounts.clone(),
+                total_supply: None,
+            }
+            .try_to_vec()
+            .expect("Failed serialize");
+            self.commit_migration(migration_data, "Accounts", *accounts_count)
+                .await?;
         }
 
-        self.check_migration_full(reproducible_data_for_accounts)
+        self.check_migration_full(reproducible_data_for_accounts, &mut summary)
             .await
     }
 
     /// Prepare indexed data for migration from Indexer data
     /// and store to file serialized with borsh.
-    pub async fn prepare_indexed<P: AsRef<Path>>(input: P, output: P) -> anyhow::Result<()> {
-        use crate::indexer::IndexerData;
-        use crate::rpc::AURORA_CONTRACT;
+    /// With `exclude_failed` accounts found only in failed actions are skipped.
+    /// With `followed` state data, kept by the indexer follow mode, balances
+    /// are fetched only for accounts touched after their last refresh.
+    /// Balances are requested from `contract` engine account.
+    pub async fn prepare_indexed<P: AsRef<Path>>(
+        input: P,
+        output: P,
+        contract: &str,
+        exclude_failed: bool,
+        followed: Option<P>,
+        format: SummaryFormat,
+    ) -> anyhow::Result<()> {
+        use crate::rpc::{BlockKind, FailedAccounts};
+        use crate::store::load_indexer_data;
 
-        let data = std::fs::read(input)
-            .map_err(|e| anyhow::anyhow!("Failed read indexer data file, {e}"))?;
-        let indexer_data: IndexerData = IndexerData::try_from_slice(&data)
-            .map_err(|e| anyhow::anyhow!("Failed deserialize indexed data, {e}"))?;
-        let rpc = Client::new();
+        // The last block in which the account was touched
+        let mut touched: HashMap<AccountId, u64> = HashMap::new();
+        let mut failed_accounts = FailedAccounts::default();
+        let indexer_data = load_indexer_data(input, |log| {
+            failed_accounts.add(&log);
+            for account in log.actions.iter().flat_map(|action| &action.accounts) {
+                let height = touched.entry(account.clone()).or_default();
+                *height = (*height).max(log.block_height);
+            }
+        })?;
+        let followed = followed
+            .map(|followed| {
+                std::fs::read(followed)
+                    .map_err(|e| anyhow::anyhow!("Failed read followed state data file, {e}"))
+                    .and_then(|data| {
+                        StateData::try_from_slice(&data).map_err(|e| {
+                            anyhow::anyhow!("Failed deserialize followed state data, {e}")
+                        })
+                    })
+            })
+            .transpose()?;
+        let mut rpc = Client::new();
+
+        // Aurora contract is paused, so balances are the same for any block after that
+        let (block_height, ..) = rpc.get_block(BlockKind::Latest).await?;
 
         let mut migration_data = StateData {
+            block_height,
             total_supply: NEP141Wei::new(0),
             total_stuck_supply: NEP141Wei::new(0),
             accounts: HashMap::new(),
+            storage_balances: HashMap::new(),
+            provenance: HashMap::new(),
+            stuck_accounts: HashMap::new(),
         };
 
         let data = rpc
-            .request_view(AURORA_CONTRACT, "ft_total_supply".to_string(), vec![])
+            .request_view(contract, "ft_total_supply".to_string(), vec![])
             .await?;
         let total_supply: U128 = serde_json::from_slice(&data).unwrap();
         migration_data.total_supply = NEP141Wei::new(total_supply.0);
 
-        for account in indexer_data.data.accounts {
-            let args = json!({ "account_id": account })
-                .to_string()
-                .as_bytes()
-                .to_vec();
+        let failed_accounts = if exclude_failed {
+            failed_accounts.failed_only()
+        } else {
+            HashSet::new()
+        };
+        let mut accounts = indexer_data.data.accounts;
+        accounts.retain(|account| !failed_accounts.contains(account));
+        let mut summary = Summary::new(format);
+        if exclude_failed {
+            summary.field(
+                "excluded_failed_accounts",
+                "Excluded failed accounts",
+                failed_accounts.len(),
+            );
+        }
 
-            let data = rpc
-                .request_view(AURORA_CONTRACT, "ft_balance_of".to_string(), args)
-                .await?;
-            let balance: U128 =
-                serde_json::from_slice(&data[..]).expect("Failed deserialize account balance");
+        let mut followed_accounts = 0;
+        for account in accounts {
+            let followed_balance = followed
+                .as_ref()
+                .and_then(|followed| followed_balance(followed, &account, touched.get(&account)));
+            let (balance, storage_balance) = match followed_balance {
+                Some(balance) => {
+                    followed_accounts += 1;
+                    balance
+                }
+                None => fetch_account_balance(&rpc, contract, &account).await?,
+            };
             migration_data
-                .accounts
-                .insert(account, NEP141Wei::new(balance.0));
-            tokio::time::sleep(REQUEST_TIMEOUT).await;
+                .storage_balances
+                .insert(account.clone(), storage_balance);
+            migration_data.accounts.insert(account, balance);
         }
 
-        println!("Accounts: {:?}", migration_data.accounts.len());
-        println!("Total supply: {:?}", migration_data.total_supply.as_u128());
+        summary.field("block_height", "Block height", block_height);
+        summary.field("accounts", "Accounts", migration_data.accounts.len());
+        if followed.is_some() {
+            summary.field(
+                "followed_accounts",
+                "Accounts taken from followed state",
+                followed_accounts,
+            );
+            summary.field(
+                "fetched_accounts",
+                "Fetched accounts",
+                migration_data.accounts.len() - followed_accounts,
+            );
+        }
+        summary.field(
+            "registered_accounts",
+            "Registered accounts",
+            migration_data
+                .storage_balances
+                .values()
+                .filter(|storage_balance| storage_balance.is_some())
+                .count(),
+        );
+        summary.field(
+            "total_supply",
+            "Total supply",
+            migration_data.total_supply.as_u128().to_string(),
+        );
+        summary.finish();
 
         migration_data
             .try_to_vec()
@@ -276,10 +549,15 @@ impl Migration {
             .map_err(|e| anyhow::anyhow!("Failed save migration data, {e}"))
     }
 
+    /// Combine state and indexed data. Indexed balances take precedence
+    /// over state balances, and total supply is taken from indexed data.
+    /// Every account in the output is tagged with its provenance.
     pub fn combine_indexed_and_state_data<P: AsRef<Path>>(
         state: P,
         indexed: P,
         output: P,
+        report_file: Option<P>,
+        format: SummaryFormat,
     ) -> anyhow::Result<()> {
         let mut state_data = {
             let data = std::fs::read(state)
@@ -295,17 +573,81 @@ impl Migration {
                 .map_err(|e| anyhow::anyhow!("Failed deserialize indexed data, {e}"))?
         };
 
+        let report = CombineReport::new(&state_data, &indexed_data);
+
+        state_data.provenance = state_data
+            .accounts
+            .keys()
+            .map(|account| {
+                let provenance = Provenance {
+                    source: AccountSource::State,
+                    block_height: state_data.block_height,
+                };
+                (account.clone(), provenance)
+            })
+            .collect();
         for (account, balance) in indexed_data.accounts {
+            state_data.provenance.insert(
+                account.clone(),
+                Provenance {
+                    source: AccountSource::Indexed,
+                    block_height: indexed_data.block_height,
+                },
+            );
             state_data.accounts.insert(account, balance);
         }
+        for (account, storage_balance) in indexed_data.storage_balances {
+            state_data.storage_balances.insert(account, storage_balance);
+        }
+        state_data
+            .stuck_accounts
+            .extend(indexed_data.stuck_accounts);
         state_data.total_supply = indexed_data.total_supply;
+        state_data.block_height = indexed_data.block_height;
 
-        println!("Accounts: {:?}", state_data.accounts.len());
-        println!("Total supply: {:?}", state_data.total_supply.as_u128());
-        println!(
-            "Total stuck supply: {:?}",
-            state_data.total_stuck_supply.as_u128()
+        let mut summary = Summary::new(format);
+        if summary.is_text() {
+            report.print(report_file.is_none());
+        } else {
+            summary.set("report", report.to_json());
+        }
+        if let Some(report_file) = report_file {
+            std::fs::write(report_file, report.to_json().to_string())
+                .map_err(|e| anyhow::anyhow!("Failed save combine report, {e}"))?;
+        }
+
+        let real_total_supply = state_data
+            .accounts
+            .values()
+            .try_fold(state_data.total_stuck_supply.as_u128(), |sum, balance| {
+                sum.checked_add(balance.as_u128())
+            })
+            .ok_or_else(|| anyhow::anyhow!("Accounts balances sum overflow"))?;
+
+        summary.field("accounts", "Accounts", state_data.accounts.len());
+        summary.field(
+            "total_supply",
+            "Total supply",
+            state_data.total_supply.as_u128().to_string(),
+        );
+        summary.field(
+            "total_stuck_supply",
+            "Total stuck supply",
+            state_data.total_stuck_supply.as_u128().to_string(),
         );
+        summary.field(
+            "balances_sum",
+            "Balances sum + stuck supply",
+            real_total_supply.to_string(),
+        );
+        summary.finish();
+
+        if real_total_supply != state_data.total_supply.as_u128() {
+            anyhow::bail!(
+                "Balances sum + stuck supply {real_total_supply} doesn't match total supply {}",
+                state_data.total_supply.as_u128()
+            );
+        }
 
         state_data
             .try_to_vec()
@@ -313,3 +655,446 @@ impl Migration {
             .map_err(|e| anyhow::anyhow!("Failed save migration data, {e}"))
     }
 }
+
+/// Differences between state and indexed data found during combining
+#[derive(Debug, Default)]
+pub struct CombineReport {
+    pub state_block_height: u64,
+    pub indexed_block_height: u64,
+    pub state_total_supply: Balance,
+    pub indexed_total_supply: Balance,
+    pub only_in_state: Vec<(AccountId, Balance)>,
+    pub only_in_indexed: Vec<(AccountId, Balance)>,
+    /// Accounts with different balances: state balance and indexed balance
+    pub changed: Vec<(AccountId, Balance, Balance)>,
+}
+
+impl CombineReport {
+    #[must_use]
+    pub fn new(state_data: &StateData, indexed_data: &StateData) -> Self {
+        let mut report = Self {
+            state_block_height: state_data.block_height,
+            indexed_block_height: indexed_data.block_height,
+            state_total_supply: state_data.total_supply.as_u128(),
+            indexed_total_supply: indexed_data.total_supply.as_u128(),
+            ..Self::default()
+        };
+
+        for (account, balance) in &state_data.accounts {
+            if !indexed_data.accounts.contains_key(account) {
+                report
+                    .only_in_state
+                    .push((account.clone(), balance.as_u128()));
+            }
+        }
+        for (account, balance) in &indexed_data.accounts {
+            match state_data.accounts.get(account) {
+                None => report
+                    .only_in_indexed
+                    .push((account.clone(), balance.as_u128())),
+                Some(state_balance) if state_balance != balance => report.changed.push((
+                    account.clone(),
+                    state_balance.as_u128(),
+                    balance.as_u128(),
+                )),
+                Some(_) => (),
+            }
+        }
+
+        report.only_in_state.sort();
+        report.only_in_indexed.sort();
+        report.changed.sort();
+        report
+    }
+
+    /// Print report summary. With `extend` print all accounts.
+    pub fn print(&self, extend: bool) {
+        if extend {
+            for (account, balance) in &self.only_in_state {
+                println!("\tOnly in state: {account} with balance {balance}");
+            }
+            for (account, balance) in &self.only_in_indexed {
+                println!("\tOnly in indexed: {account} with balance {balance}");
+            }
+            for (account, state_balance, indexed_balance) in &self.changed {
+                println!(
+                    "\tChanged: {account} {state_balance} -> {indexed_balance} [{}]",
+                    balance_delta(*state_balance, *indexed_balance)
+                );
+            }
+        }
+        println!("State block height: {:?}", self.state_block_height);
+        println!("Indexed block height: {:?}", self.indexed_block_height);
+        println!("State total supply: {:?}", self.state_total_supply);
+        println!("Indexed total supply: {:?}", self.indexed_total_supply);
+        println!("Accounts only in state: {}", self.only_in_state.len());
+        println!("Accounts only in indexed: {}", self.only_in_indexed.len());
+        println!("Accounts with changed balance: {}", self.changed.len());
+    }
+
+    #[must_use]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "state_block_height": self.state_block_height,
+            "indexed_block_height": self.indexed_block_height,
+            "state_total_supply": self.state_total_supply.to_string(),
+            "indexed_total_supply": self.indexed_total_supply.to_string(),
+            "only_in_state": self.only_in_state.iter().map(|(account, balance)| json!({
+                "account_id": account,
+                "balance": balance.to_string(),
+            })).collect::<Vec<_>>(),
+            "only_in_indexed": self.only_in_indexed.iter().map(|(account, balance)| json!({
+                "account_id": account,
+                "balance": balance.to_string(),
+            })).collect::<Vec<_>>(),
+            "changed": self.changed.iter().map(|(account, state_balance, indexed_balance)| json!({
+                "account_id": account,
+                "state_balance": state_balance.to_string(),
+                "indexed_balance": indexed_balance.to_string(),
+                "delta": balance_delta(*state_balance, *indexed_balance),
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Get NEP-141 balance and NEP-145 storage balance of the account from
+/// `contract` at the final block. Requests share the client rate limit.
+pub(crate) async fn fetch_account_balance(
+    rpc: &Client,
+    contract: &str,
+    account: &AccountId,
+) -> anyhow::Result<(NEP141Wei, Option<StorageBalance>)> {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct StorageBalanceResult {
+        total: U128,
+        available: U128,
+    }
+
+    let args = json!({ "account_id": account })
+        .to_string()
+        .as_bytes()
+        .to_vec();
+
+    rpc.limiter.wait().await;
+    let data = rpc
+        .request_view(contract, "ft_balance_of".to_string(), args.clone())
+        .await?;
+    let balance: U128 = serde_json::from_slice(&data[..])
+        .map_err(|e| anyhow::anyhow!("Failed deserialize account balance, {e}"))?;
+
+    rpc.limiter.wait().await;
+    let data = rpc
+        .request_view(contract, "storage_balance_of".to_string(), args)
+        .await?;
+    let storage_balance: Option<StorageBalanceResult> = serde_json::from_slice(&data[..])
+        .map_err(|e| anyhow::anyhow!("Failed deserialize account storage balance, {e}"))?;
+
+    Ok((
+        NEP141Wei::new(balance.0),
+        storage_balance.map(|storage_balance| StorageBalance {
+            total: storage_balance.total.0,
+            available: storage_balance.available.0,
+        }),
+    ))
+}
+
+/// Balance and storage balance of the account from followed state data.
+/// Followed balance is fresh if the account wasn't touched after its
+/// refresh: the refresh provenance height isn't lower than the last block
+/// `touched` in which the account was touched.
+fn followed_balance(
+    followed: &StateData,
+    account: &AccountId,
+    touched: Option<&u64>,
+) -> Option<(NEP141Wei, Option<StorageBalance>)> {
+    let refreshed = followed.provenance.get(account)?.block_height;
+    if refreshed < *touched? {
+        return None;
+    }
+    Some((
+        *followed.accounts.get(account)?,
+        *followed.storage_balances.get(account)?,
+    ))
+}
+
+/// Signed difference between two balances
+fn balance_delta(from: Balance, to: Balance) -> String {
+    if to >= from {
+        format!("+{}", to - from)
+    } else {
+        format!("-{}", from - to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+
+    fn account(account: &str) -> AccountId {
+        account.parse().unwrap()
+    }
+
+    fn migration(treasury: Option<&str>, excluded: &[(&str, u128)]) -> Migration {
+        let data = StateData {
+            block_height: 100,
+            total_supply: NEP141Wei::new(1_000),
+            total_stuck_supply: NEP141Wei::new(50),
+            accounts: HashMap::from([
+                (account("alice.near"), NEP141Wei::new(600)),
+                (account("bob.near"), NEP141Wei::new(250)),
+            ]),
+            storage_balances: HashMap::new(),
+            provenance: HashMap::new(),
+            stuck_accounts: HashMap::new(),
+        };
+        let mut migration = Migration {
+            client: Client::new(),
+            data,
+            config: MigrationConfig {
+                signer_account_id: None,
+                signer_secret_key: None,
+                contract: "aurora-eth-connector.near".to_string(),
+            },
+            excluded: excluded
+                .iter()
+                .map(|(account_id, balance)| (account(account_id), NEP141Wei::new(*balance)))
+                .collect(),
+            treasury: treasury.map(account),
+        };
+        if let Some(treasury) = &migration.treasury {
+            migration
+                .data
+                .accounts
+                .entry(treasury.clone())
+                .or_insert_with(|| NEP141Wei::new(0));
+        }
+        migration
+    }
+
+    fn batches(migration: &Migration) -> HashMap<AccountId, Balance> {
+        let mut summary = Summary::new(SummaryFormat::Json);
+        migration
+            .get_reproducible_data_for_accounts(&mut summary)
+            .into_iter()
+            .flat_map(|(accounts, _)| accounts)
+            .collect()
+    }
+
+    #[test]
+    fn test_treasury_in_migrated_batch() {
+        let migration = migration(Some("treasury.near"), &[("carol.near", 100)]);
+        let accounts = batches(&migration);
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[&account("treasury.near")], 50);
+        assert_eq!(accounts[&account("alice.near")], 600);
+        assert_eq!(migration.expected_total_supply().unwrap(), 900);
+    }
+
+    #[test]
+    fn test_treasury_with_own_balance() {
+        let migration = migration(Some("bob.near"), &[("carol.near", 100)]);
+        let accounts = batches(&migration);
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[&account("bob.near")], 300);
+    }
+
+    #[test]
+    fn test_stuck_supply_without_treasury() {
+        let migration = migration(None, &[]);
+        let accounts = batches(&migration);
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[&account("bob.near")], 250);
+        assert_eq!(migration.expected_total_supply().unwrap(), 950);
+    }
+
+    #[test]
+    fn test_unregistered_accounts_not_migrated() {
+        let mut migration = migration(None, &[]);
+        migration
+            .data
+            .accounts
+            .insert(account("carol.near"), NEP141Wei::new(0));
+        migration
+            .data
+            .accounts
+            .insert(account("dave.near"), NEP141Wei::new(0));
+        let storage_balance = StorageBalance {
+            total: 1_250_000_000_000_000_000_000,
+            available: 0,
+        };
+        migration.data.storage_balances = HashMap::from([
+            (account("alice.near"), Some(storage_balance)),
+            (account("carol.near"), None),
+            (account("dave.near"), Some(storage_balance)),
+        ]);
+        let accounts = batches(&migration);
+        // Registered account without balance is migrated, as account without storage data
+        assert_eq!(accounts.len(), 3);
+        assert!(!accounts.contains_key(&account("carol.near")));
+        assert_eq!(accounts[&account("dave.near")], 0);
+    }
+
+    #[test]
+    fn test_expected_total_supply_underflow() {
+        let migration = migration(None, &[("carol.near", 1_000)]);
+        assert!(migration.expected_total_supply().is_err());
+    }
+
+    fn state_data(
+        block_height: u64,
+        total_supply: u128,
+        stuck_supply: u128,
+        accounts: &[(&str, u128)],
+    ) -> StateData {
+        StateData {
+            block_height,
+            total_supply: NEP141Wei::new(total_supply),
+            total_stuck_supply: NEP141Wei::new(stuck_supply),
+            accounts: accounts
+                .iter()
+                .map(|(account_id, balance)| (account(account_id), NEP141Wei::new(*balance)))
+                .collect(),
+            ..StateData::default()
+        }
+    }
+
+    /// Write state and indexed data files and combine them
+    fn combine(dir: &TestDir, state: &StateData, indexed: &StateData) -> anyhow::Result<StateData> {
+        let write = |file: &str, data: &StateData| {
+            let path = dir.path(file);
+            std::fs::write(&path, data.try_to_vec().unwrap()).unwrap();
+            path
+        };
+        let output = dir.path("combined.borsh");
+        Migration::combine_indexed_and_state_data(
+            write("state.borsh", state),
+            write("indexed.borsh", indexed),
+            output.clone(),
+            Some(dir.path("report.json")),
+            SummaryFormat::Json,
+        )?;
+        Ok(StateData::try_from_slice(&std::fs::read(output).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_combine_report() {
+        let state = state_data(
+            100,
+            1_000,
+            50,
+            &[("alice.near", 600), ("bob.near", 250), ("carol.near", 100)],
+        );
+        let indexed = state_data(
+            200,
+            1_010,
+            0,
+            &[("alice.near", 500), ("bob.near", 250), ("dave.near", 110)],
+        );
+        let report = CombineReport::new(&state, &indexed);
+        assert_eq!(report.state_block_height, 100);
+        assert_eq!(report.indexed_block_height, 200);
+        assert_eq!(report.only_in_state, [(account("carol.near"), 100)]);
+        assert_eq!(report.only_in_indexed, [(account("dave.near"), 110)]);
+        assert_eq!(report.changed, [(account("alice.near"), 600, 500)]);
+
+        let json = report.to_json();
+        assert_eq!(json["changed"][0]["account_id"], "alice.near");
+        assert_eq!(json["changed"][0]["delta"], "-100");
+        assert_eq!(json["only_in_indexed"][0]["balance"], "110");
+    }
+
+    #[test]
+    fn test_combine_indexed_and_state_data() {
+        let dir = TestDir::new("migration-combine");
+        let state = state_data(
+            100,
+            1_000,
+            50,
+            &[("alice.near", 600), ("bob.near", 250), ("carol.near", 100)],
+        );
+        let indexed = state_data(
+            200,
+            1_060,
+            0,
+            &[("alice.near", 500), ("bob.near", 300), ("dave.near", 110)],
+        );
+        let data = combine(&dir, &state, &indexed).unwrap();
+        assert_eq!(data.block_height, 200);
+        assert_eq!(data.total_supply.as_u128(), 1_060);
+        assert_eq!(data.total_stuck_supply.as_u128(), 50);
+        assert_eq!(data.accounts.len(), 4);
+        assert_eq!(data.accounts[&account("alice.near")].as_u128(), 500);
+        assert_eq!(data.accounts[&account("carol.near")].as_u128(), 100);
+        assert_eq!(
+            data.provenance[&account("carol.near")].source,
+            AccountSource::State
+        );
+        assert_eq!(
+            data.provenance[&account("dave.near")],
+            Provenance {
+                source: AccountSource::Indexed,
+                block_height: 200,
+            }
+        );
+
+        let report = std::fs::read(dir.path("report.json")).unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&report).unwrap();
+        assert_eq!(report["changed"].as_array().unwrap().len(), 2);
+        assert_eq!(report["changed"][1]["account_id"], "bob.near");
+        assert_eq!(report["changed"][1]["delta"], "+50");
+    }
+
+    #[test]
+    fn test_combine_supply_mismatch() {
+        let dir = TestDir::new("migration-combine-mismatch");
+        let state = state_data(100, 1_000, 50, &[("alice.near", 950)]);
+        let indexed = state_data(200, 1_000, 0, &[("alice.near", 900)]);
+        let error = combine(&dir, &state, &indexed).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Balances sum + stuck supply 950 doesn't match total supply 1000"
+        );
+        assert!(!dir.path("combined.borsh").exists());
+    }
+
+    #[test]
+    fn test_followed_balance() {
+        let refreshed = |account_id: &str, block_height| {
+            (
+                account(account_id),
+                Provenance {
+                    source: AccountSource::Indexed,
+                    block_height,
+                },
+            )
+        };
+        let mut followed = state_data(
+            100,
+            1_000,
+            0,
+            &[("alice.near", 600), ("bob.near", 250), ("carol.near", 150)],
+        );
+        followed.provenance =
+            HashMap::from([refreshed("alice.near", 20), refreshed("bob.near", 20)]);
+        followed.storage_balances = ["alice.near", "bob.near", "carol.near"]
+            .into_iter()
+            .map(|account_id| (account(account_id), None))
+            .collect();
+
+        let balance = |account_id: &str, touched: Option<u64>| {
+            followed_balance(&followed, &account(account_id), touched.as_ref())
+                .map(|(balance, _)| balance.as_u128())
+        };
+        // Refreshed at or after the last touch
+        assert_eq!(balance("alice.near", Some(20)), Some(600));
+        assert_eq!(balance("alice.near", Some(19)), Some(600));
+        // Touched after the refresh, or unknown refresh or touch heights
+        assert_eq!(balance("bob.near", Some(21)), None);
+        assert_eq!(balance("bob.near", None), None);
+        assert_eq!(balance("carol.near", Some(10)), None);
+        assert_eq!(balance("dave.near", Some(10)), None);
+    }
+}
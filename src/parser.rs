@@ -1,4 +1,5 @@
-use aurora_engine_migration_tool::{BlockData, FungibleToken, StateData};
+use crate::summary::{Summary, SummaryFormat};
+use aurora_engine_migration_tool::{BlockData, FungibleToken, StateData, StorageBalance};
 use aurora_engine_types::storage::{bytes_to_key, EthConnectorStorageId, KeyPrefix};
 use aurora_engine_types::types::NEP141Wei;
 use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
@@ -7,6 +8,9 @@ use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+/// Storage price per byte in `yoctoNEAR` (same as `env::storage_byte_cost()`)
+const STORAGE_PRICE_PER_BYTE: u128 = 10_000_000_000_000_000_000;
+
 enum KeyType {
     Accounts(Vec<u8>),
     Contract,
@@ -28,7 +32,11 @@ pub fn get_contract_key() -> Vec<u8> {
     construct_contract_key(EthConnectorStorageId::FungibleToken)
 }
 
-pub fn parse<P: AsRef<Path>>(json_file: P, output: Option<P>) -> anyhow::Result<()> {
+pub fn parse<P: AsRef<Path>>(
+    json_file: P,
+    output: Option<P>,
+    format: SummaryFormat,
+) -> anyhow::Result<()> {
     let data =
         std::fs::read_to_string(json_file).map_err(|e| anyhow::anyhow!("Failed read data: {e}"))?;
     let json_data: BlockData =
@@ -43,12 +51,22 @@ pub fn parse<P: AsRef<Path>>(json_file: P, output: Option<P>) -> anyhow::Result<
         |p| p.as_ref().to_path_buf(),
     );
 
-    println!("Block height: {:?}", json_data.result.block_height);
-    println!("Data size: {:.3} Gb", data.len() as f64 / 1_000_000_000.);
-    println!("Data values: {:#?}", json_data.result.values.len());
+    let mut summary = Summary::new(format);
+    summary.field(
+        "block_height",
+        "Block height",
+        json_data.result.block_height,
+    );
+    summary.text(format_args!(
+        "Data size: {:.3} Gb",
+        data.len() as f64 / 1_000_000_000.
+    ));
+    summary.set("data_size", data.len());
+    summary.field("data_values", "Data values", json_data.result.values.len());
 
     let mut accounts: HashMap<AccountId, NEP141Wei> = HashMap::new();
-    let mut contract_data: FungibleToken = FungibleToken::default();
+    let mut contract_data: Option<FungibleToken> = None;
+    let mut stuck_accounts: HashMap<String, NEP141Wei> = HashMap::new();
     let mut total_stuck_supply = NEP141Wei::new(0);
     let mut real_total_supply = NEP141Wei::new(0);
 
@@ -67,7 +85,10 @@ pub fn parse<P: AsRef<Path>>(json_file: P, output: Option<P>) -> anyhow::Result<
                 .map_err(|e| anyhow::anyhow!("Failed parse account balance, {e}"))?;
                 let Ok(account) = AccountId::from_str(account_str) else {
                     total_stuck_supply = total_stuck_supply + account_balance;
-                    println!("\tNot fetched account: {account_str} with balance {account_balance}");
+                    stuck_accounts.insert(account_str.to_string(), account_balance);
+                    summary.text(format_args!(
+                        "\tNot fetched account: {account_str} with balance {account_balance}"
+                    ));
                     continue;
                 };
                 real_total_supply = real_total_supply + account_balance;
@@ -77,36 +98,77 @@ pub fn parse<P: AsRef<Path>>(json_file: P, output: Option<P>) -> anyhow::Result<
                 let val = base64::decode(&result_value.value)
                     .map_err(|e| anyhow::anyhow!("Failed get contract data, {e}"))?;
 
-                contract_data = FungibleToken::try_from_slice(&val)
-                    .map_err(|e| anyhow::anyhow!("Failed parse contract data, {e}"))?;
+                contract_data = Some(
+                    FungibleToken::try_from_slice(&val)
+                        .map_err(|e| anyhow::anyhow!("Failed parse contract data, {e}"))?,
+                );
             }
             KeyType::Unknown => (), //anyhow::bail!("Unknown key type"),
         }
     }
 
+    let contract_data =
+        contract_data.ok_or_else(|| anyhow::anyhow!("Contract data not found in the snapshot"))?;
+    let storage_balances = accounts
+        .keys()
+        .map(|account| (account.clone(), Some(storage_balance(&contract_data))))
+        .collect();
+
     let total_supply = contract_data.total_eth_supply_on_near;
-    println!("num_of_accounts: {}", accounts.len());
-    println!("total_supply: {total_supply}");
-    println!("real_total_supply: {real_total_supply}");
-    println!("total_stuck_supply: {total_stuck_supply}");
-    println!(
-        "total_supply - real_total_supply: {}",
-        total_supply
-            .checked_sub(real_total_supply)
-            .expect("Real total supply cannot be higher than the total supply")
+    let diff_total_supply = total_supply
+        .checked_sub(real_total_supply)
+        .expect("Real total supply cannot be higher than the total supply");
+    summary.field("num_of_accounts", "num_of_accounts", accounts.len());
+    summary.field(
+        "account_storage_usage",
+        "account_storage_usage",
+        contract_data.account_storage_usage,
+    );
+    summary.field("total_supply", "total_supply", total_supply.to_string());
+    summary.field(
+        "real_total_supply",
+        "real_total_supply",
+        real_total_supply.to_string(),
+    );
+    summary.field(
+        "total_stuck_supply",
+        "total_stuck_supply",
+        total_stuck_supply.to_string(),
     );
+    summary.set("num_of_stuck_accounts", stuck_accounts.len());
+    summary.field(
+        "diff_total_supply",
+        "total_supply - real_total_supply",
+        diff_total_supply.to_string(),
+    );
+    summary.finish();
 
     // Store result data
     StateData {
+        block_height: json_data.result.block_height,
         total_supply,
         total_stuck_supply,
         accounts,
+        storage_balances,
+        provenance: HashMap::new(),
+        stuck_accounts,
     }
     .try_to_vec()
     .and_then(|data| std::fs::write(result_file_name, data))
     .map_err(|e| anyhow::anyhow!("Failed save result data, {e}"))
 }
 
+/// NEP-145 storage balance of the account registered in the engine. The
+/// engine keeps no storage record per account: the account is registered
+/// if it has a balance record, and `storage_balance_of` returns the storage
+/// balance bound of the contract with nothing available.
+fn storage_balance(contract_data: &FungibleToken) -> StorageBalance {
+    StorageBalance {
+        total: u128::from(contract_data.account_storage_usage) * STORAGE_PRICE_PER_BYTE,
+        available: 0,
+    }
+}
+
 fn key_type(key: &[u8]) -> KeyType {
     if is_account_prefix_key(key) {
         let account_prefix_len = prefix_account_key().len();
@@ -123,3 +185,59 @@ fn is_account_prefix_key(key: &[u8]) -> bool {
     let account_prefix = &prefix_account_key();
     key.len() > account_prefix.len() && &key[..account_prefix.len()] == account_prefix
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+
+    #[test]
+    fn test_parse_snapshot() {
+        let dir = TestDir::new("parser-snapshot");
+        let snapshot =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/state/snapshot.json");
+        let output = dir.path("state.borsh");
+        parse(&snapshot, Some(&output), SummaryFormat::Json).unwrap();
+        let data = StateData::try_from_slice(&std::fs::read(&output).unwrap()).unwrap();
+
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let bob: AccountId = "bob.near".parse().unwrap();
+        assert_eq!(data.block_height, 100);
+        assert_eq!(data.total_supply.as_u128(), 700);
+        assert_eq!(data.total_stuck_supply.as_u128(), 50);
+        assert_eq!(data.accounts.len(), 2);
+        assert_eq!(data.accounts[&alice].as_u128(), 600);
+        assert_eq!(data.accounts[&bob].as_u128(), 0);
+        assert_eq!(data.stuck_accounts["Invalid Account"], NEP141Wei::new(50));
+
+        // Account with zero balance is registered too
+        let storage_balance = Some(StorageBalance {
+            total: 100 * STORAGE_PRICE_PER_BYTE,
+            available: 0,
+        });
+        assert_eq!(data.storage_balances.len(), 2);
+        assert_eq!(data.storage_balances[&alice], storage_balance);
+        assert_eq!(data.storage_balances[&bob], storage_balance);
+    }
+
+    #[test]
+    fn test_snapshot_without_contract_data() {
+        let dir = TestDir::new("parser-no-contract");
+        let snapshot = std::fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/state/snapshot.json"),
+        )
+        .unwrap();
+        let mut snapshot: serde_json::Value = serde_json::from_str(&snapshot).unwrap();
+        let contract_key = base64::encode(get_contract_key());
+        snapshot["result"]["values"]
+            .as_array_mut()
+            .unwrap()
+            .retain(|value| value["key"] != contract_key.as_str());
+        let input = dir.path("snapshot.json");
+        std::fs::write(&input, snapshot.to_string()).unwrap();
+
+        let output = dir.path("state.borsh");
+        let error = parse(&input, Some(&output), SummaryFormat::Json).unwrap_err();
+        assert_eq!(error.to_string(), "Contract data not found in the snapshot");
+    }
+}
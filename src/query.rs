@@ -0,0 +1,402 @@
+//! # Query
+//! Search indexed actions logs by account, method, block range and
+//! execution status.
+//!
+use crate::rpc::{ActionResultLog, ExecutionStatus, IndexedResultLog};
+use crate::store::load_indexer_data;
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::BlockHeight;
+use near_sdk::AccountId;
+use serde_json::json;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+
+/// Query output format
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Aligned columns
+    #[default]
+    Table,
+    /// JSON object per line
+    Json,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => anyhow::bail!("Unknown output format: {s}"),
+        }
+    }
+}
+
+/// Indexed actions filter. Unset fields match everything.
+#[derive(Debug, Default, Clone)]
+pub struct LogFilter {
+    pub account: Option<AccountId>,
+    pub method: Option<String>,
+    pub from_block: Option<BlockHeight>,
+    pub to_block: Option<BlockHeight>,
+    pub status: Option<ExecutionStatus>,
+}
+
+impl LogFilter {
+    fn is_log_matched(&self, log: &IndexedResultLog) -> bool {
+        self.from_block
+            .map_or(true, |from| log.block_height >= from)
+            && self.to_block.map_or(true, |to| log.block_height <= to)
+            && self.status.map_or(true, |status| log.status == status)
+    }
+
+    fn is_action_matched(&self, action: &ActionResultLog) -> bool {
+        self.method
+            .as_ref()
+            .map_or(true, |method| &action.method == method)
+            && self
+                .account
+                .as_ref()
+                .map_or(true, |account| action.accounts.contains(account))
+    }
+}
+
+/// Action of the indexed log, one output row
+struct Row {
+    // Output position: block height, position of the log in the data file
+    // and position of the action in the log
+    key: (u64, u64, usize),
+    log: Rc<IndexedResultLog>,
+    action: usize,
+}
+
+impl Row {
+    const HEADER: [&'static str; 6] = [
+        "block_height",
+        "status",
+        "method",
+        "accounts",
+        "receipt_id",
+        "origin_receipt_id",
+    ];
+
+    fn action(&self) -> &ActionResultLog {
+        &self.log.actions[self.action]
+    }
+
+    fn fields(&self) -> [String; 6] {
+        let hash =
+            |hash: Option<CryptoHash>| hash.map(|hash| format!("{hash}")).unwrap_or_default();
+        [
+            self.log.block_height.to_string(),
+            self.log.status.to_string(),
+            self.action().method.clone(),
+            self.accounts().join(" "),
+            hash(self.log.receipt_id),
+            hash(self.log.origin_receipt_id),
+        ]
+    }
+
+    fn accounts(&self) -> Vec<&str> {
+        self.action()
+            .accounts
+            .iter()
+            .map(AccountId::as_str)
+            .collect()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "block_height": self.log.block_height,
+            "status": self.log.status.to_string(),
+            "method": self.action().method,
+            "accounts": self.accounts(),
+            "receipt_id": self.log.receipt_id.map(|hash| hash.to_string()),
+            "origin_receipt_id": self.log.origin_receipt_id.map(|hash| hash.to_string()),
+        })
+    }
+}
+
+impl PartialEq for Row {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Row {}
+
+impl PartialOrd for Row {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Row {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// Print indexed actions matched by the filter, in blocks order. With
+/// `latest` the newest actions are printed first. `limit` sets max rows count.
+pub fn run_query<P: AsRef<Path>>(
+    data_file: P,
+    filter: &LogFilter,
+    format: OutputFormat,
+    latest: bool,
+    limit: Option<usize>,
+) -> anyhow::Result<()> {
+    let rows = query_rows(data_file, filter, latest, limit)?;
+    write_rows(&mut std::io::stdout().lock(), &rows, format)?;
+    Ok(())
+}
+
+/// Stream logs from the data file and keep only matched actions. With
+/// `limit` only the first rows are kept in the bounded heap.
+fn query_rows<P: AsRef<Path>>(
+    data_file: P,
+    filter: &LogFilter,
+    latest: bool,
+    limit: Option<usize>,
+) -> anyhow::Result<Vec<Row>> {
+    let mut rows = BinaryHeap::new();
+    let mut position = 0;
+    load_indexer_data(data_file, |log| {
+        position += 1;
+        if !filter.is_log_matched(&log) {
+            return;
+        }
+        let actions: Vec<_> = (0..log.actions.len())
+            .filter(|&action| filter.is_action_matched(&log.actions[action]))
+            .collect();
+        if actions.is_empty() {
+            return;
+        }
+        // The newest logs go first, actions of the log keep their order
+        let (height, position) = if latest {
+            (u64::MAX - log.block_height, u64::MAX - position)
+        } else {
+            (log.block_height, position)
+        };
+        let log = Rc::new(log);
+        for action in actions {
+            rows.push(Row {
+                key: (height, position, action),
+                log: log.clone(),
+                action,
+            });
+            // Drop the last row in the output order
+            if limit.map_or(false, |limit| rows.len() > limit) {
+                rows.pop();
+            }
+        }
+    })?;
+    Ok(rows.into_sorted_vec())
+}
+
+fn write_rows<W: Write>(out: &mut W, rows: &[Row], format: OutputFormat) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Table => print_table(out, rows)?,
+        OutputFormat::Json => {
+            for row in rows {
+                writeln!(out, "{}", row.to_json())?;
+            }
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "{}", Row::HEADER.join(","))?;
+            for row in rows {
+                let fields = row.fields().map(|field| csv_field(&field));
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_table<W: Write>(out: &mut W, rows: &[Row]) -> std::io::Result<()> {
+    let rows: Vec<_> = rows.iter().map(Row::fields).collect();
+    let mut widths = Row::HEADER.map(str::len);
+    for fields in &rows {
+        for (width, field) in widths.iter_mut().zip(fields) {
+            *width = (*width).max(field.len());
+        }
+    }
+
+    let header = Row::HEADER.map(ToString::to_string);
+    for fields in std::iter::once(&header).chain(&rows) {
+        let line: Vec<_> = fields
+            .iter()
+            .zip(widths)
+            .map(|(field, width)| format!("{field:width$}"))
+            .collect();
+        writeln!(out, "{}", line.join("  ").trim_end())?;
+    }
+    writeln!(out, "\nRows: {}", rows.len())
+}
+
+/// Quote CSV field if it contains separator, quotes or line breaks
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::IndexerData;
+    use crate::store::write_checkpoint;
+    use crate::test_utils::TestDir;
+
+    fn action(method: &str, accounts: &[&str]) -> ActionResultLog {
+        ActionResultLog {
+            accounts: accounts.iter().map(|a| a.parse().unwrap()).collect(),
+            method: method.to_string(),
+        }
+    }
+
+    fn log(
+        block_height: BlockHeight,
+        status: ExecutionStatus,
+        actions: Vec<ActionResultLog>,
+    ) -> IndexedResultLog {
+        IndexedResultLog {
+            block_height,
+            actions,
+            status,
+            receipt_id: None,
+            origin_receipt_id: None,
+        }
+    }
+
+    /// Data file with logs out of blocks order
+    fn data_file(dir: &TestDir) -> std::path::PathBuf {
+        let mut data = IndexerData::default();
+        data.data.logs = vec![
+            log(
+                30,
+                ExecutionStatus::Success,
+                vec![
+                    action("ft_transfer", &["alice.near", "bob.near"]),
+                    action("withdraw", &["bob.near"]),
+                ],
+            ),
+            log(
+                10,
+                ExecutionStatus::Failure,
+                vec![action("ft_transfer", &["alice.near", "carol.near"])],
+            ),
+            log(
+                20,
+                ExecutionStatus::Success,
+                vec![action("deposit", &["alice.near"])],
+            ),
+        ];
+        let path = dir.path("data.borsh");
+        write_checkpoint(&path, &data).unwrap();
+        path
+    }
+
+    fn query(
+        dir: &TestDir,
+        filter: &LogFilter,
+        format: OutputFormat,
+        latest: bool,
+        limit: Option<usize>,
+    ) -> String {
+        let rows = query_rows(data_file(dir), filter, latest, limit).unwrap();
+        let mut out = vec![];
+        write_rows(&mut out, &rows, format).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_table_filter() {
+        let dir = TestDir::new("query-table");
+        let filter = LogFilter {
+            method: Some("ft_transfer".to_string()),
+            ..LogFilter::default()
+        };
+        let out = query(&dir, &filter, OutputFormat::Table, false, None);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("block_height  status   method"));
+        assert!(lines[1].starts_with("10            failure  ft_transfer  alice.near carol.near"));
+        assert!(lines[2].starts_with("30            success  ft_transfer  alice.near bob.near"));
+        assert_eq!(lines[4], "Rows: 2");
+    }
+
+    #[test]
+    fn test_csv_filter() {
+        let dir = TestDir::new("query-csv");
+        let filter = LogFilter {
+            account: Some("bob.near".parse().unwrap()),
+            status: Some(ExecutionStatus::Success),
+            ..LogFilter::default()
+        };
+        let out = query(&dir, &filter, OutputFormat::Csv, false, None);
+        assert_eq!(
+            out,
+            "block_height,status,method,accounts,receipt_id,origin_receipt_id\n\
+             30,success,ft_transfer,alice.near bob.near,,\n\
+             30,success,withdraw,bob.near,,\n"
+        );
+    }
+
+    #[test]
+    fn test_json_filter() {
+        let dir = TestDir::new("query-json");
+        let filter = LogFilter {
+            account: Some("alice.near".parse().unwrap()),
+            from_block: Some(15),
+            ..LogFilter::default()
+        };
+        let out = query(&dir, &filter, OutputFormat::Json, false, None);
+        let rows: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["block_height"], 20);
+        assert_eq!(rows[0]["method"], "deposit");
+        assert_eq!(rows[1]["block_height"], 30);
+        assert_eq!(rows[1]["accounts"], json!(["alice.near", "bob.near"]));
+        assert_eq!(rows[1]["receipt_id"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_limit() {
+        let dir = TestDir::new("query-limit");
+        let filter = LogFilter::default();
+        let out = query(&dir, &filter, OutputFormat::Csv, false, Some(2));
+        let lines: Vec<_> = out.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "10,failure,ft_transfer,alice.near carol.near,,",
+                "20,success,deposit,alice.near,,"
+            ]
+        );
+
+        // The newest log first, its actions keep their order
+        let out = query(&dir, &filter, OutputFormat::Csv, true, Some(3));
+        let lines: Vec<_> = out.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "30,success,ft_transfer,alice.near bob.near,,",
+                "30,success,withdraw,bob.near,,",
+                "20,success,deposit,alice.near,,"
+            ]
+        );
+    }
+}
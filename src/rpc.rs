@@ -5,16 +5,23 @@ use near_jsonrpc_client::{methods, JsonRpcClient, MethodCallResult};
 use near_jsonrpc_primitives::types::query::QueryResponseKind;
 use near_primitives::hash::CryptoHash;
 use near_primitives::transaction::{Action, FunctionCallAction, Transaction};
-use near_primitives::types::{BlockHeight, BlockReference};
-use near_primitives::views::{ActionView, ChunkHeaderView, FinalExecutionStatus};
+use near_primitives::types::{BlockHeight, BlockReference, Gas, ShardId};
+use near_primitives::views::{
+    ActionView, ChunkHeaderView, ChunkView, ExecutionOutcomeWithIdView, ExecutionStatusView,
+    FinalExecutionStatus, StateChangeValueView, StateChangesView,
+};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::U128;
 use near_sdk::AccountId;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::time::Instant;
 
 use self::error::CommitTx;
+use crate::catalogue::Catalogue;
+use crate::metrics::METRICS;
+use crate::parser::prefix_account_key;
 
 #[cfg(feature = "mainnet")]
 const NEAR_RPC_ADDRESS: &str = near_jsonrpc_client::NEAR_MAINNET_RPC_URL;
@@ -28,44 +35,209 @@ const NEAR_RPC_ADDRESS: &str = near_jsonrpc_client::NEAR_TESTNET_RPC_URL;
 #[cfg(feature = "localnet")]
 const NEAR_RPC_ADDRESS: &str = "http://127.0.0.1:3030";
 
+#[cfg(any(feature = "mainnet", feature = "mainnet-archival"))]
+const NEAR_ARCHIVAL_RPC_ADDRESS: &str = near_jsonrpc_client::NEAR_MAINNET_ARCHIVAL_RPC_URL;
+
+#[cfg(feature = "testnet")]
+const NEAR_ARCHIVAL_RPC_ADDRESS: &str = near_jsonrpc_client::NEAR_TESTNET_ARCHIVAL_RPC_URL;
+
+#[cfg(feature = "localnet")]
+const NEAR_ARCHIVAL_RPC_ADDRESS: &str = "http://127.0.0.1:3030";
+
 /// NEAR-RPC has limits: 600 req/sec, so we need timeout per requests
 pub const REQUEST_TIMEOUT: Duration = Duration::from_millis(90);
 
 /// Gas for commit tx to blockchain (300 `TGas`)
 const GAS_FOR_COMMIT_TX: u64 = 300_000_000_000_000;
 
-/// Transactions receiver
+/// Default transactions receiver
 pub const AURORA_CONTRACT: &str = "aurora";
 
 /// How many retries per success request
 const RETRIES_COUNT: u8 = 10;
 
-/// Transaction action methods allowed for output parsing and
-/// get `predecessor_account_id`
-const ACTION_METHODS: &[&str] = &[
-    "ft_transfer",
-    "deposit",
-    "ft_transfer_call",
-    "withdraw",
-    "finish_deposit",
-    "storage_deposit",
-    "storage_withdraw",
-    "storage_unregister",
-];
-
 pub struct Client {
     /// NEAR-rpc client
     pub client: JsonRpcClient,
+    /// Requests rate limiter shared with fetchers
+    pub limiter: RateLimiter,
     /// One possible reason: https://stackoverflow.com/a/72230096
-    pub unresolved_blocks: HashSet<BlockHeight>,
+    pub unresolved_blocks: MissedBlocks,
+    /// Heights of the unresolved blocks changed since the last taken delta
+    changed_blocks: HashSet<BlockHeight>,
+    /// Engine contract and tracked methods
+    pub catalogue: Arc<Catalogue>,
+}
+
+/// Rate limiter shared between concurrent requests. Keeps
+/// `REQUEST_TIMEOUT` interval between requests starts.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    next_request: Arc<tokio::sync::Mutex<Instant>>,
+}
+
+/// Cloneable NEAR-rpc handle for concurrent blocks and chunks fetching.
+/// All fetchers created from the same `Client` share the rate limit.
+#[derive(Clone)]
+pub struct Fetcher {
+    client: JsonRpcClient,
+    limiter: RateLimiter,
+    catalogue: Arc<Catalogue>,
+}
+
+/// Block data: height, chunks, block hash, previous block hash
+pub type BlockData = (BlockHeight, Vec<ChunkHeaderView>, CryptoHash, CryptoHash);
+
+/// Fetched chunk data with chunk header info
+pub struct FetchedChunk {
+    pub shard_id: ShardId,
+    pub chunk_hash: CryptoHash,
+    pub chunk: anyhow::Result<ChunkView>,
+    /// Execution statuses of the chunk transactions and receipts with indexed actions
+    pub outcomes: anyhow::Result<Outcomes>,
+}
+
+/// Execution status of the transaction or receipt with indexed actions
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum ExecutionStatus {
+    /// Execution outcome isn't known
+    #[default]
+    Unknown,
+    Success,
+    Failure,
+}
+
+impl std::fmt::Display for ExecutionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown"),
+            Self::Success => write!(f, "success"),
+            Self::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+impl FromStr for ExecutionStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unknown" => Ok(Self::Unknown),
+            "success" => Ok(Self::Success),
+            "failure" => Ok(Self::Failure),
+            _ => anyhow::bail!("Unknown execution status: {s}"),
+        }
+    }
 }
 
+/// Execution outcome of the transaction or receipt with indexed actions
+#[derive(Debug, Default, Clone)]
+pub struct Outcome {
+    pub status: ExecutionStatus,
+    /// Receipt that executed the actions. For transaction it's the receipt
+    /// the transaction was converted to.
+    pub receipt_id: Option<CryptoHash>,
+    /// Receipts created by the actions execution
+    pub receipt_ids: Vec<CryptoHash>,
+}
+
+/// Execution outcomes by transaction hash or receipt ID
+pub type Outcomes = HashMap<CryptoHash, Outcome>;
+
+/// Failure reason of the block that could not be processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum FailureKind {
+    /// Block not found: skipped height or block isn't available on the node
+    UnknownBlock,
+    /// Block request failed
+    Block,
+    /// Chunk request failed
+    Chunk {
+        shard_id: ShardId,
+        chunk_hash: CryptoHash,
+    },
+    /// State changes request failed
+    StateChanges,
+    /// Execution outcomes request failed for the chunk
+    Outcome {
+        shard_id: ShardId,
+        chunk_hash: CryptoHash,
+    },
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnknownBlock => write!(f, "unknown block"),
+            Self::Block => write!(f, "block"),
+            Self::Chunk {
+                shard_id,
+                chunk_hash,
+            } => write!(f, "chunk [shard: {shard_id}, hash: {chunk_hash}]"),
+            Self::StateChanges => write!(f, "state changes"),
+            Self::Outcome {
+                shard_id,
+                chunk_hash,
+            } => write!(f, "outcome [shard: {shard_id}, hash: {chunk_hash}]"),
+        }
+    }
+}
+
+impl FailureKind {
+    /// Failure kind name without details
+    #[must_use]
+    pub const fn label(&self) -> &'static str {
+        match self {
+            Self::UnknownBlock => "unknown_block",
+            Self::Block => "block",
+            Self::Chunk { .. } => "chunk",
+            Self::StateChanges => "state_changes",
+            Self::Outcome { .. } => "outcome",
+        }
+    }
+}
+
+/// Information about the block that could not be processed
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct MissedBlock {
+    pub kind: FailureKind,
+    /// The last error message
+    pub error: String,
+    pub attempts: u32,
+    /// Unix time of the last attempt in seconds
+    pub last_attempt: u64,
+    /// Hashes of the failed chunks. Only these chunks are fetched again.
+    /// If empty, the whole block is fetched again.
+    pub chunks: Vec<CryptoHash>,
+}
+
+/// Blocks that could not be processed
+pub type MissedBlocks = HashMap<BlockHeight, MissedBlock>;
+
+/// Changes of the missed blocks: new or changed missed blocks, and heights
+/// of the resolved ones
+#[derive(Debug, Default, Clone)]
+pub struct MissedBlocksDelta {
+    pub changed: Vec<(BlockHeight, MissedBlock)>,
+    pub resolved: Vec<BlockHeight>,
+}
+
+impl MissedBlocksDelta {
+    pub fn apply(&self, missed_blocks: &mut MissedBlocks) {
+        missed_blocks.extend(self.changed.iter().cloned());
+        for height in &self.resolved {
+            missed_blocks.remove(height);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum BlockKind {
     Latest,
     Height(BlockHeight),
 }
 
-#[derive(Debug, Default, Clone, BorshSerialize, BorshDeserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
 pub struct ActionResultLog {
     pub accounts: Vec<AccountId>,
     pub method: String,
@@ -82,12 +254,138 @@ pub struct ActionResult {
 pub struct IndexedResultLog {
     pub block_height: BlockHeight,
     pub actions: Vec<ActionResultLog>,
+    pub status: ExecutionStatus,
+    /// Receipt that executed the actions, if known
+    pub receipt_id: Option<CryptoHash>,
+    /// `ft_transfer_call` receipt for `ft_on_transfer` and
+    /// `ft_resolve_transfer` callbacks
+    pub origin_receipt_id: Option<CryptoHash>,
+}
+
+impl IndexedResultLog {
+    /// Check if the log was indexed without execution outcome, and `log` is
+    /// the same actions indexed again with the outcome
+    #[must_use]
+    pub fn is_replaced_by(&self, log: &Self) -> bool {
+        self.status == ExecutionStatus::Unknown
+            && self.block_height == log.block_height
+            && self.actions == log.actions
+            && self
+                .receipt_id
+                .map_or(true, |id| log.receipt_id == Some(id))
+    }
 }
 
 #[derive(Debug, Default, Clone, BorshSerialize, BorshDeserialize)]
 pub struct IndexedData {
     pub accounts: HashSet<AccountId>,
     pub logs: Vec<IndexedResultLog>,
+    /// Receipts created by `ft_transfer_call` receipts, that are not linked yet
+    pub transfer_calls: HashMap<CryptoHash, CryptoHash>,
+}
+
+impl IndexedData {
+    /// Merge indexed data of the next blocks. Callback logs are linked
+    /// to their `ft_transfer_call` receipts.
+    pub fn merge(&mut self, other: Self) {
+        self.accounts.extend(other.accounts);
+        self.transfer_calls.extend(other.transfer_calls);
+        for mut log in other.logs {
+            self.link_transfer_call(&mut log);
+            self.logs.push(log);
+        }
+    }
+
+    /// Merge indexed data of the retried blocks. Logs indexed before without
+    /// execution outcome are replaced by the same logs indexed again.
+    pub fn merge_retried(&mut self, other: Self) {
+        self.logs.retain(|log| {
+            !other
+                .logs
+                .iter()
+                .any(|retried_log| log.is_replaced_by(retried_log))
+        });
+        self.merge(other);
+    }
+
+    /// Link all not linked callback logs to their `ft_transfer_call` receipts.
+    /// Required if data was merged not in blocks order.
+    pub fn link_transfer_calls(&mut self) {
+        let mut logs = std::mem::take(&mut self.logs);
+        for log in &mut logs {
+            self.link_transfer_call(log);
+        }
+        self.logs = logs;
+    }
+
+    /// Add actions log. The `ft_resolve_transfer` receipt created by
+    /// `ft_transfer_call` is remembered to link it to its origin.
+    fn push_log(
+        &mut self,
+        block_height: BlockHeight,
+        actions: Vec<ActionResultLog>,
+        receipt_id: Option<CryptoHash>,
+        outcome: &Outcome,
+    ) {
+        if let Some(receipt_id) = receipt_id {
+            if actions
+                .iter()
+                .any(|action| action.method == "ft_transfer_call")
+            {
+                // `ft_resolve_transfer` is the engine callback chained after
+                // `ft_on_transfer` of the receiver, so it's created last.
+                // Receipt to the receiver is never indexed, so it isn't kept.
+                if let Some(resolve_receipt_id) = outcome.receipt_ids.last() {
+                    self.transfer_calls.insert(*resolve_receipt_id, receipt_id);
+                }
+            }
+        }
+        self.logs.push(IndexedResultLog {
+            block_height,
+            actions,
+            status: outcome.status,
+            receipt_id,
+            origin_receipt_id: None,
+        });
+    }
+
+    fn link_transfer_call(&mut self, log: &mut IndexedResultLog) {
+        if log.origin_receipt_id.is_some() {
+            return;
+        }
+        if let Some(receipt_id) = log.receipt_id {
+            log.origin_receipt_id = self.transfer_calls.remove(&receipt_id);
+        }
+    }
+}
+
+/// Accounts of failed and succeeded actions, collected from streamed logs
+#[derive(Debug, Default)]
+pub struct FailedAccounts {
+    failed: HashSet<AccountId>,
+    succeeded: HashSet<AccountId>,
+}
+
+impl FailedAccounts {
+    /// Add accounts of the log actions
+    pub fn add(&mut self, log: &IndexedResultLog) {
+        let accounts = if log.status == ExecutionStatus::Failure {
+            &mut self.failed
+        } else {
+            &mut self.succeeded
+        };
+        accounts.extend(
+            log.actions
+                .iter()
+                .flat_map(|action| action.accounts.iter().cloned()),
+        );
+    }
+
+    /// Accounts that are found only in failed actions
+    #[must_use]
+    pub fn failed_only(&self) -> HashSet<AccountId> {
+        self.failed.difference(&self.succeeded).cloned().collect()
+    }
 }
 
 impl Client {
@@ -97,80 +395,186 @@ impl Client {
         Self {
             // Init ner-rpc client
             client: JsonRpcClient::connect(NEAR_RPC_ADDRESS),
-            unresolved_blocks: HashSet::new(),
+            limiter: RateLimiter::new(),
+            unresolved_blocks: HashMap::new(),
+            changed_blocks: HashSet::new(),
+            catalogue: Arc::new(Catalogue::default()),
+        }
+    }
+
+    /// Init RPC with archival node of the network
+    #[must_use]
+    pub fn new_archival() -> Self {
+        Self {
+            client: JsonRpcClient::connect(NEAR_ARCHIVAL_RPC_ADDRESS),
+            ..Self::new()
         }
     }
 
+    /// Set engine contract and tracked methods
+    pub fn set_catalogue(&mut self, catalogue: Catalogue) {
+        self.catalogue = Arc::new(catalogue);
+    }
+
     /// Set missed blocks for RPC runner
-    pub fn set_missed_blocks(&mut self, missed_blocks: HashSet<BlockHeight>) {
+    pub fn set_missed_blocks(&mut self, missed_blocks: MissedBlocks) {
         self.unresolved_blocks = missed_blocks;
+        self.changed_blocks.clear();
     }
 
-    /// Wrap rpc-client calls.
-    /// All calls should have timeout, it's related to
-    /// restrictions of request count per minute: 600 per/min
-    pub async fn call<M>(&self, method: M) -> MethodCallResult<M::Response, M::Error>
-    where
-        M: methods::RpcMethod,
-    {
-        tokio::time::sleep(REQUEST_TIMEOUT).await;
-        self.client.call(method).await
+    /// Remove the block from unresolved blocks. Return `true` if it was unresolved.
+    pub fn resolve(&mut self, height: BlockHeight) -> bool {
+        let resolved = self.unresolved_blocks.remove(&height).is_some();
+        if resolved {
+            self.changed_blocks.insert(height);
+        }
+        resolved
     }
 
-    /// Get block data with Block kind request
-    pub async fn get_block(
+    /// Keep only unresolved blocks matched by the predicate
+    pub fn retain_unresolved<F: FnMut(BlockHeight) -> bool>(&mut self, mut f: F) {
+        let changed_blocks = &mut self.changed_blocks;
+        self.unresolved_blocks.retain(|height, _| {
+            let keep = f(*height);
+            if !keep {
+                changed_blocks.insert(*height);
+            }
+            keep
+        });
+    }
+
+    /// Take changes of the unresolved blocks made since the last taken delta
+    pub fn take_missed_blocks_delta(&mut self) -> MissedBlocksDelta {
+        let mut delta = MissedBlocksDelta::default();
+        for height in self.changed_blocks.drain() {
+            match self.unresolved_blocks.get(&height) {
+                Some(missed_block) => delta.changed.push((height, missed_block.clone())),
+                None => delta.resolved.push(height),
+            }
+        }
+        delta
+    }
+
+    /// Set block as unresolved or update failure info for already unresolved block
+    pub fn set_unresolved(
         &mut self,
-        bloch_kind: BlockKind,
-    ) -> anyhow::Result<(BlockHeight, Vec<ChunkHeaderView>, CryptoHash, CryptoHash)> {
-        let block_reference = if let BlockKind::Height(height) = bloch_kind {
-            BlockReference::BlockId(near_primitives::types::BlockId::Height(height))
-        } else {
-            BlockReference::Finality(near_primitives::types::Finality::Final)
-        };
-        let block = self
-            .call(methods::block::RpcBlockRequest { block_reference })
-            .await
-            .map_err(|e| {
-                let mut msg = "Failed get block".to_string();
-                if let BlockKind::Height(height) = bloch_kind {
-                    self.unresolved_blocks.insert(height);
-                    msg = format!("{msg}: {height:?}");
-                }
-                print_log(&msg);
-                e
-            })?;
+        height: BlockHeight,
+        kind: FailureKind,
+        error: &anyhow::Error,
+    ) {
+        // Unknown block is a skipped height, not a failed request
+        if kind != FailureKind::UnknownBlock {
+            METRICS.rpc_error(kind.label());
+        }
+        let last_attempt = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        self.changed_blocks.insert(height);
+        let missed_block = self
+            .unresolved_blocks
+            .entry(height)
+            .or_insert_with(|| MissedBlock {
+                kind,
+                error: String::new(),
+                attempts: 0,
+                last_attempt,
+                chunks: vec![],
+            });
+        missed_block.kind = kind;
+        missed_block.error = error.to_string();
+        missed_block.attempts += 1;
+        missed_block.last_attempt = last_attempt;
+    }
 
-        Ok((
-            block.header.height,
-            block.chunks,
-            block.header.hash,
-            block.header.prev_hash,
-        ))
+    /// Get failure kind of the block request error
+    #[must_use]
+    pub fn block_failure_kind(error: &anyhow::Error) -> FailureKind {
+        use near_jsonrpc_client::errors::JsonRpcError;
+        use near_jsonrpc_primitives::types::blocks::RpcBlockError;
+
+        match error
+            .downcast_ref::<JsonRpcError<RpcBlockError>>()
+            .and_then(JsonRpcError::handler_error)
+        {
+            Some(RpcBlockError::UnknownBlock { .. }) => FailureKind::UnknownBlock,
+            // Block directory is missing in the local blocks archive
+            _ if error
+                .downcast_ref::<std::io::Error>()
+                .map_or(false, |e| e.kind() == std::io::ErrorKind::NotFound) =>
+            {
+                FailureKind::UnknownBlock
+            }
+            _ => FailureKind::Block,
+        }
+    }
+
+    /// Get fetcher that shares rpc-client and rate limiter
+    #[must_use]
+    pub fn fetcher(&self) -> Fetcher {
+        Fetcher {
+            client: self.client.clone(),
+            limiter: self.limiter.clone(),
+            catalogue: self.catalogue.clone(),
+        }
+    }
+
+    /// Get block data with Block kind request
+    pub async fn get_block(&mut self, bloch_kind: BlockKind) -> anyhow::Result<BlockData> {
+        self.fetcher().get_block(bloch_kind).await.map_err(|e| {
+            if let BlockKind::Height(height) = bloch_kind {
+                self.set_unresolved(height, Self::block_failure_kind(&e), &e);
+            }
+            e
+        })
     }
 
     /// Get action output for chunk transaction (including receipt output)
-    /// It includes: Accounts
-    pub fn get_actions_data(&mut self, actions: Vec<ActionView>) -> ActionResult {
+    /// It includes: Accounts. Inner actions of NEP-366 delegate actions are
+    /// executed by a separate receipt from the delegate action sender, and
+    /// they are logged only from that receipt. For the transaction or
+    /// receipt carrying the delegate action only accounts are collected.
+    pub fn get_actions_data(&mut self, receiver_id: &str, actions: &[ActionView]) -> ActionResult {
         let mut result = ActionResult::default();
 
-        for action in actions {
-            // Check action method and filter it
+        if self.catalogue.is_contract(receiver_id) {
+            for action in actions {
+                // Check action method and filter it
+                if let ActionView::FunctionCall {
+                    method_name, args, ..
+                } = action
+                {
+                    if self.catalogue.method(method_name).is_some() {
+                        let accounts = self.parse_action_argument(method_name, args);
+
+                        result.is_action_found = true;
+                        result.log.push(ActionResultLog {
+                            accounts: accounts.clone(),
+                            method: method_name.clone(),
+                        });
+                        result.accounts.extend(accounts);
+                    }
+                }
+            }
+        }
+
+        let (delegated_actions, senders) = unwrap_delegate_actions(&self.catalogue, actions);
+        let mut is_delegated_action_found = false;
+        for action in delegated_actions {
             if let ActionView::FunctionCall {
                 method_name, args, ..
             } = action
             {
-                if ACTION_METHODS.contains(&method_name.as_str()) {
-                    let accounts = self.parse_action_argument(&method_name, &args);
-
-                    result.is_action_found = true;
-                    result.log.push(ActionResultLog {
-                        accounts: accounts.clone(),
-                        method: method_name,
-                    });
-                    result.accounts.extend(accounts);
+                if self.catalogue.method(&method_name).is_some() {
+                    is_delegated_action_found = true;
+                    result
+                        .accounts
+                        .extend(self.parse_action_argument(&method_name, &args));
                 }
             }
         }
+        if is_delegated_action_found {
+            result.accounts.extend(senders);
+        }
 
         result
     }
@@ -179,7 +583,10 @@ impl Client {
     /// Main goal is gather all accounts that can be modified.
     /// In `aurora-eth-connector` migration just receive balances
     /// for modified accounts. So main goals is just catch modified
-    /// accounts.
+    /// accounts. How accounts are pulled from arguments is defined
+    /// by the methods catalogue.
+    ///
+    /// In the default catalogue:
     ///
     /// `deposit` function catch just for log information.
     ///
@@ -190,99 +597,28 @@ impl Client {
     ///
     /// `storage_unregister` - use `predecessor_account_id` (that catch in
     /// other flow), just for log.
+    ///
+    /// `ft_on_transfer` and `ft_resolve_transfer` - `ft_transfer_call`
+    /// callbacks. When receiver doesn't use all tokens, `ft_resolve_transfer`
+    /// refunds them to `sender_id`, so both transfer sides are caught.
     #[must_use]
     pub fn parse_action_argument(&self, method: &str, args: &[u8]) -> Vec<AccountId> {
-        use serde::Deserialize;
-
-        match method {
-            "ft_transfer" => {
-                #[derive(Debug, Deserialize)]
-                pub struct FtTransferArgs {
-                    pub receiver_id: AccountId,
-                    pub amount: U128,
-                    pub memo: Option<String>,
-                }
-                if let Ok(res) = serde_json::from_slice::<FtTransferArgs>(args) {
-                    print_log("ft_transfer");
-                    vec![res.receiver_id]
-                } else {
-                    print_log(" Failed deserialize FtTransferArgs");
-                    vec![]
-                }
-            }
-            "ft_transfer_call" => {
-                #[derive(Debug, Deserialize)]
-                pub struct FtTransferCallArgs {
-                    pub receiver_id: AccountId,
-                    pub amount: U128,
-                    pub memo: Option<String>,
-                    pub msg: String,
-                }
-                if let Ok(res) = serde_json::from_slice::<FtTransferCallArgs>(args) {
-                    print_log("ft_transfer_call");
-                    vec![res.receiver_id]
-                } else {
-                    print_log("Failed deserialize FtTransferCallArgs");
-                    vec![]
-                }
-            }
-            "withdraw" => {
-                print_log(" Withdraw");
-                vec![]
-            }
-            "finish_deposit" => {
-                #[derive(Debug, Clone, BorshDeserialize)]
-                pub struct FinishDepositArgs {
-                    pub new_owner_id: AccountId,
-                    pub amount: u128,
-                    pub proof_key: String,
-                    pub relayer_id: AccountId,
-                    pub fee: u128,
-                    pub msg: Option<Vec<u8>>,
-                }
-                if let Ok(res) = FinishDepositArgs::try_from_slice(args) {
-                    print_log("finish_deposit");
-                    vec![res.new_owner_id, res.relayer_id]
-                } else {
-                    print_log("Failed deserialize FinishDepositArgs");
-                    vec![]
-                }
-            }
-            "deposit" => {
-                print_log("deposit");
-                vec![]
-            }
-            "storage_deposit" => {
-                #[derive(Debug, Clone, Deserialize)]
-                pub struct StorageDepositArgs {
-                    pub account_id: Option<AccountId>,
-                    pub registration_only: Option<bool>,
-                }
-                if let Ok(res) = serde_json::from_slice::<StorageDepositArgs>(args) {
-                    print_log("storage_deposit");
-                    if let Some(account_id) = res.account_id {
-                        vec![account_id]
-                    } else {
-                        vec![]
-                    }
-                } else {
-                    print_log("Failed deserialize FinishDepositArgs");
-                    vec![]
-                }
-            }
-            "storage_withdraw" => {
-                print_log("storage_withdraw");
-                vec![]
+        let Some(entry) = self.catalogue.method(method) else {
+            return vec![];
+        };
+        match entry.parse_accounts(args) {
+            Ok(accounts) => {
+                print_log(method);
+                accounts
             }
-            "storage_unregister" => {
-                print_log("storage_unregister");
+            Err(e) => {
+                print_log(&format!("Failed parse {method} arguments, {e}"));
                 vec![]
             }
-            _ => vec![],
         }
     }
 
-    /// Get transactions and receipts indexed data from chunks.
+    /// Get transactions and receipts indexed data from fetched chunks.
     /// Return indexed data including actions log.
     ///
     /// Special notice to catch `predecessor_account_id`:
@@ -290,129 +626,205 @@ impl Client {
     /// for `withdraw`, `ft_transfer`, `ft_transfer_call`
     /// and all `storage_deposit`,`storage_withdraw`,
     /// `storage_unregister`.
-    pub async fn get_chunk_indexed_data(
+    pub fn get_chunk_indexed_data(
+        &mut self,
+        chunks: Vec<FetchedChunk>,
+        block_height: BlockHeight,
+    ) -> IndexedData {
+        let mut results = IndexedData::default();
+        let mut failed_chunks = vec![];
+
+        for fetched_chunk in chunks {
+            let chunk_data = match fetched_chunk.chunk {
+                Ok(chunk_data) => chunk_data,
+                Err(e) => {
+                    // Set block as unresolved
+                    let kind = FailureKind::Chunk {
+                        shard_id: fetched_chunk.shard_id,
+                        chunk_hash: fetched_chunk.chunk_hash,
+                    };
+                    self.set_unresolved(block_height, kind, &e);
+                    failed_chunks.push(fetched_chunk.chunk_hash);
+                    continue;
+                }
+            };
+            // Chunk is indexed with unknown execution status, and retried
+            // later to get the status
+            let outcomes = match fetched_chunk.outcomes {
+                Ok(outcomes) => outcomes,
+                Err(e) => {
+                    let kind = FailureKind::Outcome {
+                        shard_id: fetched_chunk.shard_id,
+                        chunk_hash: fetched_chunk.chunk_hash,
+                    };
+                    self.set_unresolved(block_height, kind, &e);
+                    failed_chunks.push(fetched_chunk.chunk_hash);
+                    Outcomes::new()
+                }
+            };
+            self.index_chunk(&chunk_data, &outcomes, block_height, &mut results);
+        }
+
+        // Flow passed successfully - remove block. Otherwise only failed
+        // chunks are left to retry, indexed data of other chunks is kept.
+        if failed_chunks.is_empty() {
+            self.resolve(block_height);
+        } else if let Some(missed_block) = self.unresolved_blocks.get_mut(&block_height) {
+            self.changed_blocks.insert(block_height);
+            missed_block.chunks = failed_chunks;
+        }
+        results
+    }
+
+    /// Get indexed data from Aurora contract state changes in the block.
+    /// Every changed or deleted account balance key is recorded, whatever
+    /// caused the change.
+    pub fn get_state_changes_indexed_data(
         &mut self,
-        chunks: Vec<ChunkHeaderView>,
+        changes: anyhow::Result<StateChangesView>,
         block_height: BlockHeight,
     ) -> IndexedData {
-        let mut results = IndexedData {
-            accounts: HashSet::new(),
-            logs: vec![],
+        let mut results = IndexedData::default();
+        let changes = match changes {
+            Ok(changes) => changes,
+            Err(e) => {
+                self.set_unresolved(block_height, FailureKind::StateChanges, &e);
+                return results;
+            }
         };
 
-        // Fetch all chunks from block
-        for chunk in chunks {
-            // Get chunk data
-            let Ok(chunk_data) = self
-                .call(methods::chunk::RpcChunkRequest {
-                    chunk_reference:
-                        near_jsonrpc_primitives::types::chunks::ChunkReference::ChunkHash {
-                            chunk_id: chunk.chunk_hash,
-                        },
-                })
-                .await
+        let account_prefix = prefix_account_key();
+        let mut actions = vec![];
+        for change in changes {
+            let (key, method) = match &change.value {
+                StateChangeValueView::DataUpdate { key, .. } => (key, "balance_update"),
+                StateChangeValueView::DataDeletion { key, .. } => (key, "balance_deletion"),
+                _ => continue,
+            };
+            let Some(account) = key.strip_prefix(account_prefix.as_slice()) else {
+                continue;
+            };
+            let Some(account) = std::str::from_utf8(account)
+                .ok()
+                .and_then(|account| AccountId::from_str(account).ok())
             else {
-                print_log("Failed get chunk");
-                // Set block as unresolved
-                self.unresolved_blocks.insert(block_height);
+                print_log("Failed parse account from state key");
                 continue;
             };
+            results.accounts.insert(account.clone());
+            actions.push(ActionResultLog {
+                accounts: vec![account],
+                method: method.to_string(),
+            });
+        }
+        if !actions.is_empty() {
+            // State is changed only by successful execution
+            results.logs.push(IndexedResultLog {
+                block_height,
+                actions,
+                status: ExecutionStatus::Success,
+                receipt_id: None,
+                origin_receipt_id: None,
+            });
+        }
 
-            // Fetch chunk transactions
-            for tx in &chunk_data.transactions {
-                // We should process only specific receiver
-                if tx.receiver_id.as_str() != AURORA_CONTRACT {
-                    continue;
+        // Flow passed successfully - remove block
+        self.resolve(block_height);
+        results
+    }
+
+    /// Collect accounts and actions log from chunk transactions and receipts
+    fn index_chunk(
+        &mut self,
+        chunk_data: &ChunkView,
+        outcomes: &Outcomes,
+        block_height: BlockHeight,
+        results: &mut IndexedData,
+    ) {
+        // Fetch chunk transactions
+        for tx in &chunk_data.transactions {
+            // Get actions from transaction. Only actions addressed to
+            // Aurora contract are processed, including delegated ones.
+            let res = self.get_actions_data(tx.receiver_id.as_str(), &tx.actions);
+
+            // Added predecessor account. It's especially important
+            // for `withdraw`, `ft_transfer`, `ft_transfer_call`
+            // and all `storage_deposit`,`storage_withdraw`,
+            // `storage_unregister`
+            if res.is_action_found {
+                results
+                    .accounts
+                    .insert(AccountId::from_str(tx.signer_id.as_str()).unwrap());
+                results
+                    .accounts
+                    .insert(self.catalogue.contract.parse().unwrap());
+
+                let mut log = res.log;
+                if !log.is_empty() {
+                    log[0]
+                        .accounts
+                        .push(AccountId::from_str(tx.signer_id.as_str()).unwrap());
+                    log[0]
+                        .accounts
+                        .push(self.catalogue.contract.parse().unwrap());
                 }
-                // Get actions from transaction
-                let res = self.get_actions_data(tx.actions.clone());
+                let outcome = outcomes.get(&tx.hash).cloned().unwrap_or_default();
+                results.push_log(block_height, log, outcome.receipt_id, &outcome);
+            }
+            for account in res.accounts {
+                results.accounts.insert(account);
+            }
+        }
 
-                // Added predecessor account. It's especially important
-                // for `withdraw`, `ft_transfer`, `ft_transfer_call`
-                // and all `storage_deposit`,`storage_withdraw`,
-                // `storage_unregister`
+        // Fetch chunk transactions for receipts
+        for receipt in &chunk_data.receipts {
+            // Get actions accounts from receipt
+            if let near_primitives::views::ReceiptEnumView::Action {
+                signer_id, actions, ..
+            } = &receipt.receipt
+            {
+                let res = self.get_actions_data(receipt.receiver_id.as_str(), actions);
+                // Added predecessor_account_id.
+                // NOTE: same notice as before about importance
+                // for that field.
                 if res.is_action_found {
                     results
                         .accounts
-                        .insert(AccountId::from_str(tx.signer_id.as_str()).unwrap());
-                    results.accounts.insert(AURORA_CONTRACT.parse().unwrap());
+                        .insert(AccountId::from_str(signer_id.as_str()).unwrap());
+                    results
+                        .accounts
+                        .insert(AccountId::from_str(receipt.predecessor_id.as_str()).unwrap());
+                    results
+                        .accounts
+                        .insert(AccountId::from_str(receipt.receiver_id.as_str()).unwrap());
 
                     let mut log = res.log;
                     if !log.is_empty() {
                         log[0]
                             .accounts
-                            .push(AccountId::from_str(tx.signer_id.as_str()).unwrap());
-                        log[0].accounts.push(AURORA_CONTRACT.parse().unwrap());
+                            .push(AccountId::from_str(signer_id.as_str()).unwrap());
+                        log[0]
+                            .accounts
+                            .push(AccountId::from_str(receipt.predecessor_id.as_str()).unwrap());
+                        log[0]
+                            .accounts
+                            .push(AccountId::from_str(receipt.receiver_id.as_str()).unwrap());
                     }
-                    results.logs.push(IndexedResultLog {
-                        block_height,
-                        actions: log,
-                    });
+                    let outcome = outcomes
+                        .get(&receipt.receipt_id)
+                        .cloned()
+                        .unwrap_or_default();
+                    results.push_log(block_height, log, Some(receipt.receipt_id), &outcome);
                 }
                 for account in res.accounts {
                     results.accounts.insert(account);
                 }
             }
-
-            // Fetch chunk transactions for receipts
-            for receipt in &chunk_data.receipts {
-                // We should process only specific receiver
-                if receipt.receiver_id.as_str() != AURORA_CONTRACT {
-                    continue;
-                }
-
-                // Get actions accounts from receipt
-                if let near_primitives::views::ReceiptEnumView::Action {
-                    signer_id, actions, ..
-                } = receipt.receipt.clone()
-                {
-                    let res = self.get_actions_data(actions);
-                    // Added predecessor_account_id.
-                    // NOTE: same notice as before about importance
-                    // for that field.
-                    if res.is_action_found {
-                        results
-                            .accounts
-                            .insert(AccountId::from_str(signer_id.as_str()).unwrap());
-                        results
-                            .accounts
-                            .insert(AccountId::from_str(receipt.predecessor_id.as_str()).unwrap());
-                        results
-                            .accounts
-                            .insert(AccountId::from_str(receipt.receiver_id.as_str()).unwrap());
-
-                        let mut log = res.log;
-                        if !log.is_empty() {
-                            log[0]
-                                .accounts
-                                .push(AccountId::from_str(signer_id.as_str()).unwrap());
-                            log[0].accounts.push(
-                                AccountId::from_str(receipt.predecessor_id.as_str()).unwrap(),
-                            );
-                            log[0]
-                                .accounts
-                                .push(AccountId::from_str(receipt.receiver_id.as_str()).unwrap());
-                        }
-                        results.logs.push(IndexedResultLog {
-                            block_height,
-                            actions: log,
-                        });
-                    }
-                    for account in res.accounts {
-                        results.accounts.insert(account);
-                    }
-                }
-            }
         }
-        // Flow passed successfully - remove block
-        if self.unresolved_blocks.contains(&block_height) {
-            self.unresolved_blocks.remove(&block_height);
-        }
-        results
     }
 
     /// Commit transaction and wait respond. It should retry if it's fail
-    /// for some reason.
+    /// for some reason. Return gas burnt by the transaction and its receipts.
     /// Return error if request call failed, or status type not Success
     /// after retries.
     pub async fn commit_tx(
@@ -422,7 +834,7 @@ impl Client {
         contract: String,
         method: String,
         args: Vec<u8>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<Gas> {
         // Get signer key for Tx commit
         let signer = near_crypto::InMemorySigner::from_secret_key(
             signer_account_id.parse()?,
@@ -483,9 +895,15 @@ impl Client {
 
             // Check response and set errors if it needs
             if let Ok(tx_res) = res {
+                let gas_burnt = tx_res.transaction_outcome.outcome.gas_burnt
+                    + tx_res
+                        .receipts_outcome
+                        .iter()
+                        .map(|receipt| receipt.outcome.gas_burnt)
+                        .sum::<Gas>();
                 // If success - check response status
                 match tx_res.status {
-                    FinalExecutionStatus::SuccessValue(_) => return Ok(()),
+                    FinalExecutionStatus::SuccessValue(_) => return Ok(gas_burnt),
                     FinalExecutionStatus::Failure(err) => {
                         res = Err(CommitTx::Status(format!("{err:?}")));
                     }
@@ -494,6 +912,7 @@ impl Client {
             }
 
             // If request failed for some reason - retry request
+            METRICS.rpc_error("commit_tx");
             retry += 1;
             println!("\nRequest retry: {retry:?}");
             // If all retries failed it's incident, just panic
@@ -538,6 +957,382 @@ impl Default for Client {
     }
 }
 
+impl RateLimiter {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            next_request: Arc::new(tokio::sync::Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Wait for the next request slot
+    pub async fn wait(&self) {
+        let request_time = {
+            let mut next_request = self.next_request.lock().await;
+            let request_time = (*next_request).max(Instant::now());
+            *next_request = request_time + REQUEST_TIMEOUT;
+            request_time
+        };
+        tokio::time::sleep_until(request_time).await;
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fetcher {
+    /// Wrap rpc-client calls.
+    /// All calls should have timeout, it's related to
+    /// restrictions of request count per minute: 600 per/min
+    pub async fn call<M>(&self, method: M) -> MethodCallResult<M::Response, M::Error>
+    where
+        M: methods::RpcMethod,
+    {
+        self.limiter.wait().await;
+        self.client.call(method).await
+    }
+
+    /// Get block data with Block kind request
+    pub async fn get_block(&self, bloch_kind: BlockKind) -> anyhow::Result<BlockData> {
+        let block_reference = if let BlockKind::Height(height) = bloch_kind {
+            BlockReference::BlockId(near_primitives::types::BlockId::Height(height))
+        } else {
+            BlockReference::Finality(near_primitives::types::Finality::Final)
+        };
+        let block = self
+            .call(methods::block::RpcBlockRequest { block_reference })
+            .await
+            .map_err(|e| {
+                let mut msg = "Failed get block".to_string();
+                if let BlockKind::Height(height) = bloch_kind {
+                    msg = format!("{msg}: {height:?}");
+                }
+                print_log(&msg);
+                e
+            })?;
+
+        Ok((
+            block.header.height,
+            block.chunks,
+            block.header.hash,
+            block.header.prev_hash,
+        ))
+    }
+
+    /// Get chunk data by chunk hash
+    pub async fn get_chunk(&self, chunk_hash: CryptoHash) -> anyhow::Result<ChunkView> {
+        self.call(methods::chunk::RpcChunkRequest {
+            chunk_reference: near_jsonrpc_primitives::types::chunks::ChunkReference::ChunkHash {
+                chunk_id: chunk_hash,
+            },
+        })
+        .await
+        .map_err(|e| {
+            print_log("Failed get chunk");
+            e.into()
+        })
+    }
+
+    /// Get Aurora contract account balances changes in the block
+    pub async fn get_state_changes(
+        &self,
+        block_hash: CryptoHash,
+    ) -> anyhow::Result<StateChangesView> {
+        use near_primitives::views::StateChangesRequestView;
+
+        let response = self
+            .call(
+                methods::EXPERIMENTAL_changes::RpcStateChangesInBlockByTypeRequest {
+                    block_reference: BlockReference::BlockId(
+                        near_primitives::types::BlockId::Hash(block_hash),
+                    ),
+                    state_changes_request: StateChangesRequestView::DataChanges {
+                        account_ids: vec![self.catalogue.contract.parse()?],
+                        key_prefix: prefix_account_key().into(),
+                    },
+                },
+            )
+            .await
+            .map_err(|e| {
+                print_log("Failed get state changes");
+                e
+            })?;
+        Ok(response.changes)
+    }
+
+    /// Get execution outcome of the receipt proved against the light client head
+    async fn get_receipt_outcome(
+        &self,
+        receipt_id: CryptoHash,
+        receiver_id: near_primitives::types::AccountId,
+        light_client_head: CryptoHash,
+    ) -> anyhow::Result<ExecutionOutcomeWithIdView> {
+        let id = near_primitives::types::TransactionOrReceiptId::Receipt {
+            receipt_id,
+            receiver_id,
+        };
+        let response = self
+            .call(
+                methods::light_client_proof::RpcLightClientExecutionProofRequest {
+                    id,
+                    light_client_head,
+                },
+            )
+            .await
+            .map_err(|e| {
+                print_log("Failed get execution outcome");
+                e
+            })?;
+        Ok(response.outcome_proof)
+    }
+
+    /// Get all block chunks concurrently, then their execution outcomes.
+    /// Results are in the same order as chunks.
+    pub async fn get_chunks(&self, chunks: &[ChunkHeaderView]) -> Vec<FetchedChunk> {
+        let handles: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                let fetcher = self.clone();
+                let chunk_hash = chunk.chunk_hash;
+                let handle = tokio::spawn(async move { fetcher.get_chunk(chunk_hash).await });
+                (chunk.shard_id, chunk_hash, handle)
+            })
+            .collect();
+
+        let mut lookup = OutcomeLookup {
+            fetcher: self,
+            light_client_head: None,
+            receipts: HashMap::new(),
+        };
+        let mut results = Vec::with_capacity(handles.len());
+        for (shard_id, chunk_hash, handle) in handles {
+            let chunk = handle.await.unwrap_or_else(|e| Err(e.into()));
+            let outcomes = match &chunk {
+                Ok(chunk) => lookup.get_outcomes(chunk).await,
+                Err(_) => Ok(Outcomes::new()),
+            };
+            results.push(FetchedChunk {
+                shard_id,
+                chunk_hash,
+                chunk,
+                outcomes,
+            });
+        }
+        results
+    }
+}
+
+/// Execution outcomes lookup for the chunks of one block. Transaction
+/// outcome comes with the outcomes of its receipts in one request, and
+/// the light client head for receipts proofs is requested once.
+struct OutcomeLookup<'a> {
+    fetcher: &'a Fetcher,
+    light_client_head: Option<CryptoHash>,
+    // Receipts outcomes of the requested transactions
+    receipts: HashMap<CryptoHash, ExecutionOutcomeWithIdView>,
+}
+
+impl OutcomeLookup<'_> {
+    /// Get execution statuses of the chunk transactions and receipts
+    /// addressed to Aurora contract with indexed actions
+    async fn get_outcomes(&mut self, chunk: &ChunkView) -> anyhow::Result<Outcomes> {
+        use near_primitives::types::TransactionOrReceiptId;
+
+        let mut outcomes = Outcomes::new();
+        for id in indexed_outcome_ids(&self.fetcher.catalogue, chunk) {
+            let (hash, is_receipt, outcome) = match id {
+                TransactionOrReceiptId::Transaction {
+                    transaction_hash,
+                    sender_id,
+                } => {
+                    let outcome = self
+                        .get_transaction_outcome(transaction_hash, sender_id)
+                        .await?;
+                    (transaction_hash, false, outcome)
+                }
+                TransactionOrReceiptId::Receipt {
+                    receipt_id,
+                    receiver_id,
+                } => {
+                    let outcome = self.get_receipt_outcome(receipt_id, receiver_id).await?;
+                    (receipt_id, true, outcome)
+                }
+            };
+            outcomes.insert(hash, indexed_outcome(hash, is_receipt, outcome));
+        }
+        Ok(outcomes)
+    }
+
+    /// Get outcome of the receipt the transaction was converted to, that
+    /// executes the actions. Outcomes of all transaction receipts are kept.
+    async fn get_transaction_outcome(
+        &mut self,
+        hash: CryptoHash,
+        sender_id: near_primitives::types::AccountId,
+    ) -> anyhow::Result<ExecutionOutcomeWithIdView> {
+        use methods::EXPERIMENTAL_tx_status::{RpcTransactionStatusRequest, TransactionInfo};
+
+        let response = self
+            .fetcher
+            .call(RpcTransactionStatusRequest {
+                transaction_info: TransactionInfo::TransactionId {
+                    hash,
+                    account_id: sender_id,
+                },
+            })
+            .await
+            .map_err(|e| {
+                print_log("Failed get transaction status");
+                e
+            })?;
+        let outcome = response.final_outcome;
+        for receipt_outcome in outcome.receipts_outcome {
+            self.receipts.insert(receipt_outcome.id, receipt_outcome);
+        }
+        match outcome.transaction_outcome.outcome.status {
+            ExecutionStatusView::SuccessReceiptId(receipt_id) => self
+                .receipts
+                .get(&receipt_id)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("Failed get execution outcome: {receipt_id}")),
+            _ => Ok(outcome.transaction_outcome),
+        }
+    }
+
+    /// Get receipt outcome. Outcomes of the requested transactions are
+    /// reused, others are proved against the light client head.
+    async fn get_receipt_outcome(
+        &mut self,
+        receipt_id: CryptoHash,
+        receiver_id: near_primitives::types::AccountId,
+    ) -> anyhow::Result<ExecutionOutcomeWithIdView> {
+        if let Some(outcome) = self.receipts.get(&receipt_id) {
+            return Ok(outcome.clone());
+        }
+        let light_client_head = if let Some(head) = self.light_client_head {
+            head
+        } else {
+            // Outcomes are proved against the latest final block
+            let (_, _, head, _) = self.fetcher.get_block(BlockKind::Latest).await?;
+            self.light_client_head = Some(head);
+            head
+        };
+        self.fetcher
+            .get_receipt_outcome(receipt_id, receiver_id, light_client_head)
+            .await
+    }
+}
+
+/// IDs of the chunk transactions and receipts addressed to Aurora contract
+/// with indexed actions
+pub(crate) fn indexed_outcome_ids(
+    catalogue: &Catalogue,
+    chunk: &ChunkView,
+) -> Vec<near_primitives::types::TransactionOrReceiptId> {
+    use near_primitives::types::TransactionOrReceiptId;
+    use near_primitives::views::ReceiptEnumView;
+
+    let mut ids = vec![];
+    for tx in &chunk.transactions {
+        if has_action_methods(catalogue, tx.receiver_id.as_str(), &tx.actions) {
+            ids.push(TransactionOrReceiptId::Transaction {
+                transaction_hash: tx.hash,
+                sender_id: tx.signer_id.clone(),
+            });
+        }
+    }
+    for receipt in &chunk.receipts {
+        if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt {
+            if has_action_methods(catalogue, receipt.receiver_id.as_str(), actions) {
+                ids.push(TransactionOrReceiptId::Receipt {
+                    receipt_id: receipt.receipt_id,
+                    receiver_id: receipt.receiver_id.clone(),
+                });
+            }
+        }
+    }
+    ids
+}
+
+/// Indexed outcome of the transaction `hash` or receipt from the execution
+/// outcome of the receipt that executed its actions
+pub(crate) fn indexed_outcome(
+    hash: CryptoHash,
+    is_receipt: bool,
+    outcome: ExecutionOutcomeWithIdView,
+) -> Outcome {
+    let status = match outcome.outcome.status {
+        ExecutionStatusView::Unknown => ExecutionStatus::Unknown,
+        ExecutionStatusView::Failure(_) => ExecutionStatus::Failure,
+        ExecutionStatusView::SuccessValue(_) | ExecutionStatusView::SuccessReceiptId(_) => {
+            ExecutionStatus::Success
+        }
+    };
+    // Transaction outcome ID is the transaction hash if it wasn't converted to a receipt
+    let receipt_id = (is_receipt || outcome.id != hash).then_some(outcome.id);
+    Outcome {
+        status,
+        receipt_id,
+        receipt_ids: outcome.outcome.receipt_ids,
+    }
+}
+
+/// Check if actions contain calls of tracked methods addressed to engine
+/// contract. Delegated actions are executed and checked in a separate receipt.
+fn has_action_methods(catalogue: &Catalogue, receiver_id: &str, actions: &[ActionView]) -> bool {
+    catalogue.is_contract(receiver_id)
+        && actions.iter().any(|action| {
+            matches!(action, ActionView::FunctionCall { method_name, .. }
+                if catalogue.method(method_name).is_some())
+        })
+}
+
+/// Unwrap NEP-366 delegate actions recursively. Return inner actions
+/// addressed to engine contract and senders of delegate actions that
+/// carry them.
+fn unwrap_delegate_actions(
+    catalogue: &Catalogue,
+    actions: &[ActionView],
+) -> (Vec<ActionView>, Vec<AccountId>) {
+    let mut aurora_actions = vec![];
+    let mut senders = vec![];
+    for action in actions {
+        let ActionView::Delegate {
+            delegate_action, ..
+        } = action
+        else {
+            continue;
+        };
+        let inner_actions: Vec<ActionView> = delegate_action
+            .get_actions()
+            .into_iter()
+            .map(ActionView::from)
+            .collect();
+        let (mut contract_actions, inner_senders) =
+            unwrap_delegate_actions(catalogue, &inner_actions);
+        if catalogue.is_contract(delegate_action.receiver_id.as_str()) {
+            contract_actions.extend(
+                inner_actions
+                    .into_iter()
+                    .filter(|action| !matches!(action, ActionView::Delegate { .. })),
+            );
+        }
+        if contract_actions.is_empty() {
+            continue;
+        }
+        match AccountId::from_str(delegate_action.sender_id.as_str()) {
+            Ok(sender_id) => senders.push(sender_id),
+            Err(_) => print_log("Failed parse delegate action sender"),
+        }
+        aurora_actions.extend(contract_actions);
+        senders.extend(inner_senders);
+    }
+    (aurora_actions, senders)
+}
+
 #[allow(dead_code)]
 fn print_log(msg: &str) {
     #[cfg(feature = "log")]
@@ -571,3 +1366,365 @@ mod error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(account: &str) -> AccountId {
+        account.parse().unwrap()
+    }
+
+    fn action(method: &str, accounts: &[&str]) -> Vec<ActionResultLog> {
+        vec![ActionResultLog {
+            accounts: accounts.iter().copied().map(account).collect(),
+            method: method.to_string(),
+        }]
+    }
+
+    fn outcome(receipt_ids: &[&[u8]]) -> Outcome {
+        Outcome {
+            status: ExecutionStatus::Success,
+            receipt_id: None,
+            receipt_ids: receipt_ids
+                .iter()
+                .map(|id| CryptoHash::hash_bytes(id))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_transfer_call_linked() {
+        let transfer_call_id = CryptoHash::hash_bytes(b"transfer_call");
+        let resolve_id = CryptoHash::hash_bytes(b"resolve");
+
+        let mut data = IndexedData::default();
+        data.push_log(
+            100,
+            action("ft_transfer_call", &["alice.near", "dex.near"]),
+            Some(transfer_call_id),
+            &outcome(&[b"on_transfer", b"resolve"]),
+        );
+        assert_eq!(data.transfer_calls.len(), 1);
+        assert_eq!(data.transfer_calls[&resolve_id], transfer_call_id);
+
+        // Refund of not used tokens in the next block
+        let mut next = IndexedData::default();
+        next.push_log(
+            102,
+            action("ft_resolve_transfer", &["alice.near", "dex.near"]),
+            Some(resolve_id),
+            &outcome(&[]),
+        );
+        data.merge(next);
+
+        assert!(data.transfer_calls.is_empty());
+        assert_eq!(data.logs[1].origin_receipt_id, Some(transfer_call_id));
+        assert_eq!(data.logs[0].origin_receipt_id, None);
+    }
+
+    fn execution_outcome(id: CryptoHash, status: serde_json::Value) -> ExecutionOutcomeWithIdView {
+        serde_json::from_value(serde_json::json!({
+            "proof": [],
+            "block_hash": CryptoHash::default(),
+            "id": id,
+            "outcome": {
+                "logs": [],
+                "receipt_ids": [],
+                "gas_burnt": 0,
+                "tokens_burnt": "0",
+                "executor_id": "aurora",
+                "status": status,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_outcome_status() {
+        use serde_json::json;
+
+        let tx_hash = CryptoHash::hash_bytes(b"tx");
+        let receipt_id = CryptoHash::hash_bytes(b"receipt");
+        let statuses = [
+            (json!({ "SuccessValue": "" }), ExecutionStatus::Success),
+            (
+                json!({ "SuccessReceiptId": receipt_id }),
+                ExecutionStatus::Success,
+            ),
+            (json!("Unknown"), ExecutionStatus::Unknown),
+            (
+                json!({ "Failure": { "ActionError": { "index": 0, "kind": {
+                    "AccountDoesNotExist": { "account_id": "aurora" }
+                } } } }),
+                ExecutionStatus::Failure,
+            ),
+        ];
+        for (status, expected) in statuses {
+            let outcome = indexed_outcome(receipt_id, true, execution_outcome(receipt_id, status));
+            assert_eq!(outcome.status, expected);
+            assert_eq!(outcome.receipt_id, Some(receipt_id));
+        }
+
+        // Transaction is tagged by the receipt it was converted to
+        let outcome = execution_outcome(receipt_id, json!({ "SuccessValue": "" }));
+        assert_eq!(
+            indexed_outcome(tx_hash, false, outcome).receipt_id,
+            Some(receipt_id)
+        );
+        // Transaction failed before it was converted to a receipt
+        let outcome = execution_outcome(tx_hash, json!("Unknown"));
+        assert_eq!(indexed_outcome(tx_hash, false, outcome).receipt_id, None);
+    }
+
+    #[test]
+    fn test_failed_accounts() {
+        let log = |method, accounts, status| IndexedResultLog {
+            block_height: 100,
+            actions: action(method, accounts),
+            status,
+            receipt_id: None,
+            origin_receipt_id: None,
+        };
+        let mut failed_accounts = FailedAccounts::default();
+        for log in [
+            log("withdraw", &["carol.near"], ExecutionStatus::Failure),
+            log(
+                "ft_transfer",
+                &["alice.near", "bob.near"],
+                ExecutionStatus::Failure,
+            ),
+            log("ft_transfer", &["bob.near"], ExecutionStatus::Success),
+            log("deposit", &["dave.near"], ExecutionStatus::Unknown),
+            log("withdraw", &["dave.near"], ExecutionStatus::Failure),
+        ] {
+            failed_accounts.add(&log);
+        }
+        // Accounts of successful or unknown actions are kept by `--exclude-failed`
+        assert_eq!(
+            failed_accounts.failed_only(),
+            HashSet::from([account("alice.near"), account("carol.near")])
+        );
+    }
+
+    #[test]
+    fn test_missed_blocks_delta() {
+        let mut client = Client::new();
+        let error = anyhow::anyhow!("Block not found");
+        client.set_unresolved(10, FailureKind::Block, &error);
+        client.set_unresolved(11, FailureKind::Block, &error);
+        let mut missed_blocks = MissedBlocks::new();
+        let delta = client.take_missed_blocks_delta();
+        assert_eq!(delta.changed.len(), 2);
+        delta.apply(&mut missed_blocks);
+        assert!(client.take_missed_blocks_delta().changed.is_empty());
+
+        // Not missed block isn't recorded as resolved
+        assert!(client.resolve(10));
+        assert!(!client.resolve(12));
+        client.set_unresolved(11, FailureKind::Block, &error);
+        let delta = client.take_missed_blocks_delta();
+        assert_eq!(delta.resolved, vec![10]);
+        assert_eq!(delta.changed.len(), 1);
+        assert_eq!(delta.changed[0].1.attempts, 2);
+        delta.apply(&mut missed_blocks);
+        assert_eq!(missed_blocks, client.unresolved_blocks);
+
+        client.retain_unresolved(|height| height < 11);
+        let delta = client.take_missed_blocks_delta();
+        assert_eq!(delta.resolved, vec![11]);
+        delta.apply(&mut missed_blocks);
+        assert!(missed_blocks.is_empty());
+    }
+
+    /// Chunk with a meta-transaction from `relayer.near`: the transaction
+    /// and the receipt carrying the signed delegate action of `alice.near`,
+    /// and the receipt executing its `ft_transfer` on the contract.
+    fn delegate_chunk() -> ChunkView {
+        let chunk = std::fs::read(
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/chunk/delegate.json"),
+        )
+        .unwrap();
+        serde_json::from_slice(&chunk).unwrap()
+    }
+
+    #[test]
+    fn test_delegate_action_indexed_once() {
+        use near_primitives::types::TransactionOrReceiptId;
+
+        let chunk = delegate_chunk();
+        let executed_id = chunk.receipts[1].receipt_id;
+        let ids = indexed_outcome_ids(&Catalogue::default(), &chunk);
+        assert!(matches!(
+            ids.as_slice(),
+            [TransactionOrReceiptId::Receipt { receipt_id, .. }] if *receipt_id == executed_id
+        ));
+
+        let mut client = Client::new();
+        client.set_catalogue(Catalogue::default());
+        let fetched = FetchedChunk {
+            shard_id: 0,
+            chunk_hash: chunk.header.chunk_hash,
+            chunk: Ok(chunk),
+            outcomes: Ok(Outcomes::new()),
+        };
+        let data = client.get_chunk_indexed_data(vec![fetched], 100);
+
+        let accounts = ["alice.near", "aurora", "bob.near", "relayer.near"];
+        assert_eq!(data.accounts, accounts.into_iter().map(account).collect());
+        // Delegate action sender is the predecessor of the executing receipt
+        assert_eq!(data.logs.len(), 1);
+        assert_eq!(data.logs[0].receipt_id, Some(executed_id));
+        assert_eq!(
+            data.logs[0].actions,
+            action(
+                "ft_transfer",
+                &["bob.near", "relayer.near", "alice.near", "aurora"]
+            )
+        );
+    }
+
+    #[test]
+    fn test_unwrap_delegate_actions() {
+        let chunk = delegate_chunk();
+        let catalogue = Catalogue::default();
+        let actions = &chunk.transactions[0].actions;
+        let (inner_actions, senders) = unwrap_delegate_actions(&catalogue, actions);
+        assert!(matches!(
+            inner_actions.as_slice(),
+            [ActionView::FunctionCall { method_name, .. }] if method_name == "ft_transfer"
+        ));
+        assert_eq!(senders, [account("alice.near")]);
+        // Only delegated accounts are collected from the carrying transaction
+        let mut client = Client::new();
+        let result = client.get_actions_data("alice.near", actions);
+        assert!(!result.is_action_found);
+        assert!(result.log.is_empty());
+        assert_eq!(
+            result.accounts,
+            [account("bob.near"), account("alice.near")]
+        );
+    }
+
+    #[test]
+    fn test_failure_bookkeeping() {
+        let not_found: anyhow::Error =
+            std::io::Error::new(std::io::ErrorKind::NotFound, "No block").into();
+        assert_eq!(
+            Client::block_failure_kind(&not_found),
+            FailureKind::UnknownBlock
+        );
+        let error = anyhow::anyhow!("Request timeout");
+        assert_eq!(Client::block_failure_kind(&error), FailureKind::Block);
+
+        let mut client = Client::new();
+        client.set_unresolved(10, FailureKind::UnknownBlock, &not_found);
+        client.set_unresolved(10, FailureKind::Block, &error);
+        let missed_block = &client.unresolved_blocks[&10];
+        // The last failure is kept with the attempts count
+        assert_eq!(missed_block.kind, FailureKind::Block);
+        assert_eq!(missed_block.error, "Request timeout");
+        assert_eq!(missed_block.attempts, 2);
+        assert!(missed_block.last_attempt > 0);
+        assert!(missed_block.chunks.is_empty());
+
+        // Failed chunks of the block are recorded, the chunk with failed
+        // outcomes is indexed without execution status
+        let chunk = delegate_chunk();
+        let failed_hash = CryptoHash::hash_bytes(b"chunk1");
+        let outcome_hash = chunk.header.chunk_hash;
+        let chunks = vec![
+            FetchedChunk {
+                shard_id: 1,
+                chunk_hash: failed_hash,
+                chunk: Err(anyhow::anyhow!("Chunk not found")),
+                outcomes: Ok(Outcomes::new()),
+            },
+            FetchedChunk {
+                shard_id: 0,
+                chunk_hash: outcome_hash,
+                chunk: Ok(chunk),
+                outcomes: Err(anyhow::anyhow!("Outcome not found")),
+            },
+        ];
+        client.set_catalogue(Catalogue::default());
+        let data = client.get_chunk_indexed_data(chunks, 11);
+        assert_eq!(data.logs.len(), 1);
+        assert_eq!(data.logs[0].status, ExecutionStatus::Unknown);
+        let missed_block = &client.unresolved_blocks[&11];
+        assert_eq!(
+            missed_block.kind,
+            FailureKind::Outcome {
+                shard_id: 0,
+                chunk_hash: outcome_hash,
+            }
+        );
+        assert_eq!(missed_block.chunks, [failed_hash, outcome_hash]);
+
+        // Block with all chunks indexed is resolved
+        client.get_chunk_indexed_data(vec![], 10);
+        assert!(!client.unresolved_blocks.contains_key(&10));
+    }
+
+    #[test]
+    fn test_state_changes_key_prefix() {
+        use crate::parser::construct_contract_key;
+        use aurora_engine_types::storage::EthConnectorStorageId;
+        use serde_json::json;
+
+        let key =
+            |prefix: &[u8], account: &str| base64::encode([prefix, account.as_bytes()].concat());
+        let account_prefix = prefix_account_key();
+        let used_event_prefix = construct_contract_key(EthConnectorStorageId::UsedEvent);
+        let change = |kind: &str, key: String| {
+            json!({
+                "cause": { "type": "receipt_processing", "receipt_hash": CryptoHash::default() },
+                "type": kind,
+                "change": { "account_id": "aurora", "key_base64": key, "value_base64": "" },
+            })
+        };
+        let changes = json!([
+            change("data_update", key(&account_prefix, "alice.near")),
+            change("data_deletion", key(&account_prefix, "bob.near")),
+            // Contract data, other storage prefix and invalid account ID
+            change("data_update", key(&account_prefix, "")),
+            change("data_update", key(&used_event_prefix, "carol.near")),
+            change("data_update", key(&account_prefix, "Invalid Account")),
+            // Prefix of the key isn't at the start
+            change(
+                "data_update",
+                key(
+                    b"x",
+                    &format!("{}dave.near", String::from_utf8_lossy(&account_prefix))
+                )
+            ),
+        ]);
+        let changes: StateChangesView = serde_json::from_value(changes).unwrap();
+
+        let mut client = Client::new();
+        client.set_unresolved(100, FailureKind::StateChanges, &anyhow::anyhow!("Timeout"));
+        let data = client.get_state_changes_indexed_data(Ok(changes), 100);
+        assert_eq!(
+            data.accounts,
+            HashSet::from([account("alice.near"), account("bob.near")])
+        );
+        assert_eq!(data.logs.len(), 1);
+        assert_eq!(data.logs[0].status, ExecutionStatus::Success);
+        let mut actions = action("balance_update", &["alice.near"]);
+        actions.extend(action("balance_deletion", &["bob.near"]));
+        assert_eq!(data.logs[0].actions, actions);
+        assert!(client.unresolved_blocks.is_empty());
+
+        // Failed request is recorded, and block without changes has no logs
+        let data = client.get_state_changes_indexed_data(Err(anyhow::anyhow!("Timeout")), 101);
+        assert!(data.logs.is_empty());
+        assert_eq!(
+            client.unresolved_blocks[&101].kind,
+            FailureKind::StateChanges
+        );
+        let data = client.get_state_changes_indexed_data(Ok(vec![]), 101);
+        assert!(data.logs.is_empty());
+        assert!(client.unresolved_blocks.is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+//! # Source
+//! Blocks sources of the indexer: NEAR RPC or a local directory with
+//! blocks and chunks in the NEAR Lake layout.
+//!
+use crate::catalogue::Catalogue;
+use crate::rpc::{
+    indexed_outcome, indexed_outcome_ids, BlockData, BlockKind, FetchedChunk, Fetcher, Outcomes,
+};
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::{AccountId, BlockHeight, ShardId, TransactionOrReceiptId};
+use near_primitives::views::{
+    BlockView, ChunkHeaderView, ChunkView, ExecutionOutcomeWithIdView, ExecutionStatusView,
+    ReceiptView, SignedTransactionView, StateChangeValueView, StateChangesView,
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Number of blocks, starting from the chunk block, searched for
+/// execution outcomes of the chunk transactions and receipts
+pub const OUTCOME_BLOCKS: u64 = 8;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Source of blocks, chunks and state changes for the indexer
+pub trait BlockSource: Send + Sync {
+    /// Get block data with Block kind request
+    fn get_block(&self, block_kind: BlockKind) -> BoxFuture<'_, anyhow::Result<BlockData>>;
+
+    /// Get block chunks with execution outcomes. Results are in the same order as chunks.
+    fn get_chunks<'a>(&'a self, chunks: &'a [ChunkHeaderView]) -> BoxFuture<'a, Vec<FetchedChunk>>;
+
+    /// Get Aurora contract account balances changes in the block
+    fn get_state_changes(
+        &self,
+        height: BlockHeight,
+        block_hash: CryptoHash,
+    ) -> BoxFuture<'_, anyhow::Result<StateChangesView>>;
+}
+
+impl BlockSource for Fetcher {
+    fn get_block(&self, block_kind: BlockKind) -> BoxFuture<'_, anyhow::Result<BlockData>> {
+        Box::pin(Self::get_block(self, block_kind))
+    }
+
+    fn get_chunks<'a>(&'a self, chunks: &'a [ChunkHeaderView]) -> BoxFuture<'a, Vec<FetchedChunk>> {
+        Box::pin(Self::get_chunks(self, chunks))
+    }
+
+    fn get_state_changes(
+        &self,
+        _height: BlockHeight,
+        block_hash: CryptoHash,
+    ) -> BoxFuture<'_, anyhow::Result<StateChangesView>> {
+        Box::pin(Self::get_state_changes(self, block_hash))
+    }
+}
+
+/// Local directory with blocks in the NEAR Lake layout:
+/// `<height>/block.json` with the block and `<height>/shard_N.json`
+/// with the chunk, execution outcomes and state changes of the shard.
+#[derive(Debug, Clone)]
+pub struct LakeSource {
+    dir: PathBuf,
+    catalogue: Arc<Catalogue>,
+}
+
+/// Shard of the block. Fields that aren't indexed are skipped.
+#[derive(Deserialize)]
+struct LakeShard {
+    chunk: Option<LakeChunk>,
+    #[serde(default)]
+    receipt_execution_outcomes: Vec<LakeOutcome>,
+    #[serde(default)]
+    state_changes: StateChangesView,
+}
+
+#[derive(Deserialize)]
+struct LakeChunk {
+    author: AccountId,
+    header: ChunkHeaderView,
+    transactions: Vec<LakeTransaction>,
+    receipts: Vec<ReceiptView>,
+}
+
+#[derive(Deserialize)]
+struct LakeTransaction {
+    transaction: SignedTransactionView,
+    outcome: LakeOutcome,
+}
+
+#[derive(Deserialize)]
+struct LakeOutcome {
+    execution_outcome: ExecutionOutcomeWithIdView,
+}
+
+/// Execution outcomes of the blocks after the chunk block, loaded on demand
+struct OutcomeSearch<'a> {
+    source: &'a LakeSource,
+    outcomes: HashMap<CryptoHash, ExecutionOutcomeWithIdView>,
+    next_height: BlockHeight,
+    last_height: BlockHeight,
+}
+
+impl OutcomeSearch<'_> {
+    /// Find execution outcome by transaction hash or receipt ID
+    async fn find(&mut self, id: CryptoHash) -> anyhow::Result<ExecutionOutcomeWithIdView> {
+        loop {
+            if let Some(outcome) = self.outcomes.get(&id) {
+                return Ok(outcome.clone());
+            }
+            if self.next_height > self.last_height {
+                anyhow::bail!("Failed get execution outcome: {id}");
+            }
+            let height = self.next_height;
+            self.next_height += 1;
+            self.source
+                .load_outcomes(height, &mut self.outcomes)
+                .await?;
+        }
+    }
+}
+
+impl LakeSource {
+    #[must_use]
+    pub fn new<P: AsRef<Path>>(dir: P, catalogue: Arc<Catalogue>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            catalogue,
+        }
+    }
+
+    /// NEAR Lake pads heights with zeros to 12 digits, heights
+    /// without padding are accepted too
+    fn block_dir(&self, height: BlockHeight) -> PathBuf {
+        let padded = self.dir.join(format!("{height:012}"));
+        if padded.is_dir() {
+            padded
+        } else {
+            self.dir.join(height.to_string())
+        }
+    }
+
+    /// Read JSON file. Missing file error keeps `std::io::Error`,
+    /// so it's recognized as unknown block.
+    async fn read_json<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+        let data = tokio::fs::read(path).await.map_err(|e| {
+            let msg = format!("Failed read {}, {e}", path.display());
+            anyhow::Error::new(e).context(msg)
+        })?;
+        serde_json::from_slice(&data)
+            .map_err(|e| anyhow::anyhow!("Failed parse {}, {e}", path.display()))
+    }
+
+    async fn read_block(&self, height: BlockHeight) -> anyhow::Result<BlockView> {
+        Self::read_json(&self.block_dir(height).join("block.json")).await
+    }
+
+    async fn read_shard(
+        &self,
+        height: BlockHeight,
+        shard_id: ShardId,
+    ) -> anyhow::Result<LakeShard> {
+        Self::read_json(
+            &self
+                .block_dir(height)
+                .join(format!("shard_{shard_id}.json")),
+        )
+        .await
+    }
+
+    /// Height of the latest block in the directory
+    fn latest_height(&self) -> anyhow::Result<BlockHeight> {
+        let entries = std::fs::read_dir(&self.dir)
+            .map_err(|e| anyhow::anyhow!("Failed read {}, {e}", self.dir.display()))?;
+        entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().join("block.json").is_file())
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .max()
+            .ok_or_else(|| anyhow::anyhow!("No blocks in {}", self.dir.display()))
+    }
+
+    async fn get_chunk(&self, header: &ChunkHeaderView) -> anyhow::Result<ChunkView> {
+        let shard = self
+            .read_shard(header.height_included, header.shard_id)
+            .await?;
+        let chunk = shard
+            .chunk
+            .filter(|chunk| chunk.header.chunk_hash == header.chunk_hash)
+            .ok_or_else(|| anyhow::anyhow!("Failed get chunk: {}", header.chunk_hash))?;
+        Ok(ChunkView {
+            author: chunk.author,
+            header: chunk.header,
+            transactions: chunk
+                .transactions
+                .into_iter()
+                .map(|tx| tx.transaction)
+                .collect(),
+            receipts: chunk.receipts,
+        })
+    }
+
+    /// Load execution outcomes of the block transactions and receipts.
+    /// Heights without blocks are skipped.
+    async fn load_outcomes(
+        &self,
+        height: BlockHeight,
+        outcomes: &mut HashMap<CryptoHash, ExecutionOutcomeWithIdView>,
+    ) -> anyhow::Result<()> {
+        if !self.block_dir(height).join("block.json").is_file() {
+            return Ok(());
+        }
+        let block = self.read_block(height).await?;
+        for header in &block.chunks {
+            let shard = self.read_shard(height, header.shard_id).await?;
+            let transactions = shard.chunk.into_iter().flat_map(|chunk| chunk.transactions);
+            for outcome in transactions
+                .map(|tx| tx.outcome)
+                .chain(shard.receipt_execution_outcomes)
+            {
+                outcomes.insert(outcome.execution_outcome.id, outcome.execution_outcome);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get execution statuses of the chunk transactions and receipts
+    /// addressed to Aurora contract with indexed actions. Outcomes are
+    /// searched in `OUTCOME_BLOCKS` blocks starting from the chunk block.
+    async fn get_outcomes(&self, chunk: &ChunkView) -> anyhow::Result<Outcomes> {
+        let ids = indexed_outcome_ids(&self.catalogue, chunk);
+        let mut outcomes = Outcomes::new();
+        if ids.is_empty() {
+            return Ok(outcomes);
+        }
+        let height = chunk.header.height_included;
+        let mut search = OutcomeSearch {
+            source: self,
+            outcomes: HashMap::new(),
+            next_height: height,
+            last_height: height + OUTCOME_BLOCKS - 1,
+        };
+        for id in ids {
+            let is_receipt = matches!(id, TransactionOrReceiptId::Receipt { .. });
+            let (hash, outcome) = match id {
+                TransactionOrReceiptId::Transaction {
+                    transaction_hash, ..
+                } => {
+                    // Transaction is converted to a receipt, that executes the actions
+                    let outcome = search.find(transaction_hash).await?;
+                    let outcome = match outcome.outcome.status {
+                        ExecutionStatusView::SuccessReceiptId(receipt_id) => {
+                            search.find(receipt_id).await?
+                        }
+                        _ => outcome,
+                    };
+                    (transaction_hash, outcome)
+                }
+                TransactionOrReceiptId::Receipt { receipt_id, .. } => {
+                    (receipt_id, search.find(receipt_id).await?)
+                }
+            };
+            outcomes.insert(hash, indexed_outcome(hash, is_receipt, outcome));
+        }
+        Ok(outcomes)
+    }
+}
+
+impl BlockSource for LakeSource {
+    fn get_block(&self, block_kind: BlockKind) -> BoxFuture<'_, anyhow::Result<BlockData>> {
+        Box::pin(async move {
+            let height = match block_kind {
+                BlockKind::Height(height) => height,
+                BlockKind::Latest => self.latest_height()?,
+            };
+            let block = self.read_block(height).await?;
+            Ok((
+                block.header.height,
+                block.chunks,
+                block.header.hash,
+                block.header.prev_hash,
+            ))
+        })
+    }
+
+    fn get_chunks<'a>(&'a self, chunks: &'a [ChunkHeaderView]) -> BoxFuture<'a, Vec<FetchedChunk>> {
+        Box::pin(async move {
+            let mut results = Vec::with_capacity(chunks.len());
+            for header in chunks {
+                let chunk = self.get_chunk(header).await;
+                let outcomes = match &chunk {
+                    Ok(chunk) => self.get_outcomes(chunk).await,
+                    Err(_) => Ok(Outcomes::new()),
+                };
+                results.push(FetchedChunk {
+                    shard_id: header.shard_id,
+                    chunk_hash: header.chunk_hash,
+                    chunk,
+                    outcomes,
+                });
+            }
+            results
+        })
+    }
+
+    fn get_state_changes(
+        &self,
+        height: BlockHeight,
+        _block_hash: CryptoHash,
+    ) -> BoxFuture<'_, anyhow::Result<StateChangesView>> {
+        Box::pin(async move {
+            let block = self.read_block(height).await?;
+            let mut changes = vec![];
+            for header in &block.chunks {
+                let shard = self.read_shard(height, header.shard_id).await?;
+                // Only Aurora contract data changes are requested from RPC
+                changes.extend(shard.state_changes.into_iter().filter(|change| {
+                    matches!(&change.value,
+                        StateChangeValueView::DataUpdate { account_id, .. }
+                        | StateChangeValueView::DataDeletion { account_id, .. }
+                        if account_id.as_str() == self.catalogue.contract)
+                }));
+            }
+            Ok(changes)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{Client, ExecutionStatus};
+
+    /// Blocks 100 and 101 in the NEAR Lake layout. Block 100 has an
+    /// `ft_transfer` transaction, executed by a receipt in block 101,
+    /// and a failed `withdraw` receipt.
+    fn fixture() -> LakeSource {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lake");
+        LakeSource::new(dir, Arc::new(Catalogue::default()))
+    }
+
+    fn account(account: &str) -> near_sdk::AccountId {
+        account.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_latest_block() {
+        let source = fixture();
+        let (height, chunks, hash, prev_hash) = source.get_block(BlockKind::Latest).await.unwrap();
+        assert_eq!(height, 101);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(hash, CryptoHash::hash_bytes(b"block101"));
+        assert_eq!(prev_hash, CryptoHash::hash_bytes(b"block100"));
+        assert!(source.get_block(BlockKind::Height(102)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunk_indexed_data() {
+        let source = fixture();
+        let (height, chunks, ..) = source.get_block(BlockKind::Height(100)).await.unwrap();
+        let fetched = source.get_chunks(&chunks).await;
+        assert_eq!(fetched.len(), 1);
+        assert!(fetched[0].chunk.is_ok());
+        assert_eq!(fetched[0].outcomes.as_ref().unwrap().len(), 2);
+
+        let mut client = Client::new();
+        client.set_catalogue(Catalogue::default());
+        let data = client.get_chunk_indexed_data(fetched, height);
+
+        let accounts = ["alice.near", "aurora", "bob.near", "carol.near"];
+        assert_eq!(data.accounts, accounts.into_iter().map(account).collect());
+        assert_eq!(data.logs.len(), 2);
+
+        let transfer = &data.logs[0];
+        assert_eq!(transfer.block_height, 100);
+        assert_eq!(transfer.actions.len(), 1);
+        assert_eq!(
+            transfer.actions[0].accounts,
+            vec![
+                account("bob.near"),
+                account("alice.near"),
+                account("aurora")
+            ]
+        );
+        assert_eq!(transfer.actions[0].method, "ft_transfer");
+        assert_eq!(transfer.status, ExecutionStatus::Success);
+        assert_eq!(
+            transfer.receipt_id,
+            Some(CryptoHash::hash_bytes(b"receipt1"))
+        );
+
+        let withdraw = &data.logs[1];
+        assert_eq!(withdraw.actions[0].method, "withdraw");
+        assert_eq!(
+            withdraw.actions[0].accounts,
+            vec![
+                account("carol.near"),
+                account("carol.near"),
+                account("aurora")
+            ]
+        );
+        assert_eq!(withdraw.status, ExecutionStatus::Failure);
+        assert_eq!(
+            withdraw.receipt_id,
+            Some(CryptoHash::hash_bytes(b"receipt2"))
+        );
+        assert!(data.transfer_calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_missing_chunk() {
+        // Shard of block 101 has only receipt execution outcomes
+        let source = fixture();
+        let (_, chunks, ..) = source.get_block(BlockKind::Height(101)).await.unwrap();
+        let fetched = source.get_chunks(&chunks).await;
+        assert!(fetched[0].chunk.is_err());
+        assert!(fetched[0].outcomes.as_ref().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1298 @@
+//! # Store
+//! Journal store for the indexer state. Every handled block is appended to
+//! the state journal as one small checksummed record with the cursor and
+//! changes of the indexer state, and its actions logs are appended to the
+//! separate logs file. So the update is incremental, and the block is either
+//! stored completely or not stored at all. The state journal is compacted
+//! into a snapshot record, and logs are only read by streaming.
+//!
+use crate::indexer::{
+    IndexerData, RecentBlock, Reorg, ShardProgress, INDEXER_DATA_MAGIC, RECENT_BLOCKS,
+};
+use crate::rpc::{ExecutionStatus, IndexedData, IndexedResultLog, MissedBlock, MissedBlocksDelta};
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::{BlockHeight, ShardId};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::AccountId;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// State journal file header
+const MAGIC: &[u8; 8] = b"AURIDXJ2";
+/// Common prefix of the state journal headers of all versions
+const JOURNAL_PREFIX: &[u8] = b"AURIDXJ";
+/// Journal logs file header
+const LOGS_MAGIC: &[u8; 8] = b"AURIDXL1";
+/// Number of state journal records after which it's compacted into a snapshot
+const COMPACT_RECORDS: usize = 10_000;
+/// Max number of logs in one record of the logs file converted from borsh
+const LOGS_PER_RECORD: usize = 10_000;
+/// Number of kept previous borsh checkpoints: `<file>.1` is the newest
+const CHECKPOINT_BACKUPS: usize = 3;
+/// Record header: payload length and payload hash
+const RECORD_HEADER_LEN: usize = 4 + 32;
+
+/// Indexer data file format
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    /// Whole data serialized with borsh and rewritten on every save
+    #[default]
+    Borsh,
+    /// Append-only state journal with record per block and logs file
+    Journal,
+}
+
+impl FromStr for StoreKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "borsh" => Ok(Self::Borsh),
+            "journal" => Ok(Self::Journal),
+            _ => anyhow::bail!("Unknown store: {s}"),
+        }
+    }
+}
+
+/// Indexer position
+#[derive(Debug, Default, Clone, BorshSerialize, BorshDeserialize)]
+struct Cursor {
+    first_block: BlockHeight,
+    last_block: BlockHeight,
+    last_handled_block: BlockHeight,
+    current_block: BlockHeight,
+    last_block_hash: Option<CryptoHash>,
+    skipped_blocks: u64,
+}
+
+impl Cursor {
+    const fn new(data: &IndexerData) -> Self {
+        Self {
+            first_block: data.first_block,
+            last_block: data.last_block,
+            last_handled_block: data.last_handled_block,
+            current_block: data.current_block,
+            last_block_hash: data.last_block_hash,
+            skipped_blocks: data.skipped_blocks,
+        }
+    }
+
+    fn apply(self, data: &mut IndexerData) {
+        data.first_block = self.first_block;
+        data.last_block = self.last_block;
+        data.last_handled_block = self.last_handled_block;
+        data.current_block = self.current_block;
+        data.last_block_hash = self.last_block_hash;
+        data.skipped_blocks = self.skipped_blocks;
+    }
+}
+
+/// State journal record
+#[derive(BorshSerialize, BorshDeserialize)]
+enum Record {
+    /// Whole indexer state without logs, the first record after compaction
+    Snapshot {
+        data: Box<IndexerData>,
+        logs_len: u64,
+    },
+    /// Changes of the indexer state
+    Update(Box<Update>),
+}
+
+/// Changes of the indexer state made by handled block, retried block,
+/// rollback or save. Changes are written against the previous record.
+#[derive(Debug, Default, Clone, BorshSerialize, BorshDeserialize)]
+struct Update {
+    cursor: Cursor,
+    // Length of the logs file including logs of the record
+    logs_len: u64,
+    // New handled blocks
+    recent_blocks: Vec<RecentBlock>,
+    // Changed shards progress
+    shards: Vec<(ShardId, ShardProgress)>,
+    // Indexed accounts
+    accounts: Vec<AccountId>,
+    // Receipts created by `ft_transfer_call` receipts, and receipts
+    // that were linked to their `ft_transfer_call` receipts
+    transfer_calls: Vec<(CryptoHash, CryptoHash)>,
+    linked_transfer_calls: Vec<CryptoHash>,
+    // New or changed missed blocks, and resolved missed blocks
+    missed_blocks: Vec<(BlockHeight, MissedBlock)>,
+    resolved_blocks: Vec<BlockHeight>,
+    // Rollback of the orphaned blocks
+    reorg: Option<Reorg>,
+}
+
+impl Update {
+    /// Changes made by the block with `indexed_data`, that changed `shards`.
+    /// Only changes are taken, the state maps of the `data` aren't scanned.
+    fn new(
+        data: &IndexerData,
+        indexed_data: Option<&IndexedData>,
+        shards: &[ShardId],
+        missed_blocks: &MissedBlocksDelta,
+    ) -> Self {
+        let mut update = Self {
+            cursor: Cursor::new(data),
+            shards: shards
+                .iter()
+                .filter_map(|shard_id| Some((*shard_id, data.shards.get(shard_id)?.clone())))
+                .collect(),
+            missed_blocks: missed_blocks.changed.clone(),
+            resolved_blocks: missed_blocks.resolved.clone(),
+            ..Self::default()
+        };
+        if let Some(indexed_data) = indexed_data {
+            update.accounts = indexed_data.accounts.iter().cloned().collect();
+            update.transfer_calls = indexed_data
+                .transfer_calls
+                .iter()
+                .map(|(receipt_id, origin)| (*receipt_id, *origin))
+                .collect();
+            update.linked_transfer_calls = indexed_data
+                .logs
+                .iter()
+                .filter(|log| log.origin_receipt_id.is_some())
+                .filter_map(|log| log.receipt_id)
+                .collect();
+        }
+        update
+    }
+
+    fn apply(self, data: &mut IndexerData) {
+        if let Some(reorg) = &self.reorg {
+            data.rollback(reorg);
+            data.recent_blocks
+                .retain(|block| block.height <= reorg.fork_height);
+        }
+        data.recent_blocks.extend(self.recent_blocks);
+        let excess = data.recent_blocks.len().saturating_sub(RECENT_BLOCKS);
+        data.recent_blocks.drain(..excess);
+        data.shards.extend(self.shards);
+        data.data.accounts.extend(self.accounts);
+        data.data.transfer_calls.extend(self.transfer_calls);
+        for receipt_id in &self.linked_transfer_calls {
+            data.data.transfer_calls.remove(receipt_id);
+        }
+        data.missed_blocks.extend(self.missed_blocks);
+        for height in &self.resolved_blocks {
+            data.missed_blocks.remove(height);
+        }
+        self.cursor.apply(data);
+    }
+}
+
+/// Logs file record
+#[derive(BorshSerialize, BorshDeserialize)]
+enum LogRecord {
+    /// Logs of the handled block
+    Block(Vec<IndexedResultLog>),
+    /// Logs of the retried block with receipts created by its
+    /// `ft_transfer_call` receipts, that link callbacks of the next blocks
+    Retry {
+        logs: Vec<IndexedResultLog>,
+        transfer_calls: Vec<(CryptoHash, CryptoHash)>,
+    },
+    /// Heights of the orphaned blocks, previous logs of them are rolled back
+    Rollback(Vec<BlockHeight>),
+}
+
+/// Replayed state journal
+struct Replay {
+    data: IndexerData,
+    // Length of the logs file with logs of the replayed records
+    logs_len: u64,
+    // Number of records after the last snapshot
+    records: usize,
+    // Length of the complete replayed records
+    valid_len: u64,
+}
+
+pub struct Journal {
+    file: File,
+    logs_file: File,
+    path: PathBuf,
+    logs_len: u64,
+    // Number of records after the last snapshot
+    records: usize,
+}
+
+impl Journal {
+    /// Check if file is a journal of any version
+    pub fn is_journal<P: AsRef<Path>>(path: P) -> bool {
+        let mut magic = [0; MAGIC.len()];
+        File::open(path)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .map_or(false, |()| magic.starts_with(JOURNAL_PREFIX))
+    }
+
+    /// Create journal from the indexer data: logs are written to the logs
+    /// file, and the rest of the data is the snapshot record. Existing data
+    /// file is replaced only after the journal is completely written.
+    pub fn create<P: AsRef<Path>>(path: P, data: &IndexerData) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let logs_path = logs_path(path);
+        let mut logs_file = File::create(&logs_path)
+            .map_err(|e| anyhow::anyhow!("Failed create {}, {e}", logs_path.display()))?;
+        let mut logs_len = LOGS_MAGIC.len() as u64;
+        logs_file.write_all(LOGS_MAGIC)?;
+        for logs in data.data.logs.chunks(LOGS_PER_RECORD) {
+            let payload = LogRecord::Block(logs.to_vec()).try_to_vec()?;
+            logs_len += write_record(&mut logs_file, &payload)?;
+        }
+        logs_file.sync_all()?;
+        write_snapshot(path, data, logs_len)?;
+        Self::open(path).map(|(journal, _)| journal)
+    }
+
+    /// Open journal for appending and replay it to the indexer data without
+    /// logs. Incomplete last record and logs of not recorded block, left by
+    /// interrupted write, are truncated. Journal with many records is
+    /// compacted into a snapshot.
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<(Self, IndexerData)> {
+        let path = path.as_ref().to_path_buf();
+        let replay = replay(&path)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed open journal {}, {e}", path.display()))?;
+        if file.metadata()?.len() > replay.valid_len {
+            eprintln!("Truncate incomplete journal record");
+            file.set_len(replay.valid_len)?;
+        }
+        let logs_path = logs_path(&path);
+        let logs_file = OpenOptions::new()
+            .append(true)
+            .open(&logs_path)
+            .map_err(|e| anyhow::anyhow!("Failed open {}, {e}", logs_path.display()))?;
+        if logs_file.metadata()?.len() > replay.logs_len {
+            eprintln!("Truncate not recorded journal logs");
+            logs_file.set_len(replay.logs_len)?;
+        }
+
+        let data = replay.data;
+        let mut journal = Self {
+            file,
+            logs_file,
+            path,
+            logs_len: replay.logs_len,
+            records: replay.records,
+        };
+        if journal.records > COMPACT_RECORDS {
+            journal.compact(&data)?;
+        }
+        Ok((journal, data))
+    }
+
+    /// Append handled block: its logs and changes of the indexer state.
+    /// The block is the last recent block of the `data`, and `missed_blocks`
+    /// are changes of the missed blocks since the previous record.
+    /// Logs of `indexed_data` are expected to be linked by merge.
+    pub fn append_block(
+        &mut self,
+        data: &IndexerData,
+        indexed_data: &IndexedData,
+        missed_blocks: &MissedBlocksDelta,
+    ) -> anyhow::Result<()> {
+        let recent_block = data.recent_blocks.last();
+        let shards: Vec<_> = recent_block
+            .iter()
+            .flat_map(|block| block.shards.iter().map(|(shard_id, _)| *shard_id))
+            .collect();
+        let update = Update {
+            recent_blocks: recent_block.into_iter().cloned().collect(),
+            ..Update::new(data, Some(indexed_data), &shards, missed_blocks)
+        };
+        let logs = LogRecord::Block(indexed_data.logs.clone());
+        self.append(update, Some(&logs))
+    }
+
+    /// Append retried block: its logs and changes of the indexer state.
+    /// `shards` are shards changed by the block.
+    pub fn append_retry(
+        &mut self,
+        data: &IndexerData,
+        indexed_data: &IndexedData,
+        shards: &[ShardId],
+        missed_blocks: &MissedBlocksDelta,
+    ) -> anyhow::Result<()> {
+        let logs = LogRecord::Retry {
+            logs: indexed_data.logs.clone(),
+            transfer_calls: indexed_data
+                .transfer_calls
+                .iter()
+                .map(|(receipt_id, origin)| (*receipt_id, *origin))
+                .collect(),
+        };
+        let update = Update::new(data, Some(indexed_data), shards, missed_blocks);
+        self.append(update, Some(&logs))
+    }
+
+    /// Append rollback of the orphaned blocks, that is already applied to the data
+    pub fn append_rollback(
+        &mut self,
+        data: &IndexerData,
+        reorg: &Reorg,
+        missed_blocks: &MissedBlocksDelta,
+    ) -> anyhow::Result<()> {
+        let heights = reorg.orphaned.iter().map(|(height, _)| *height).collect();
+        let update = Update {
+            reorg: Some(reorg.clone()),
+            ..Update::new(data, None, &[], missed_blocks)
+        };
+        self.append(update, Some(&LogRecord::Rollback(heights)))
+    }
+
+    /// Append the current cursor and missed blocks changes
+    pub fn append_cursor(
+        &mut self,
+        data: &IndexerData,
+        missed_blocks: &MissedBlocksDelta,
+    ) -> anyhow::Result<()> {
+        self.append(Update::new(data, None, &[], missed_blocks), None)
+    }
+
+    /// Flush appended logs and records to disk. Logs are flushed first, so
+    /// records never refer to lost logs. Journal with many records is
+    /// compacted into a snapshot of the `data`.
+    pub fn sync(&mut self, data: &IndexerData) -> anyhow::Result<()> {
+        self.logs_file.sync_data().map_err(|e| {
+            anyhow::anyhow!("Failed sync journal logs {}, {e}", self.path.display())
+        })?;
+        if self.records > COMPACT_RECORDS {
+            return self.compact(data);
+        }
+        self.file
+            .sync_data()
+            .map_err(|e| anyhow::anyhow!("Failed sync journal {}, {e}", self.path.display()))
+    }
+
+    /// Replace journal records with a snapshot of the `data`, that has
+    /// all appended changes
+    fn compact(&mut self, data: &IndexerData) -> anyhow::Result<()> {
+        write_snapshot(&self.path, data, self.logs_len)?;
+        self.file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| anyhow::anyhow!("Failed open journal {}, {e}", self.path.display()))?;
+        self.records = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, mut update: Update, logs: Option<&LogRecord>) -> anyhow::Result<()> {
+        let path = self.path.display().to_string();
+        let map_err = |e| anyhow::anyhow!("Failed append to journal {path}, {e}");
+        if let Some(logs) = logs {
+            let payload = logs.try_to_vec().map_err(map_err)?;
+            self.logs_len += write_record(&mut self.logs_file, &payload).map_err(map_err)?;
+        }
+        update.logs_len = self.logs_len;
+        let payload = Record::Update(Box::new(update))
+            .try_to_vec()
+            .map_err(map_err)?;
+        write_record(&mut self.file, &payload).map_err(map_err)?;
+        self.records += 1;
+        Ok(())
+    }
+}
+
+/// Load indexer data without logs from borsh file or journal, and call
+/// `on_log` for every log. Journal logs are streamed from the logs file,
+/// so they are never kept in memory all together.
+pub fn load_indexer_data<P, F>(path: P, mut on_log: F) -> anyhow::Result<IndexerData>
+where
+    P: AsRef<Path>,
+    F: FnMut(IndexedResultLog),
+{
+    let path = path.as_ref();
+    if Journal::is_journal(path) {
+        let replay = replay(path)?;
+        read_logs(path, replay.logs_len, on_log)?;
+        return Ok(replay.data);
+    }
+    let data =
+        std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed read indexer data file, {e}"))?;
+    let mut data = deserialize_checkpoint(path, &data)?;
+    for log in data.data.logs.drain(..) {
+        on_log(log);
+    }
+    Ok(data)
+}
+
+/// Load indexer data without logs from borsh file or journal. Journal logs
+/// file isn't read at all.
+pub fn load_indexer_state<P: AsRef<Path>>(path: P) -> anyhow::Result<IndexerData> {
+    let path = path.as_ref();
+    if Journal::is_journal(path) {
+        return replay(path).map(|replay| replay.data);
+    }
+    load_indexer_data(path, drop)
+}
+
+/// Replay state journal records. Replay stops at the incomplete record,
+/// or at the record with logs that weren't written to the logs file.
+fn replay(path: &Path) -> anyhow::Result<Replay> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed open journal {}, {e}", path.display()))?;
+    let logs_path = logs_path(path);
+    let logs_file_len = std::fs::metadata(&logs_path)
+        .map_err(|e| anyhow::anyhow!("Failed open {}, {e}", logs_path.display()))?
+        .len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(
+        &magic == MAGIC,
+        "{} has unsupported journal version {}, index it again",
+        path.display(),
+        String::from_utf8_lossy(&magic)
+    );
+
+    let mut replay = Replay {
+        data: IndexerData::default(),
+        logs_len: LOGS_MAGIC.len() as u64,
+        records: 0,
+        valid_len: MAGIC.len() as u64,
+    };
+    let mut index = 0;
+    while let Some(payload) = read_record(&mut reader)
+        .map_err(|e| anyhow::anyhow!("Corrupted journal record {index}, {e}"))?
+    {
+        let record = Record::try_from_slice(&payload)
+            .map_err(|e| anyhow::anyhow!("Failed deserialize journal record {index}, {e}"))?;
+        let logs_len = match &record {
+            Record::Snapshot { logs_len, .. } => *logs_len,
+            Record::Update(update) => update.logs_len,
+        };
+        if logs_len > logs_file_len {
+            // Logs of the record were lost by interrupted write
+            anyhow::ensure!(
+                matches!(record, Record::Update(_)),
+                "Journal logs file {} is shorter than the snapshot requires",
+                logs_path.display()
+            );
+            break;
+        }
+        match record {
+            Record::Snapshot { data, .. } => {
+                replay.data = *data;
+                replay.records = 0;
+            }
+            Record::Update(update) => {
+                update.apply(&mut replay.data);
+                replay.records += 1;
+            }
+        }
+        replay.logs_len = logs_len;
+        replay.valid_len += (RECORD_HEADER_LEN + payload.len()) as u64;
+        index += 1;
+    }
+    Ok(replay)
+}
+
+/// Read committed logs of the journal logs file and call `on_log` for each.
+/// Logs of the blocks that were rolled back later, and logs without execution
+/// outcome that were indexed again later, are skipped. Callbacks are linked
+/// to `ft_transfer_call` receipts of the blocks retried later.
+fn read_logs<F: FnMut(IndexedResultLog)>(
+    path: &Path,
+    logs_len: u64,
+    mut on_log: F,
+) -> anyhow::Result<()> {
+    // Rollbacks and retried blocks change logs written before them, so the
+    // index of the last record that changed them is kept by key
+    let mut rollbacks: HashMap<BlockHeight, usize> = HashMap::new();
+    let mut retried_logs: HashMap<RetriedLogKey, usize> = HashMap::new();
+    let mut transfer_calls = HashMap::new();
+    read_log_records(path, logs_len, |index, record| match record {
+        LogRecord::Rollback(heights) => {
+            rollbacks.extend(heights.into_iter().map(|height| (height, index)));
+        }
+        LogRecord::Retry {
+            logs,
+            transfer_calls: calls,
+        } => {
+            for log in &logs {
+                // Replaces logs with the same receipt, or without receipt
+                let key = RetriedLogKey::new(log, log.receipt_id);
+                retried_logs.insert(RetriedLogKey::new(log, None), index);
+                retried_logs.insert(key, index);
+            }
+            transfer_calls.extend(calls);
+        }
+        LogRecord::Block(_) => (),
+    })?;
+
+    read_log_records(path, logs_len, |index, record| {
+        let logs = match record {
+            LogRecord::Block(logs) | LogRecord::Retry { logs, .. } => logs,
+            LogRecord::Rollback(_) => return,
+        };
+        for mut log in logs {
+            let is_rolled_back = rollbacks
+                .get(&log.block_height)
+                .map_or(false, |rollback| *rollback > index);
+            let is_replaced = log.status == ExecutionStatus::Unknown
+                && retried_logs
+                    .get(&RetriedLogKey::new(&log, log.receipt_id))
+                    .map_or(false, |retry| *retry > index);
+            if is_rolled_back || is_replaced {
+                continue;
+            }
+            if log.origin_receipt_id.is_none() {
+                log.origin_receipt_id = log
+                    .receipt_id
+                    .and_then(|receipt_id| transfer_calls.get(&receipt_id).copied());
+            }
+            on_log(log);
+        }
+    })
+}
+
+/// Key of the retried log: block height, actions hash and receipt. Matches
+/// logs that are replaced by the retried log, see `is_replaced_by`.
+#[derive(PartialEq, Eq, Hash)]
+struct RetriedLogKey(BlockHeight, CryptoHash, Option<CryptoHash>);
+
+impl RetriedLogKey {
+    fn new(log: &IndexedResultLog, receipt_id: Option<CryptoHash>) -> Self {
+        let actions = log.actions.try_to_vec().expect("Failed serialize actions");
+        Self(
+            log.block_height,
+            CryptoHash::hash_bytes(&actions),
+            receipt_id,
+        )
+    }
+}
+
+/// Read records of the logs file up to `logs_len` with their indexes
+fn read_log_records<F: FnMut(usize, LogRecord)>(
+    path: &Path,
+    logs_len: u64,
+    mut on_record: F,
+) -> anyhow::Result<()> {
+    let logs_path = logs_path(path);
+    let file = File::open(&logs_path)
+        .map_err(|e| anyhow::anyhow!("Failed open {}, {e}", logs_path.display()))?;
+    let mut reader = BufReader::new(file.take(logs_len));
+    let mut magic = [0; LOGS_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(
+        &magic == LOGS_MAGIC,
+        "{} isn't a journal logs file",
+        logs_path.display()
+    );
+
+    let mut index = 0;
+    while let Some(payload) = read_record(&mut reader)
+        .map_err(|e| anyhow::anyhow!("Corrupted journal logs record {index}, {e}"))?
+    {
+        let record = LogRecord::try_from_slice(&payload)
+            .map_err(|e| anyhow::anyhow!("Failed deserialize journal logs record {index}, {e}"))?;
+        on_record(index, record);
+        index += 1;
+    }
+    Ok(())
+}
+
+/// Write journal with one snapshot record of the data without logs. The
+/// journal is replaced only after the snapshot is completely written.
+fn write_snapshot(path: &Path, data: &IndexerData, logs_len: u64) -> anyhow::Result<()> {
+    let snapshot = IndexerData {
+        first_block: data.first_block,
+        last_block: data.last_block,
+        last_handled_block: data.last_handled_block,
+        current_block: data.current_block,
+        last_block_hash: data.last_block_hash,
+        missed_blocks: data.missed_blocks.clone(),
+        skipped_blocks: data.skipped_blocks,
+        shards: data.shards.clone(),
+        recent_blocks: data.recent_blocks.clone(),
+        reorgs: data.reorgs.clone(),
+        data: IndexedData {
+            accounts: data.data.accounts.clone(),
+            logs: vec![],
+            transfer_calls: data.data.transfer_calls.clone(),
+        },
+    };
+    let payload = Record::Snapshot {
+        data: Box::new(snapshot),
+        logs_len,
+    }
+    .try_to_vec()?;
+
+    let tmp_path = with_suffix(path, "tmp");
+    let mut file = File::create(&tmp_path)
+        .map_err(|e| anyhow::anyhow!("Failed create journal {}, {e}", tmp_path.display()))?;
+    file.write_all(MAGIC)?;
+    write_record(&mut file, &payload)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+        .map_err(|e| anyhow::anyhow!("Failed replace {}, {e}", path.display()))?;
+    sync_dir(path)
+}
+
+/// Path of the journal logs file: `data.journal` -> `data.journal.logs`
+fn logs_path(path: &Path) -> PathBuf {
+    with_suffix(path, "logs")
+}
+
+/// Load indexer data from borsh checkpoint. Return `None` if the file
+/// doesn't exist. Corrupted file is an error, it's never replaced with
+/// default data.
+pub fn load_checkpoint<P: AsRef<Path>>(path: P) -> anyhow::Result<Option<IndexerData>> {
+    match std::fs::read(&path) {
+        Ok(data) => deserialize_checkpoint(path, &data).map(Some),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow::anyhow!(
+            "Failed read indexer data file {}, {e}",
+            path.as_ref().display()
+        )),
+    }
+}
+
+/// Write borsh checkpoint atomically: data is written to a temporary file,
+/// synced and renamed over the data file. Previous checkpoints are kept as
+/// `<file>.1` .. `<file>.N` backups.
+pub fn write_checkpoint<P: AsRef<Path>, T: BorshSerialize>(
+    path: P,
+    data: &T,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let data = data
+        .try_to_vec()
+        .map_err(|e| anyhow::anyhow!("Failed serialize data, {e}"))?;
+    let tmp_path = with_suffix(path, "tmp");
+    let mut file = File::create(&tmp_path)
+        .map_err(|e| anyhow::anyhow!("Failed create {}, {e}", tmp_path.display()))?;
+    file.write_all(&data)
+        .and_then(|()| file.sync_all())
+        .map_err(|e| anyhow::anyhow!("Failed write {}, {e}", tmp_path.display()))?;
+
+    if path.exists() {
+        rotate_backups(path)?;
+    }
+    std::fs::rename(&tmp_path, path)
+        .map_err(|e| anyhow::anyhow!("Failed replace {}, {e}", path.display()))?;
+    sync_dir(path)
+}
+
+/// Persist the rename of the file in its directory
+fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir).and_then(|dir| dir.sync_all())?;
+    }
+    Ok(())
+}
+
+/// Shift backups and keep the current data file as the newest one. The data
+/// file stays in place until it's replaced by the new checkpoint.
+fn rotate_backups(path: &Path) -> anyhow::Result<()> {
+    for n in (1..CHECKPOINT_BACKUPS).rev() {
+        let from = with_suffix(path, &n.to_string());
+        if from.exists() {
+            std::fs::rename(&from, with_suffix(path, &(n + 1).to_string()))
+                .map_err(|e| anyhow::anyhow!("Failed rotate backup {}, {e}", from.display()))?;
+        }
+    }
+    let backup = with_suffix(path, "1");
+    let _ = std::fs::remove_file(&backup);
+    // Hard link is free, fall back to copy if it's not supported
+    std::fs::hard_link(path, &backup)
+        .or_else(|_| std::fs::copy(path, &backup).map(|_| ()))
+        .map_err(|e| anyhow::anyhow!("Failed backup {}, {e}", path.display()))
+}
+
+/// Deserialize borsh checkpoint. Data file of the legacy layout, written
+/// before versioning, is converted to the current layout.
+fn deserialize_checkpoint<P: AsRef<Path>>(path: P, data: &[u8]) -> anyhow::Result<IndexerData> {
+    let path = path.as_ref();
+    if !data.starts_with(INDEXER_DATA_MAGIC) {
+        eprintln!(
+            "Indexer data file {} has legacy layout, it's converted to the current layout",
+            path.display()
+        );
+    }
+    IndexerData::try_from_slice(data).map_err(|e| {
+        if e.kind() == ErrorKind::Unsupported {
+            anyhow::anyhow!(
+                "Indexer data file {} has incompatible format, {e}. \
+                 It was written by another version of the tool",
+                path.display()
+            )
+        } else {
+            anyhow::anyhow!(
+                "Failed deserialize indexed data {}, {e}. The file is corrupted, \
+                 restore it from the backup {}",
+                path.display(),
+                with_suffix(path, "1").display()
+            )
+        }
+    })
+}
+
+/// Append suffix to the file name: `data.borsh` -> `data.borsh.1`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Write record in one write call: payload length, payload hash and
+/// payload. Return the record length.
+fn write_record<W: Write>(writer: &mut W, payload: &[u8]) -> std::io::Result<u64> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "Record is too big"))?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&CryptoHash::hash_bytes(payload).0);
+    record.extend_from_slice(payload);
+    writer.write_all(&record)?;
+    writer.flush()?;
+    Ok(record.len() as u64)
+}
+
+/// Read record payload. Return `None` at the end of the journal or
+/// if the last record is incomplete.
+fn read_record<R: Read>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let mut record_header = [0; RECORD_HEADER_LEN];
+    if !read_full(reader, &mut record_header)? {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(record_header[..4].try_into().expect("Expected 4 bytes")) as usize;
+    let mut payload = vec![0; len];
+    if !read_full(reader, &mut payload)? {
+        return Ok(None);
+    }
+    if CryptoHash::hash_bytes(&payload).0[..] != record_header[4..] {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Record hash mismatch",
+        ));
+    }
+    Ok(Some(payload))
+}
+
+/// Fill buffer. Return `false` if reader ended before the buffer was filled.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{ActionResultLog, FailureKind};
+    use crate::test_utils::TestDir;
+    use std::collections::HashSet;
+
+    fn block_hash(height: BlockHeight) -> CryptoHash {
+        CryptoHash::hash_bytes(&height.to_le_bytes())
+    }
+
+    fn log(block_height: BlockHeight, method: &str, status: ExecutionStatus) -> IndexedResultLog {
+        IndexedResultLog {
+            block_height,
+            actions: vec![ActionResultLog {
+                accounts: vec!["alice.near".parse().unwrap()],
+                method: method.to_string(),
+            }],
+            status,
+            receipt_id: None,
+            origin_receipt_id: None,
+        }
+    }
+
+    /// Data with one log at the first block, converted from borsh file
+    fn create(path: &Path) -> (Journal, IndexerData) {
+        let mut data = IndexerData {
+            first_block: 10,
+            last_block: 10,
+            last_handled_block: 10,
+            last_block_hash: Some(block_hash(10)),
+            ..IndexerData::default()
+        };
+        data.data
+            .logs
+            .push(log(10, "deposit", ExecutionStatus::Success));
+        let journal = Journal::create(path, &data).unwrap();
+        data.data.logs.clear();
+        (journal, data)
+    }
+
+    /// Update data with the handled block and append it to the journal
+    fn handle(
+        journal: &mut Journal,
+        data: &mut IndexerData,
+        height: BlockHeight,
+        account: &str,
+        logs: Vec<IndexedResultLog>,
+    ) {
+        handle_with_missed(
+            journal,
+            data,
+            height,
+            account,
+            logs,
+            &MissedBlocksDelta::default(),
+        );
+    }
+
+    /// Update data with the handled block and missed blocks changes,
+    /// and append it to the journal
+    fn handle_with_missed(
+        journal: &mut Journal,
+        data: &mut IndexerData,
+        height: BlockHeight,
+        account: &str,
+        logs: Vec<IndexedResultLog>,
+        missed_blocks: &MissedBlocksDelta,
+    ) {
+        let account: AccountId = account.parse().unwrap();
+        let indexed_data = IndexedData {
+            accounts: HashSet::from([account.clone()]),
+            logs,
+            transfer_calls: HashMap::new(),
+        };
+        let previous = data.shards.get(&0).cloned();
+        let shard = data.shards.entry(0).or_default();
+        shard.last_chunk_block = height;
+        shard.chunks += 1;
+        data.recent_blocks.push(RecentBlock {
+            height,
+            hash: block_hash(height),
+            accounts: if data.data.accounts.insert(account.clone()) {
+                vec![account.clone()]
+            } else {
+                vec![]
+            },
+            touched: vec![account],
+            logs: indexed_data.logs.len() as u64,
+            shards: vec![(0, previous)],
+            transfer_calls: vec![],
+            linked_transfer_calls: vec![],
+        });
+        data.last_block = height;
+        data.last_handled_block = height;
+        data.last_block_hash = Some(block_hash(height));
+        missed_blocks.apply(&mut data.missed_blocks);
+        journal
+            .append_block(data, &indexed_data, missed_blocks)
+            .unwrap();
+    }
+
+    fn load(path: &Path) -> (IndexerData, Vec<IndexedResultLog>) {
+        let mut logs = vec![];
+        let data = load_indexer_data(path, |log| logs.push(log)).unwrap();
+        (data, logs)
+    }
+
+    fn methods(logs: &[IndexedResultLog]) -> Vec<(BlockHeight, &str)> {
+        logs.iter()
+            .map(|log| (log.block_height, log.actions[0].method.as_str()))
+            .collect()
+    }
+
+    fn assert_state(data: &IndexerData, expected: &IndexerData) {
+        assert_eq!(data.first_block, expected.first_block);
+        assert_eq!(data.last_handled_block, expected.last_handled_block);
+        assert_eq!(data.last_block_hash, expected.last_block_hash);
+        assert_eq!(data.shards, expected.shards);
+        assert_eq!(data.missed_blocks, expected.missed_blocks);
+        assert_eq!(data.data.accounts, expected.data.accounts);
+        assert_eq!(data.data.transfer_calls, expected.data.transfer_calls);
+        let heights = |data: &IndexerData| -> Vec<_> {
+            data.recent_blocks
+                .iter()
+                .map(|block| (block.height, block.hash))
+                .collect()
+        };
+        assert_eq!(heights(data), heights(expected));
+        assert!(data.data.logs.is_empty());
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = TestDir::new("store-replay");
+        let path = dir.path("data.journal");
+        let (mut journal, mut data) = create(&path);
+        assert!(Journal::is_journal(&path));
+
+        let success = ExecutionStatus::Success;
+        handle(
+            &mut journal,
+            &mut data,
+            11,
+            "alice.near",
+            vec![log(11, "ft_transfer", success)],
+        );
+        handle(
+            &mut journal,
+            &mut data,
+            12,
+            "bob.near",
+            vec![log(12, "withdraw", success)],
+        );
+        journal
+            .append_cursor(&data, &MissedBlocksDelta::default())
+            .unwrap();
+        journal.sync(&data).unwrap();
+        drop(journal);
+
+        let (replayed, logs) = load(&path);
+        assert_state(&replayed, &data);
+        assert_eq!(
+            methods(&logs),
+            vec![(10, "deposit"), (11, "ft_transfer"), (12, "withdraw")]
+        );
+        assert_state(&load_indexer_state(&path).unwrap(), &data);
+
+        let (_, opened) = Journal::open(&path).unwrap();
+        assert_state(&opened, &data);
+    }
+
+    #[test]
+    fn test_truncate_interrupted_write() {
+        let dir = TestDir::new("store-truncate");
+        let path = dir.path("data.journal");
+        let (mut journal, mut data) = create(&path);
+        let success = ExecutionStatus::Success;
+        handle(
+            &mut journal,
+            &mut data,
+            11,
+            "alice.near",
+            vec![log(11, "ft_transfer", success)],
+        );
+        journal.sync(&data).unwrap();
+        drop(journal);
+        let journal_len = std::fs::metadata(&path).unwrap().len();
+        let logs_len = std::fs::metadata(logs_path(&path)).unwrap().len();
+
+        // Logs of the block 12 are written, but its state record is incomplete
+        let payload = LogRecord::Block(vec![log(12, "withdraw", success)])
+            .try_to_vec()
+            .unwrap();
+        let mut logs_file = OpenOptions::new()
+            .append(true)
+            .open(logs_path(&path))
+            .unwrap();
+        write_record(&mut logs_file, &payload).unwrap();
+        let mut record = vec![];
+        write_record(
+            &mut record,
+            &Record::Update(Box::default()).try_to_vec().unwrap(),
+        )
+        .unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+
+        let (replayed, logs) = load(&path);
+        assert_state(&replayed, &data);
+        assert_eq!(methods(&logs), vec![(10, "deposit"), (11, "ft_transfer")]);
+
+        let (mut journal, mut data) = Journal::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), journal_len);
+        assert_eq!(std::fs::metadata(logs_path(&path)).unwrap().len(), logs_len);
+
+        handle(
+            &mut journal,
+            &mut data,
+            12,
+            "bob.near",
+            vec![log(12, "withdraw", success)],
+        );
+        journal.sync(&data).unwrap();
+        let (replayed, logs) = load(&path);
+        assert_state(&replayed, &data);
+        assert_eq!(
+            methods(&logs),
+            vec![(10, "deposit"), (11, "ft_transfer"), (12, "withdraw")]
+        );
+    }
+
+    #[test]
+    fn test_record_without_logs() {
+        let dir = TestDir::new("store-lost-logs");
+        let path = dir.path("data.journal");
+        let (mut journal, mut data) = create(&path);
+        let success = ExecutionStatus::Success;
+        handle(
+            &mut journal,
+            &mut data,
+            11,
+            "alice.near",
+            vec![log(11, "ft_transfer", success)],
+        );
+        let previous = load_indexer_state(&path).unwrap();
+        handle(
+            &mut journal,
+            &mut data,
+            12,
+            "bob.near",
+            vec![log(12, "withdraw", success)],
+        );
+        drop(journal);
+
+        // Logs of the last block were lost, so its record is not replayed
+        let logs_file = OpenOptions::new()
+            .write(true)
+            .open(logs_path(&path))
+            .unwrap();
+        logs_file
+            .set_len(logs_file.metadata().unwrap().len() - 1)
+            .unwrap();
+        let (replayed, logs) = load(&path);
+        assert_state(&replayed, &previous);
+        assert_eq!(methods(&logs), vec![(10, "deposit"), (11, "ft_transfer")]);
+    }
+
+    #[test]
+    fn test_corrupted_record() {
+        let dir = TestDir::new("store-corrupted");
+        let path = dir.path("data.journal");
+        let (mut journal, mut data) = create(&path);
+        let success = ExecutionStatus::Success;
+        handle(
+            &mut journal,
+            &mut data,
+            11,
+            "alice.near",
+            vec![log(11, "ft_transfer", success)],
+        );
+        drop(journal);
+
+        let mut journal_data = std::fs::read(&path).unwrap();
+        let last = journal_data.len() - 1;
+        journal_data[last] ^= 1;
+        std::fs::write(&path, journal_data).unwrap();
+        let error = load_indexer_state(&path).unwrap_err().to_string();
+        assert!(error.starts_with("Corrupted journal record 1"), "{error}");
+    }
+
+    #[test]
+    fn test_rollback() {
+        let dir = TestDir::new("store-rollback");
+        let path = dir.path("data.journal");
+        let (mut journal, mut data) = create(&path);
+        let success = ExecutionStatus::Success;
+        handle(
+            &mut journal,
+            &mut data,
+            11,
+            "alice.near",
+            vec![log(11, "ft_transfer", success)],
+        );
+        let before_fork = data.clone();
+        handle(
+            &mut journal,
+            &mut data,
+            12,
+            "bob.near",
+            vec![log(12, "withdraw", success)],
+        );
+
+        let reorg = Reorg {
+            fork_height: 11,
+            fork_hash: block_hash(11),
+            orphaned: vec![(12, block_hash(12))],
+            accounts: vec!["bob.near".parse().unwrap()],
+            logs: 1,
+            time: 0,
+        };
+        data.rollback(&reorg);
+        data.recent_blocks.retain(|block| block.height <= 11);
+        data.last_block = 11;
+        data.last_handled_block = 11;
+        data.last_block_hash = Some(block_hash(11));
+        journal
+            .append_rollback(&data, &reorg, &MissedBlocksDelta::default())
+            .unwrap();
+        assert_state(&data, &before_fork);
+
+        let (replayed, logs) = load(&path);
+        assert_state(&replayed, &before_fork);
+        assert_eq!(replayed.reorgs.len(), 1);
+        assert_eq!(methods(&logs), vec![(10, "deposit"), (11, "ft_transfer")]);
+
+        // New block at the orphaned height
+        handle(
+            &mut journal,
+            &mut data,
+            12,
+            "carol.near",
+            vec![log(12, "deposit", success)],
+        );
+        let (replayed, logs) = load(&path);
+        assert_state(&replayed, &data);
+        assert_eq!(
+            methods(&logs),
+            vec![(10, "deposit"), (11, "ft_transfer"), (12, "deposit")]
+        );
+    }
+
+    #[test]
+    fn test_retry_replaces_unknown_logs() {
+        let dir = TestDir::new("store-retry");
+        let path = dir.path("data.journal");
+        let (mut journal, mut data) = create(&path);
+        let unknown = log(11, "ft_transfer", ExecutionStatus::Unknown);
+        let missed_block = MissedBlock {
+            kind: FailureKind::Outcome {
+                shard_id: 0,
+                chunk_hash: CryptoHash::default(),
+            },
+            error: "Failed get execution outcome".to_string(),
+            attempts: 1,
+            last_attempt: 0,
+            chunks: vec![CryptoHash::default()],
+        };
+        let missed = MissedBlocksDelta {
+            changed: vec![(11, missed_block)],
+            resolved: vec![],
+        };
+        handle_with_missed(
+            &mut journal,
+            &mut data,
+            11,
+            "alice.near",
+            vec![unknown],
+            &missed,
+        );
+        assert_eq!(load_indexer_state(&path).unwrap().missed_blocks.len(), 1);
+
+        let resolved = MissedBlocksDelta {
+            changed: vec![],
+            resolved: vec![11],
+        };
+        resolved.apply(&mut data.missed_blocks);
+        let retried = IndexedData {
+            accounts: HashSet::from(["alice.near".parse().unwrap()]),
+            logs: vec![log(11, "ft_transfer", ExecutionStatus::Failure)],
+            transfer_calls: HashMap::new(),
+        };
+        journal
+            .append_retry(&data, &retried, &[], &resolved)
+            .unwrap();
+
+        let (replayed, logs) = load(&path);
+        assert_state(&replayed, &data);
+        assert_eq!(methods(&logs), vec![(10, "deposit"), (11, "ft_transfer")]);
+        assert_eq!(logs[1].status, ExecutionStatus::Failure);
+    }
+
+    #[test]
+    fn test_retry_replaces_logs_of_same_receipt() {
+        let dir = TestDir::new("store-retry-receipt");
+        let path = dir.path("data.journal");
+        let (mut journal, mut data) = create(&path);
+        let with_receipt = |method, status, receipt: &[u8]| IndexedResultLog {
+            receipt_id: Some(CryptoHash::hash_bytes(receipt)),
+            ..log(11, method, status)
+        };
+        let unknown = ExecutionStatus::Unknown;
+        handle(
+            &mut journal,
+            &mut data,
+            11,
+            "alice.near",
+            vec![
+                with_receipt("ft_transfer", unknown, b"receipt1"),
+                with_receipt("ft_transfer", unknown, b"receipt2"),
+            ],
+        );
+        let retried = IndexedData {
+            accounts: HashSet::from(["alice.near".parse().unwrap()]),
+            logs: vec![with_receipt(
+                "ft_transfer",
+                ExecutionStatus::Success,
+                b"receipt1",
+            )],
+            transfer_calls: HashMap::new(),
+        };
+        journal
+            .append_retry(&data, &retried, &[], &MissedBlocksDelta::default())
+            .unwrap();
+
+        let (_, logs) = load(&path);
+        let statuses: Vec<_> = logs[1..].iter().map(|log| log.status).collect();
+        assert_eq!(statuses, vec![unknown, ExecutionStatus::Success]);
+        assert_eq!(
+            logs[1].receipt_id,
+            Some(CryptoHash::hash_bytes(b"receipt2"))
+        );
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = TestDir::new("store-compact");
+        let path = dir.path("data.journal");
+        let (mut journal, mut data) = create(&path);
+        let success = ExecutionStatus::Success;
+        for height in 11..20 {
+            handle(
+                &mut journal,
+                &mut data,
+                height,
+                "alice.near",
+                vec![log(height, "deposit", success)],
+            );
+        }
+        let journal_len = std::fs::metadata(&path).unwrap().len();
+        journal.compact(&data).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < journal_len);
+        assert_eq!(replay(&path).unwrap().records, 0);
+
+        handle(
+            &mut journal,
+            &mut data,
+            20,
+            "bob.near",
+            vec![log(20, "withdraw", success)],
+        );
+        journal.sync(&data).unwrap();
+        let replayed = replay(&path).unwrap();
+        assert_eq!(replayed.records, 1);
+        assert_state(&replayed.data, &data);
+
+        let (_, logs) = load(&path);
+        assert_eq!(logs.len(), 11);
+        assert_eq!(methods(&logs)[10], (20, "withdraw"));
+    }
+
+    #[test]
+    fn test_checkpoint_rotation() {
+        let dir = TestDir::new("store-checkpoint");
+        let path = dir.path("data.borsh");
+        let last_block = |path: &Path| load_checkpoint(path).unwrap().map(|data| data.last_block);
+        for height in 1..=5 {
+            let data = IndexerData {
+                last_block: height,
+                ..IndexerData::default()
+            };
+            write_checkpoint(&path, &data).unwrap();
+        }
+        assert_eq!(last_block(&path), Some(5));
+        for n in 1..=CHECKPOINT_BACKUPS {
+            let backup = with_suffix(&path, &n.to_string());
+            assert_eq!(last_block(&backup), Some(5 - n as u64));
+        }
+        let oldest = with_suffix(&path, &(CHECKPOINT_BACKUPS + 1).to_string());
+        assert!(!oldest.exists());
+        assert!(!with_suffix(&path, "tmp").exists());
+
+        // Corrupted data file is restored from the newest backup
+        std::fs::write(&path, b"corrupted").unwrap();
+        let err = load_checkpoint(&path).unwrap_err().to_string();
+        assert!(err.contains("data.borsh.1"), "{err}");
+        std::fs::copy(with_suffix(&path, "1"), &path).unwrap();
+        assert_eq!(last_block(&path), Some(4));
+    }
+}
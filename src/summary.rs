@@ -0,0 +1,83 @@
+//! # Summary
+//! Command summary printed as text lines or as one JSON document.
+//!
+use serde_json::{Map, Value};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Summary output format
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryFormat {
+    /// Text lines
+    #[default]
+    Text,
+    /// One JSON document
+    Json,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("Unknown summary format: {s}"),
+        }
+    }
+}
+
+/// Command summary. Text lines are printed immediately, JSON document
+/// is printed by `finish`. Balances are set as strings, to avoid
+/// precision loss of `u128` values.
+#[derive(Debug)]
+pub struct Summary {
+    format: SummaryFormat,
+    fields: Map<String, Value>,
+}
+
+impl Summary {
+    #[must_use]
+    pub fn new(format: SummaryFormat) -> Self {
+        Self {
+            format,
+            fields: Map::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_text(&self) -> bool {
+        self.format == SummaryFormat::Text
+    }
+
+    /// Print line in text format only
+    pub fn text<T: Display>(&self, line: T) {
+        if self.is_text() {
+            println!("{line}");
+        }
+    }
+
+    /// Set JSON document field only
+    pub fn set<T: Into<Value>>(&mut self, key: &str, value: T) {
+        self.fields.insert(key.to_string(), value.into());
+    }
+
+    /// Print `label: value` line and set JSON document field
+    pub fn field<T: Display + Into<Value>>(&mut self, key: &str, label: &str, value: T) {
+        self.text(format_args!("{label}: {value}"));
+        self.set(key, value);
+    }
+
+    /// JSON document of the set fields
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        Value::Object(self.fields.clone())
+    }
+
+    /// Print JSON document
+    pub fn finish(self) {
+        if !self.is_text() {
+            println!("{}", self.to_json());
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! # Test utils
+//! Helpers shared by the unit tests.
+//!
+use std::path::PathBuf;
+
+/// Temporary directory of the test, removed with all files on drop
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Create directory unique for the test `name` and the test process
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// Path of the file in the directory
+    pub fn path(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}